@@ -1,189 +1,1855 @@
-use std::{env, mem};
+use std::{env, fs, mem};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
+use failure::{Error, ResultExt};
+use color::{self, ColorChoice};
+use config;
+use completions::Shell;
+use flags;
+use timings;
 use Command;
 
-pub(crate) fn parse_args() -> Command {
-    let mut args = env::args().skip(1);
+/// The TCP port of the QEMU monitor if `--monitor` is passed without `--monitor-port`.
+const DEFAULT_MONITOR_PORT: u16 = 4444;
+
+/// The TCP port of the QEMU gdb stub if `--gdb` is passed without `--gdb-port`.
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+/// The emulated network card if `--net user` is passed without `--net-device`.
+const DEFAULT_NET_DEVICE: &str = "e1000";
+
+/// The number of serial output lines printed for a failed test if `--tail` is not passed.
+const DEFAULT_TAIL: usize = 20;
+
+/// How deeply `@file` arguments may include further `@file` arguments.
+const MAX_ARG_FILE_DEPTH: usize = 8;
+
+pub(crate) fn parse_args() -> Result<Command, Error> {
+    parse_args_from(env::args())
+}
+
+/// Parses the command line `argv`, whose first element is the program name.
+fn parse_args_from<A>(mut args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let program = args.next().unwrap_or_default();
+    let mut args = args.peekable();
+    // `cargo bootimage build` runs `cargo-bootimage bootimage build`
+    if is_cargo_subcommand(&program) && args.peek().map(String::as_str) == Some("bootimage") {
+        args.next();
+    }
     let first = args.next();
-    match first.as_ref().map(|s| s.as_str()) {
-        Some("build") => parse_build_args(args),
-        Some("run") => match parse_build_args(args) {
+    let command = match first.as_deref() {
+        Some("build") => parse_build_args("build", args)?,
+        Some("run") => match parse_build_args("run", args)? {
             Command::Build(args) => Command::Run(args),
             Command::BuildHelp => Command::RunHelp,
             cmd => cmd,
         },
-        Some("test") => match parse_build_args(args) {
-            Command::Build(args) => {
-                assert_eq!(args.bin_name, None, "No `--bin` argument allowed for `bootimage test`");
-                Command::Test(args)
-            },
+        Some("test") => match parse_build_args("test", args)? {
+            Command::Build(args) => Command::Test(args),
             Command::BuildHelp => Command::TestHelp,
             cmd => cmd,
         },
+        Some("doctor") => match parse_build_args("doctor", args)? {
+            Command::Build(args) => Command::Doctor(args),
+            Command::BuildHelp => Command::DoctorHelp,
+            cmd => cmd,
+        },
+        Some("runner") => match parse_build_args("runner", args)? {
+            Command::Build(args) => Command::Runner(args),
+            Command::BuildHelp => Command::RunnerHelp,
+            cmd => cmd,
+        },
+        Some("completions") => match args.next() {
+            Some(shell) => Command::Completions(Shell::parse(&shell)?),
+            None => Err(format_err!(
+                "missing shell for `bootimage completions` (supported: {})",
+                Shell::NAMES.join(", ")
+            ))?,
+        },
         Some("--help") | Some("-h") => Command::Help,
         Some("--version") => Command::Version,
         _ => Command::NoSubcommand,
-    }
+    };
+    Ok(command)
 }
 
-fn parse_build_args<A>(args: A) -> Command
+/// Returns whether bootimage was invoked as `cargo-bootimage`, i.e. possibly by cargo as
+/// the `cargo bootimage` subcommand.
+///
+/// Cargo passes the name of the subcommand as first argument, so it has to be skipped.
+/// Invoking `cargo-bootimage` directly without it works as well.
+fn is_cargo_subcommand(program: &str) -> bool {
+    Path::new(program).file_stem().and_then(|stem| stem.to_str()) == Some("cargo-bootimage")
+}
+
+/// Parses the arguments of the given subcommand, which must be a subcommand with BUILD_OPTS.
+fn parse_build_args<A>(command: &'static str, args: A) -> Result<Command, Error>
 where
     A: Iterator<Item = String>,
 {
     let mut manifest_path: Option<PathBuf> = None;
+    let mut manifest_out: Option<PathBuf> = None;
+    let mut metadata_file: Option<PathBuf> = None;
+    let mut junit: Option<PathBuf> = None;
+    let mut tail: Option<usize> = None;
+    let mut emit_boot_map: Option<PathBuf> = None;
+    let mut symbols_path: Option<PathBuf> = None;
     let mut bin_name: Option<String> = None;
+    let mut example_name: Option<String> = None;
     let mut target: Option<String> = None;
     let mut release: Option<bool> = None;
+    let mut profile: Option<String> = None;
     let mut update_bootloader: Option<bool> = None;
+    let mut headless: Option<bool> = None;
+    let mut no_drive: Option<bool> = None;
+    let mut quiet: Option<bool> = None;
+    let mut snapshot: Option<bool> = None;
+    let mut print_pid: Option<bool> = None;
+    let mut reuse_build: Option<bool> = None;
+    let mut no_build: Option<bool> = None;
+    let mut reproducible: Option<bool> = None;
+    let mut feature_tagged_output: Option<bool> = None;
+    let mut force: Option<bool> = None;
+    let mut print_target_dir: Option<bool> = None;
+    let mut print_kernel_path: Option<bool> = None;
+    let mut list_tests: Option<bool> = None;
+    let mut check_config: Option<bool> = None;
+    let mut serial_socket: Option<bool> = None;
+    let mut changed: Option<bool> = None;
+    let mut profile_startup: Option<bool> = None;
+    let mut strip: Option<bool> = None;
+    let mut strict: Option<bool> = None;
+    let mut nocapture: Option<bool> = None;
+    let mut keep_image: Option<bool> = None;
+    let mut monitor: Option<bool> = None;
+    let mut monitor_port: Option<u16> = None;
+    let mut gdb: Option<bool> = None;
+    let mut gdb_port: Option<u16> = None;
+    let mut gdb_attach: Option<bool> = None;
+    let mut max_image_size: Option<u64> = None;
+    let mut network: Option<Network> = None;
+    let mut image_format: Option<ImageFormat> = None;
+    let mut checksum: Option<ChecksumAlgorithm> = None;
+    let mut partition_table: Option<PartitionTable> = None;
+    let mut net_device: Option<String> = None;
+    let mut pcap: Option<PathBuf> = None;
+    let mut qemu_cwd: Option<PathBuf> = None;
+    let mut bios: Option<PathBuf> = None;
+    let mut pflash: Vec<PathBuf> = Vec::new();
+    let mut qemu_log: Option<String> = None;
+    let mut qemu_log_file: Option<PathBuf> = None;
+    let mut qemu_stdout: Option<OutputRouting> = None;
+    let mut qemu_stderr: Option<OutputRouting> = None;
+    let mut kernel: Option<PathBuf> = None;
+    let mut color_choice: Option<ColorChoice> = None;
+    let mut targets = Vec::new();
+    let mut config_overrides = Vec::new();
+    let mut env_vars = BTreeMap::new();
+    let mut env_file_vars = BTreeMap::new();
     let mut cargo_args = Vec::new();
     let mut run_args = Vec::new();
+    let mut flag_run_args = Vec::new();
     let mut run_args_started = false;
     {
-        fn set<T>(arg: &mut Option<T>, value: Option<T>) {
+        // for singular flags (and flags that set the same value, e.g. `--keep-image` and
+        // `--no-keep-image`)
+        fn set<T>(arg: &mut Option<T>, value: Option<T>) -> Result<(), Error> {
             let previous = mem::replace(arg, value);
-            assert!(
-                previous.is_none(),
-                "multiple arguments of same type provided"
-            )
-        };
+            if previous.is_some() {
+                Err(format_err!("multiple arguments of same type provided"))?
+            }
+            Ok(())
+        }
+        // for `repeatable` flags, which accumulate their values in order
+        fn push<T>(values: &mut Vec<T>, value: Option<T>) {
+            values.extend(value);
+        }
+        // rejects e.g. `--bin ""` from an unset shell variable
+        fn non_empty(flag: &str, value: Option<String>) -> Result<Option<String>, Error> {
+            match value {
+                Some(ref value) if value.is_empty() => {
+                    Err(format_err!("'{}' value must not be empty", flag))?
+                }
+                value => Ok(value),
+            }
+        }
 
+        // split `--flag=value` into `--flag value` for the flags that take a value, so
+        // that the forms don't need to be handled separately, and resolve abbreviated
+        // flags such as `--rel` (except for `runner`, whose arguments after the kernel
+        // belong to the run command)
+        let mut args = args;
+        let mut expanded_args = Vec::new();
+        while let Some(arg) = args.next() {
+            // the run arguments (including any further `--`) are never touched, neither
+            // after the first `--` nor after the kernel executable of `runner`
+            if arg == "--" || (command == "runner" && !arg.starts_with('-')) {
+                expanded_args.push(arg);
+                expanded_args.extend(args.by_ref());
+                break;
+            }
+            let (name, value) = match arg.find('=') {
+                Some(index) => (&arg[..index], Some(&arg[index + 1..])),
+                None => (&arg[..], None),
+            };
+            let name = if command == "runner" {
+                name
+            } else {
+                flags::resolve_abbreviation(command, name)?.unwrap_or(name)
+            };
+            let flag = flags::find(name);
+            match value {
+                Some(value) if flag.is_some_and(|f| f.value.is_some()) => {
+                    expanded_args.push(name.to_owned());
+                    expanded_args.push(value.to_owned());
+                }
+                Some(_) => expanded_args.push(arg.clone()),
+                None => expanded_args.push(name.to_owned()),
+            }
+            // the value of a flag is never resolved, even if it starts with `--`
+            let takes_value = flag.is_some_and(|f| f.value.is_some()) || flags::is_cargo_value_flag(name);
+            if value.is_none() && takes_value {
+                expanded_args.extend(args.next());
+            }
+        }
+        let args = expanded_args;
+
+        // in multi-target mode, `--target` may be repeated and is passed to cargo
+        // separately for each target
+        let multi_target = args.iter().take_while(|a| *a != "--").any(|a| a == "--multi-target");
+
+        let mut seen_flags = BTreeSet::new();
         let mut arg_iter = args.into_iter();
         while let Some(arg) = arg_iter.next() {
             if run_args_started {
                 run_args.push(arg);
                 continue;
             }
+            if let Some(flag) = flags::find(&arg) {
+                if !flag.commands.contains(&command) {
+                    Err(format_err!("`{}` is not supported by `bootimage {}`", arg, command))?
+                }
+                let repeatable = flag.repeatable || (multi_target && flag.name == "--target");
+                if !repeatable && !seen_flags.insert(flag.name) {
+                    Err(format_err!("`{}` can only be passed once", flag.name))?
+                }
+            }
             match arg.as_ref() {
                 "--help" | "-h" => {
-                    return Command::BuildHelp;
+                    return Ok(Command::BuildHelp);
                 }
                 "--version" => {
-                    return Command::Version;
+                    return Ok(Command::Version);
                 }
                 "--bin" => {
-                    let next = arg_iter.next();
-                    set(&mut bin_name, next.clone());
+                    let next = non_empty("--bin", arg_iter.next())?;
+                    set(&mut bin_name, next.clone())?;
                     cargo_args.push(arg);
                     if let Some(next) = next {
                         cargo_args.push(next);
                     }
                 }
-                _ if arg.starts_with("--bin=") => {
-                    set(
-                        &mut bin_name,
-                        Some(String::from(arg.trim_left_matches("--bin="))),
-                    );
+                "--example" => {
+                    let next = non_empty("--example", arg_iter.next())?;
+                    set(&mut example_name, next.clone())?;
                     cargo_args.push(arg);
+                    cargo_args.extend(next);
                 }
+                "--target" if multi_target => {
+                    push(&mut targets, non_empty("--target", arg_iter.next())?);
+                }
+                "--multi-target" => {}
                 "--target" => {
-                    let next = arg_iter.next();
-                    set(&mut target, next.clone());
+                    let next = non_empty("--target", arg_iter.next())?;
+                    set(&mut target, next.clone())?;
                     cargo_args.push(arg);
                     if let Some(next) = next {
                         cargo_args.push(next);
                     }
                 }
-                _ if arg.starts_with("--target=") => {
-                    set(
-                        &mut target,
-                        Some(String::from(arg.trim_left_matches("--target="))),
-                    );
+                "--package" | "-p" => {
+                    let next = non_empty("--package", arg_iter.next())?;
                     cargo_args.push(arg);
+                    cargo_args.extend(next);
                 }
                 "--manifest-path" => {
                     let next = arg_iter.next();
-                    set(&mut manifest_path, next.as_ref().map(|p| {
-                        Path::new(&p).canonicalize().expect("--manifest-path invalid")
-                    }));
+                    let path = next
+                        .as_ref()
+                        .map(|p| Path::new(p).canonicalize())
+                        .transpose()
+                        .context("--manifest-path invalid")?;
+                    set(&mut manifest_path, path)?;
                     cargo_args.push(arg);
                     if let Some(next) = next {
                         cargo_args.push(next);
                     }
                 }
-                _ if arg.starts_with("--manifest-path=") => {
-                    let path = Path::new(arg.trim_left_matches("--manifest-path="))
-                        .canonicalize().expect("--manifest-path invalid");
-                    set(&mut manifest_path, Some(path));
-                    cargo_args.push(arg);
+                "--manifest-out" => {
+                    let path = non_empty("--manifest-out", arg_iter.next())?;
+                    set(&mut manifest_out, path.map(PathBuf::from))?;
+                }
+                "--metadata-file" => {
+                    let next = non_empty("--metadata-file", arg_iter.next())?;
+                    set(&mut metadata_file, next.map(PathBuf::from))?;
                 }
                 "--release" => {
-                    set(&mut release, Some(true));
+                    set(&mut release, Some(true))?;
                     cargo_args.push(arg);
                 }
+                "--profile" => {
+                    let next = non_empty("--profile", arg_iter.next())?;
+                    set(&mut profile, next.clone())?;
+                    cargo_args.push(arg);
+                    cargo_args.extend(next);
+                }
+                "--emit-boot-map" => {
+                    let path = non_empty("--emit-boot-map", arg_iter.next())?;
+                    set(&mut emit_boot_map, path.map(PathBuf::from))?;
+                }
+                "--symbols-path" => {
+                    let path = non_empty("--symbols-path", arg_iter.next())?;
+                    set(&mut symbols_path, path.map(PathBuf::from))?;
+                }
+                "--junit" => {
+                    let path = non_empty("--junit", arg_iter.next())?;
+                    set(&mut junit, path.map(PathBuf::from))?;
+                }
+                "--tail" => {
+                    let lines = arg_iter
+                        .next()
+                        .map(|n| n.parse::<usize>())
+                        .transpose()
+                        .context("--tail invalid")?;
+                    set(&mut tail, lines)?;
+                }
                 "--update-bootloader" => {
-                    set(&mut update_bootloader, Some(true));
+                    set(&mut update_bootloader, Some(true))?;
+                }
+                "--strip" => {
+                    set(&mut strip, Some(true))?;
+                }
+                "--strict" => {
+                    set(&mut strict, Some(true))?;
+                }
+                "--nocapture" => {
+                    set(&mut nocapture, Some(true))?;
+                }
+                "--keep-image" => {
+                    set(&mut keep_image, Some(true))?;
+                }
+                "--no-keep-image" => {
+                    set(&mut keep_image, Some(false))?;
+                }
+                "--headless" | "--nographic" => {
+                    set(&mut headless, Some(true))?;
+                }
+                "--no-drive" => {
+                    set(&mut no_drive, Some(true))?;
+                }
+                "--quiet" | "-q" => {
+                    set(&mut quiet, Some(true))?;
+                    cargo_args.push(arg);
+                }
+                "--snapshot" => {
+                    set(&mut snapshot, Some(true))?;
+                }
+                "--print-pid" => {
+                    set(&mut print_pid, Some(true))?;
+                }
+                "--reuse-build" => {
+                    set(&mut reuse_build, Some(true))?;
+                }
+                "--no-build" => {
+                    set(&mut no_build, Some(true))?;
+                }
+                "--reproducible" => {
+                    set(&mut reproducible, Some(true))?;
+                }
+                "--feature-tagged-output" => {
+                    set(&mut feature_tagged_output, Some(true))?;
+                }
+                "--force" => {
+                    set(&mut force, Some(true))?;
+                }
+                "--print-target-dir" => {
+                    set(&mut print_target_dir, Some(true))?;
+                }
+                "--print-kernel-path" => {
+                    set(&mut print_kernel_path, Some(true))?;
+                }
+                "--list-tests" => {
+                    set(&mut list_tests, Some(true))?;
+                }
+                "--check-config" => {
+                    set(&mut check_config, Some(true))?;
+                }
+                "--serial-socket" => {
+                    set(&mut serial_socket, Some(true))?;
+                }
+                "--changed" => {
+                    set(&mut changed, Some(true))?;
+                }
+                "--profile-startup" => {
+                    set(&mut profile_startup, Some(true))?;
+                }
+                "--monitor" => {
+                    set(&mut monitor, Some(true))?;
+                }
+                "--monitor-port" => {
+                    let port = arg_iter
+                        .next()
+                        .map(|p| p.parse::<u16>())
+                        .transpose()
+                        .context("--monitor-port invalid")?;
+                    set(&mut monitor_port, port)?;
+                }
+                "--gdb" => {
+                    set(&mut gdb, Some(true))?;
+                }
+                "--gdb-port" => {
+                    let port = arg_iter
+                        .next()
+                        .map(|p| p.parse::<u16>())
+                        .transpose()
+                        .context("--gdb-port invalid")?;
+                    set(&mut gdb_port, port)?;
+                }
+                "--gdb-attach" => {
+                    set(&mut gdb_attach, Some(true))?;
+                }
+                "--max-image-size" => {
+                    let size = arg_iter
+                        .next()
+                        .map(|s| config::parse_size(&s))
+                        .transpose()
+                        .context("--max-image-size invalid")?;
+                    set(&mut max_image_size, size)?;
+                }
+                "--net" => {
+                    let mode = arg_iter.next().map(|m| Network::parse(&m)).transpose()?;
+                    set(&mut network, mode)?;
+                }
+                "--image-format" => {
+                    let format = arg_iter.next().map(|f| ImageFormat::parse(&f)).transpose()?;
+                    set(&mut image_format, format)?;
+                }
+                "--checksum" => {
+                    let algorithm = arg_iter.next().map(|a| ChecksumAlgorithm::parse(&a)).transpose()?;
+                    set(&mut checksum, algorithm)?;
+                }
+                "--partition-table" => {
+                    let table = arg_iter.next().map(|t| PartitionTable::parse(&t)).transpose()?;
+                    set(&mut partition_table, table)?;
+                }
+                "--net-device" => {
+                    set(&mut net_device, arg_iter.next())?;
+                }
+                "--pcap" => {
+                    let path = non_empty("--pcap", arg_iter.next())?
+                        .map(|p| pcap_path(&p))
+                        .transpose()?;
+                    set(&mut pcap, path)?;
+                }
+                "--qemu-log" => {
+                    let categories = arg_iter.next();
+                    if let Some(ref categories) = categories {
+                        if categories.split(',').any(str::is_empty) {
+                            Err(format_err!(
+                                "`--qemu-log` expects a comma-separated list of categories, got `{}`",
+                                categories
+                            ))?
+                        }
+                    }
+                    set(&mut qemu_log, categories)?;
+                }
+                "--qemu-log-file" => {
+                    let next = non_empty("--qemu-log-file", arg_iter.next())?;
+                    set(&mut qemu_log_file, next.map(PathBuf::from))?;
+                }
+                "--qemu-stdout" => {
+                    let routing = non_empty("--qemu-stdout", arg_iter.next())?.map(|r| OutputRouting::parse(&r));
+                    set(&mut qemu_stdout, routing)?;
+                }
+                "--qemu-stderr" => {
+                    let routing = non_empty("--qemu-stderr", arg_iter.next())?.map(|r| OutputRouting::parse(&r));
+                    set(&mut qemu_stderr, routing)?;
+                }
+                "--qemu-cwd" => {
+                    let next = arg_iter.next();
+                    let dir = next
+                        .as_ref()
+                        .map(|d| Path::new(d).canonicalize())
+                        .transpose()
+                        .context("--qemu-cwd invalid")?;
+                    if let Some(ref dir) = dir {
+                        if !dir.is_dir() {
+                            Err(format_err!("--qemu-cwd `{}` is not a directory", dir.display()))?
+                        }
+                    }
+                    set(&mut qemu_cwd, dir)?;
+                }
+                "--bios" => {
+                    let path = arg_iter
+                        .next()
+                        .map(|p| Path::new(&p).canonicalize().context(format_err!("--bios `{}` not found", p)))
+                        .transpose()?;
+                    set(&mut bios, path)?;
+                }
+                "--pflash" => {
+                    let path = arg_iter
+                        .next()
+                        .map(|p| Path::new(&p).canonicalize().context(format_err!("--pflash `{}` not found", p)))
+                        .transpose()?;
+                    push(&mut pflash, path);
+                    if pflash.len() > 2 {
+                        Err(format_err!(
+                            "`--pflash` can be passed at most twice (firmware code and variables)"
+                        ))?
+                    }
+                }
+                "--color" => {
+                    let next = arg_iter.next();
+                    let choice = next.as_ref().map(|c| ColorChoice::parse(c)).transpose()?;
+                    // set right away, so that it also applies to errors in later arguments
+                    if let Some(choice) = choice {
+                        color::set_choice(choice);
+                    }
+                    set(&mut color_choice, choice)?;
+                    cargo_args.push(arg);
+                    cargo_args.extend(next);
+                }
+                "--env" => {
+                    if let Some(var) = arg_iter.next() {
+                        let (key, value) = parse_env_var(&var)?;
+                        env_vars.insert(key, value);
+                    }
+                }
+                "--env-file" => {
+                    if let Some(path) = arg_iter.next() {
+                        env_file_vars.extend(parse_env_file(Path::new(&path))?);
+                    }
                 }
+                "--config" => {
+                    // other `--config` values (e.g. `build.rustflags=...` or a file) are
+                    // cargo's own
+                    if let Some(value) = arg_iter.next() {
+                        if value.trim_start().starts_with("bootimage.") {
+                            config_overrides.push(value);
+                        } else if command == "runner" {
+                            Err(format_err!(
+                                "`--config {}` is a cargo option, but `bootimage runner` doesn't run \
+                                 cargo (bootimage keys start with `bootimage.`)",
+                                value
+                            ))?
+                        } else {
+                            cargo_args.push(arg);
+                            cargo_args.push(value);
+                        }
+                    }
+                }
+                "--run-args" => {
+                    if let Some(words) = arg_iter.next() {
+                        let words = config::split_args(&words)
+                            .context(format_err!("Invalid `--run-args` `{}`", words))?;
+                        flag_run_args.extend(words);
+                    }
+                }
+                "--cargo-args" => {
+                    if let Some(words) = arg_iter.next() {
+                        let words = config::split_shell_words(&words)
+                            .context(format_err!("Invalid `--cargo-args` `{}`", words))?;
+                        // bootimage interprets these itself, so they must not bypass it
+                        let name = |word: &String| word.split('=').next().unwrap_or(word).to_owned();
+                        if let Some(word) = words.iter().find(|word| flags::find(&name(word)).is_some()) {
+                            Err(format_err!(
+                                "`{}` must be passed directly instead of through `--cargo-args`",
+                                name(word)
+                            ))?
+                        }
+                        cargo_args.extend(words);
+                    }
+                }
+                // only the first `--` is a delimiter; all following arguments, including
+                // further `--`, are passed to the run command unchanged
                 "--" => {
                     run_args_started = true;
                 }
+                // cargo invokes a runner as `RUNNER EXECUTABLE ARGS...`, so the first
+                // positional argument is the kernel and all following arguments (after an
+                // optional `--`) are passed to the run command
+                _ if command == "runner" => {
+                    if arg.starts_with('-') {
+                        Err(format_err!("`{}` is not supported by `bootimage runner`", arg))?
+                    }
+                    let path = Path::new(&arg)
+                        .canonicalize()
+                        .context(format_err!("kernel executable `{}` invalid", arg))?;
+                    kernel = Some(path);
+                    let mut rest = arg_iter.by_ref().peekable();
+                    if rest.peek().map(String::as_str) == Some("--") {
+                        rest.next();
+                    }
+                    run_args.extend(rest);
+                }
+                // unknown flags are passed to cargo in the order they were given, and the
+                // value of a cargo flag stays right after it
                 _ => {
+                    let takes_value = flags::is_cargo_value_flag(&arg);
                     cargo_args.push(arg);
+                    if takes_value {
+                        cargo_args.extend(arg_iter.next());
+                    }
                 }
             };
         }
     }
 
-    Command::Build(Args {
+    if command == "runner" && kernel.is_none() {
+        Err(format_err!(
+            "missing kernel executable for `bootimage runner` (it is passed by cargo when \
+             bootimage is configured as runner)"
+        ))?
+    }
+
+    if bin_name.is_some() && example_name.is_some() {
+        Err(format_err!("`--bin` and `--example` can't be used together"))?
+    }
+
+    // `--no-build` assembles from existing artifacts, so nothing may be re-resolved or rebuilt
+    if no_build.is_some() && update_bootloader.is_some() {
+        Err(format_err!("`--no-build` and `--update-bootloader` can't be used together"))?
+    }
+    if no_build.is_some() && reuse_build.is_some() {
+        Err(format_err!("`--no-build` and `--reuse-build` can't be used together"))?
+    }
+
+    if release.is_some() && profile.is_some() {
+        Err(format_err!("`--release` and `--profile` can't be used together"))?
+    }
+
+    if net_device.is_some() {
+        match network {
+            None => network = Some(Network::User),
+            Some(Network::User) => {}
+            Some(Network::None) => Err(format_err!("`--net-device` can't be used with `--net none`"))?,
+        }
+    }
+    if pcap.is_some() {
+        match network {
+            None => network = Some(Network::User),
+            Some(Network::User) => {}
+            Some(Network::None) => Err(format_err!("`--pcap` can't be used with `--net none`"))?,
+        }
+    }
+
+    // gdb runs in the foreground, so bootimage can't read the output of QEMU meanwhile
+    let captures = [&qemu_stdout, &qemu_stderr]
+        .iter()
+        .any(|routing| **routing == Some(OutputRouting::Capture));
+    if gdb_attach.is_some() && captures {
+        Err(format_err!("`--qemu-stdout capture` and `--qemu-stderr capture` can't be used with `--gdb-attach`"))?
+    }
+
+    // both replace the default firmware of QEMU
+    if bios.is_some() && !pflash.is_empty() {
+        Err(format_err!("`--bios` and `--pflash` can't be used together"))?
+    }
+
+    if !targets.is_empty() && (print_target_dir.is_some() || print_kernel_path.is_some()) {
+        Err(format_err!("`--print-target-dir` and `--print-kernel-path` can't be used with `--multi-target`"))?
+    }
+
+    // the arguments of `--run-args` come before the ones after `--`
+    flag_run_args.extend(run_args);
+    let run_args = expand_arg_files(flag_run_args, 0)?;
+
+    // `--env` takes precedence over `--env-file`, regardless of the order
+    env_file_vars.extend(env_vars);
+    let env_vars = env_file_vars;
+    let args = Args {
+        command: command.to_owned(),
+        targets,
+        config_overrides,
+        env_vars,
         cargo_args,
         run_args,
         bin_name,
+        example_name,
         target,
         manifest_path,
+        manifest_out,
+        metadata_file,
+        junit,
+        tail: tail.unwrap_or(DEFAULT_TAIL),
+        emit_boot_map,
+        symbols_path,
         release: release.unwrap_or(false),
+        profile,
         update_bootloader: update_bootloader.unwrap_or(false),
-    })
+        strip: strip.unwrap_or(false),
+        strict: strict.unwrap_or(false),
+        nocapture: nocapture.unwrap_or(false),
+        keep_image: keep_image.unwrap_or(true),
+        headless: headless.unwrap_or(false),
+        no_drive: no_drive.unwrap_or(false),
+        quiet: quiet.unwrap_or(false),
+        snapshot: snapshot.unwrap_or(false),
+        print_pid: print_pid.unwrap_or(false),
+        reuse_build: reuse_build.unwrap_or(false),
+        no_build: no_build.unwrap_or(false),
+        reproducible: reproducible.unwrap_or(false),
+        feature_tagged_output: feature_tagged_output.unwrap_or(false),
+        force: force.unwrap_or(false),
+        print_target_dir: print_target_dir.unwrap_or(false),
+        print_kernel_path: print_kernel_path.unwrap_or(false),
+        list_tests: list_tests.unwrap_or(false),
+        check_config: check_config.unwrap_or(false),
+        serial_socket: serial_socket.unwrap_or(false),
+        changed: changed.unwrap_or(false),
+        monitor: monitor.unwrap_or(false) || monitor_port.is_some(),
+        monitor_port: monitor_port.unwrap_or(DEFAULT_MONITOR_PORT),
+        max_image_size,
+        gdb: gdb.unwrap_or(false) || gdb_port.is_some() || gdb_attach.is_some(),
+        gdb_port: gdb_port.unwrap_or(DEFAULT_GDB_PORT),
+        gdb_attach: gdb_attach.unwrap_or(false),
+        network,
+        image_format: image_format.unwrap_or(ImageFormat::Raw),
+        checksum,
+        partition_table: partition_table.unwrap_or(PartitionTable::None),
+        net_device: net_device.unwrap_or_else(|| String::from(DEFAULT_NET_DEVICE)),
+        pcap,
+        qemu_cwd,
+        bios,
+        pflash,
+        qemu_log,
+        qemu_log_file,
+        qemu_stdout,
+        qemu_stderr,
+        kernel,
+    };
+    if profile_startup.is_some() {
+        timings::enable(args.message_format_json());
+    }
+    Ok(Command::Build(args))
+}
+
+/// Hashes `data` with FNV-1a, since the hashers of `std` are not guaranteed to be stable.
+fn stable_hash(data: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Replaces each `@path` run argument with the arguments in the file at `path`.
+///
+/// The file is split like a shell would (see `config::split_args`), so arguments can span
+/// lines and be quoted, and lines starting with `#` are comments. The arguments of a
+/// file can contain further `@path` arguments, up to `MAX_ARG_FILE_DEPTH` levels deep.
+/// `@@` at the start of an argument is passed on as a literal `@`.
+fn expand_arg_files(args: Vec<String>, depth: usize) -> Result<Vec<String>, Error> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        if arg.starts_with("@@") {
+            expanded.push(arg[1..].to_owned());
+            continue;
+        }
+        let path = match arg.strip_prefix('@') {
+            Some(path) if !path.is_empty() => path,
+            _ => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        if depth == MAX_ARG_FILE_DEPTH {
+            Err(format_err!(
+                "argument file `{}` is nested more than {} levels deep (does it include itself?)",
+                path, MAX_ARG_FILE_DEPTH
+            ))?
+        }
+        let content = fs::read_to_string(path).context(format_err!("Failed to read argument file `{}`", path))?;
+        let content: Vec<&str> = content
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .collect();
+        let file_args = config::split_args(&content.join("\n"))
+            .context(format_err!("Invalid argument file `{}`", path))?;
+        expanded.extend(expand_arg_files(file_args, depth + 1)?);
+    }
+    Ok(expanded)
+}
+
+/// Checks that the directory of the `--pcap` file exists and returns the absolute path of
+/// the file.
+///
+/// The file itself is left to QEMU, so that an existing capture isn't overwritten by runs
+/// that don't start QEMU (e.g. `--check-config`). The path is made absolute, since QEMU
+/// might run in another directory (`--qemu-cwd`).
+fn pcap_path(path: &str) -> Result<PathBuf, Error> {
+    let path = Path::new(path);
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name,
+        None => Err(format_err!("--pcap `{}` is not a file path", path.display()))?,
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let dir = dir
+        .canonicalize()
+        .context(format_err!("the directory of --pcap `{}` doesn't exist", path.display()))?;
+    if !dir.is_dir() {
+        Err(format_err!("the directory of --pcap `{}` is not a directory", path.display()))?
+    }
+    Ok(dir.join(file_name))
 }
 
-#[derive(Debug, Clone)]
+/// Parses a `--env KEY=VALUE` argument.
+///
+/// The value may be empty and may contain further `=`, but the key must not be empty.
+fn parse_env_var(var: &str) -> Result<(String, String), Error> {
+    match var.find('=') {
+        Some(0) => Err(format_err!("`--env` argument `{}` has an empty key", var)),
+        Some(index) => Ok((var[..index].to_owned(), var[index + 1..].to_owned())),
+        None => Err(format_err!("`--env` argument `{}` must be of the form `KEY=VALUE`", var)),
+    }
+}
+
+/// Reads the environment variables of an `--env-file`.
+///
+/// Each line is a `KEY=VALUE` pair; blank lines and lines starting with `#` are ignored.
+/// Whitespace around the key and the value is removed. A value in single or double quotes
+/// is unquoted like a shell would (see `config::split_shell_words`), so it can contain
+/// spaces, leading whitespace, or a `#`; other values are taken literally.
+fn parse_env_file(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let content = fs::read_to_string(path).context(format_err!("Failed to read env file `{}`", path.display()))?;
+    let mut vars = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let location = format!("{}:{}", path.display(), index + 1);
+        let (key, value) = match line.find('=') {
+            Some(separator) => (line[..separator].trim(), line[separator + 1..].trim()),
+            None => Err(format_err!("{}: expected `KEY=VALUE`, got `{}`", location, line))?,
+        };
+        if key.is_empty() {
+            Err(format_err!("{}: `{}` has an empty key", location, line))?
+        }
+        let value = if value.starts_with('"') || value.starts_with('\'') {
+            let mut words = config::split_shell_words(value)
+                .context(format_err!("{}: invalid quoted value `{}`", location, value))?;
+            if words.len() != 1 {
+                Err(format_err!("{}: unexpected text after the quoted value `{}`", location, value))?
+            }
+            words.remove(0)
+        } else {
+            value.to_owned()
+        };
+        vars.push((key.to_owned(), value));
+    }
+    Ok(vars)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct Args {
+    /// The subcommand, e.g. `build` or `run` (not present in `cargo_args`).
+    command: String,
     /// All arguments that are passed to cargo.
     pub cargo_args: Vec<String>,
     /// All arguments that are passed to the runner.
     pub run_args: Vec<String>,
     /// The manifest path (also present in `cargo_args`).
     manifest_path: Option<PathBuf>,
+    /// The path of the JSON file listing the created bootimages (not present in `cargo_args`).
+    manifest_out: Option<PathBuf>,
+    /// The path of a JSON file to read instead of running `cargo metadata` (not present in
+    /// `cargo_args`).
+    metadata_file: Option<PathBuf>,
+    /// The path of the JUnit XML report of `bootimage test` (not present in `cargo_args`).
+    junit: Option<PathBuf>,
+    /// The number of serial output lines printed for a failed test (not present in `cargo_args`).
+    tail: usize,
+    /// The path of the JSON description of the image layout (not present in `cargo_args`).
+    emit_boot_map: Option<PathBuf>,
+    /// The path that the unstripped kernel is copied to (not present in `cargo_args`).
+    symbols_path: Option<PathBuf>,
     /// The name of the binary (passed `--bin` argument) (also present in `cargo_args`).
     bin_name: Option<String>,
+    /// The name of the example (passed `--example` argument) (also present in `cargo_args`).
+    example_name: Option<String>,
     /// The target triple (also present in `cargo_args`).
     target: Option<String>,
+    /// All targets passed in `--multi-target` mode (not present in `cargo_args`).
+    targets: Vec<String>,
+    /// The `--config bootimage.KEY=VALUE` overrides (not present in `cargo_args`).
+    config_overrides: Vec<String>,
+    /// The `--env KEY=VALUE` variables for the kernel build and the run command (not
+    /// present in `cargo_args`).
+    env_vars: BTreeMap<String, String>,
     /// The release flag (also present in `cargo_args`).
     release: bool,
+    /// The cargo profile passed through `--profile` (also present in `cargo_args`).
+    profile: Option<String>,
     /// Whether the bootloader should be updated (not present in `cargo_args`).
     update_bootloader: bool,
+    /// Whether debug info should be stripped from the kernel (not present in `cargo_args`).
+    strip: bool,
+    /// Whether an incompatible bootloader version is an error instead of a warning (not
+    /// present in `cargo_args`).
+    strict: bool,
+    /// Whether the serial output of tests should always be streamed (not present in `cargo_args`).
+    nocapture: bool,
+    /// Whether the bootimage of each test should be kept after it ran (not present in `cargo_args`).
+    keep_image: bool,
+    /// Whether QEMU should be run without a display (not present in `cargo_args`).
+    headless: bool,
+    /// Whether the `-drive` argument for the bootimage should be omitted (not present in `cargo_args`).
+    no_drive: bool,
+    /// Whether warnings about the run command should be suppressed (also present in `cargo_args`).
+    quiet: bool,
+    /// Whether QEMU should discard all disk writes on exit (not present in `cargo_args`).
+    snapshot: bool,
+    /// Whether the process id of QEMU should be printed after spawning it (not present in `cargo_args`).
+    print_pid: bool,
+    /// Whether an up-to-date kernel should be used without running cargo (not present in `cargo_args`).
+    reuse_build: bool,
+    /// Whether the kernel should never be built by cargo (not present in `cargo_args`).
+    no_build: bool,
+    /// Whether `--reproducible` was passed (not present in `cargo_args`).
+    reproducible: bool,
+    /// Whether the bootimage name should contain a hash of the cargo features (not present
+    /// in `cargo_args`).
+    feature_tagged_output: bool,
+    /// Whether the bootimage should be assembled even if it is up to date (not present in `cargo_args`).
+    force: bool,
+    /// Whether the target directory should be printed instead of building (not present in `cargo_args`).
+    print_target_dir: bool,
+    /// Whether the kernel path should be printed instead of building (not present in `cargo_args`).
+    print_kernel_path: bool,
+    /// Whether the integration tests should be listed instead of run (not present in `cargo_args`).
+    list_tests: bool,
+    /// Whether the configuration should be checked instead of building (not present in `cargo_args`).
+    check_config: bool,
+    /// Whether the serial output of tests is read from a Unix domain socket (not present in `cargo_args`).
+    serial_socket: bool,
+    /// Whether only tests that changed since their last successful run are run (not present in `cargo_args`).
+    changed: bool,
+    /// Whether the QEMU monitor should be exposed on a TCP port (not present in `cargo_args`).
+    monitor: bool,
+    /// The TCP port of the QEMU monitor (not present in `cargo_args`).
+    monitor_port: u16,
+    /// The maximum size of the bootimage in bytes (not present in `cargo_args`).
+    max_image_size: Option<u64>,
+    /// Whether QEMU should wait for a gdb connection on startup (not present in `cargo_args`).
+    gdb: bool,
+    /// The TCP port of the QEMU gdb stub (not present in `cargo_args`).
+    gdb_port: u16,
+    /// Whether gdb should be launched and attached to QEMU (not present in `cargo_args`).
+    gdb_attach: bool,
+    /// The network setup of QEMU, if any (not present in `cargo_args`).
+    network: Option<Network>,
+    /// The emulated network card for `--net user` (not present in `cargo_args`).
+    net_device: String,
+    /// The file that the network traffic of QEMU is captured to (not present in `cargo_args`).
+    pcap: Option<PathBuf>,
+    /// The container format of the bootimage (not present in `cargo_args`).
+    image_format: ImageFormat,
+    /// The digest algorithm of the checksum file of the bootimage (not present in `cargo_args`).
+    checksum: Option<ChecksumAlgorithm>,
+    /// The partition table written into the bootimage (not present in `cargo_args`).
+    partition_table: PartitionTable,
+    /// The working directory of QEMU (not present in `cargo_args`).
+    qemu_cwd: Option<PathBuf>,
+    /// The firmware image passed through `-bios` (not present in `cargo_args`).
+    bios: Option<PathBuf>,
+    /// The firmware flash images (code and optionally variables) passed as pflash drives
+    /// (not present in `cargo_args`).
+    pflash: Vec<PathBuf>,
+    /// The QEMU log categories passed through `-d` (not present in `cargo_args`).
+    qemu_log: Option<String>,
+    /// The QEMU log file passed through `-D` (not present in `cargo_args`).
+    qemu_log_file: Option<PathBuf>,
+    /// Where the stdout of QEMU goes, if not the default (not present in `cargo_args`).
+    qemu_stdout: Option<OutputRouting>,
+    /// Where the stderr of QEMU goes, if not the default (not present in `cargo_args`).
+    qemu_stderr: Option<OutputRouting>,
+    /// The kernel executable passed to `bootimage runner` by cargo (not present in `cargo_args`).
+    kernel: Option<PathBuf>,
+}
+
+/// The network setup of QEMU selected through `--net`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    /// User mode networking with an emulated network card.
+    User,
+    /// No network card at all.
+    None,
+}
+
+impl Network {
+    fn parse(mode: &str) -> Result<Network, Error> {
+        match mode {
+            "user" => Ok(Network::User),
+            "none" => Ok(Network::None),
+            other => Err(format_err!("invalid `--net` mode `{}` (expected `user` or `none`)", other)),
+        }
+    }
+}
+
+/// Where an output stream of QEMU goes, selected through `--qemu-stdout` or `--qemu-stderr`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputRouting {
+    /// The stream of bootimage is inherited.
+    Inherit,
+    /// The stream is read by bootimage and only printed if QEMU (or the test) fails.
+    Capture,
+    /// The stream is written to the given file, which is truncated first.
+    File(PathBuf),
+}
+
+impl OutputRouting {
+    /// Parses `inherit`, `capture`, or else a file path (e.g. `./capture` for a file
+    /// named `capture`).
+    fn parse(routing: &str) -> OutputRouting {
+        match routing {
+            "inherit" => OutputRouting::Inherit,
+            "capture" => OutputRouting::Capture,
+            path => OutputRouting::File(PathBuf::from(path)),
+        }
+    }
+}
+
+/// The container format of the bootimage selected through `--image-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// The raw disk image created by bootimage.
+    Raw,
+    /// The VMware format (also supported by VirtualBox).
+    Vmdk,
+    /// The native format of QEMU.
+    Qcow2,
+}
+
+impl ImageFormat {
+    fn parse(format: &str) -> Result<ImageFormat, Error> {
+        match format {
+            "raw" => Ok(ImageFormat::Raw),
+            "vmdk" => Ok(ImageFormat::Vmdk),
+            "qcow2" => Ok(ImageFormat::Qcow2),
+            other => Err(format_err!(
+                "invalid `--image-format` `{}` (expected `raw`, `vmdk`, or `qcow2`)",
+                other
+            )),
+        }
+    }
+
+    /// The name of the format for `qemu-img`, which is also used as file extension.
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Vmdk => "vmdk",
+            ImageFormat::Qcow2 => "qcow2",
+        }
+    }
+}
+
+/// The digest algorithm of the checksum file selected through `--checksum`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    fn parse(algorithm: &str) -> Result<ChecksumAlgorithm, Error> {
+        match algorithm {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            other => Err(format_err!(
+                "invalid `--checksum` `{}` (expected `sha256` or `blake3`)",
+                other
+            )),
+        }
+    }
+
+    /// The name of the algorithm, which is also used as file extension of the checksum file.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+/// The partition table of the bootimage selected through `--partition-table`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionTable {
+    /// A flat image without partition table.
+    None,
+    /// An MBR partition table in the first sector, for BIOS firmware.
+    Mbr,
+    /// A GUID partition table with an EFI system partition, for UEFI firmware.
+    Gpt,
+}
+
+impl PartitionTable {
+    fn parse(table: &str) -> Result<PartitionTable, Error> {
+        match table {
+            "none" => Ok(PartitionTable::None),
+            "mbr" => Ok(PartitionTable::Mbr),
+            "gpt" => Ok(PartitionTable::Gpt),
+            other => Err(format_err!(
+                "invalid `--partition-table` `{}` (expected `none`, `mbr`, or `gpt`)",
+                other
+            )),
+        }
+    }
 }
 
 impl Args {
+    /// Returns a stable hash of the arguments that affect the created bootimage.
+    ///
+    /// Arguments that only affect how the bootimage is run (e.g. `run_args` or `--headless`)
+    /// are not included. The hash is the same across bootimage invocations and platforms,
+    /// so it can be stored to detect changed build configurations.
+    pub fn fingerprint(&self) -> String {
+        let build_args = json!({
+            "cargo-args": self.cargo_args,
+            "manifest-path": self.manifest_path,
+            "bin-name": self.bin_name,
+            "example-name": self.example_name,
+            "target": self.target,
+            "targets": self.targets,
+            "release": self.release,
+            "profile": self.profile,
+            "strip": self.strip,
+            "image-format": self.image_format,
+            "partition-table": self.partition_table,
+            "feature-tagged-output": self.feature_tagged_output,
+            "reproducible": self.reproducible(),
+            "config-overrides": self.config_overrides,
+            "env": self.env_vars,
+        });
+        format!("{:016x}", stable_hash(&build_args.to_string()))
+    }
+
+    /// Returns a short hash of the selected cargo features for `--feature-tagged-output`.
+    ///
+    /// The features are sorted and deduplicated first, so the tag only depends on the set
+    /// of features (and `--all-features` and `--no-default-features`), not on their order.
+    pub fn feature_tag(&self) -> String {
+        let selection = self.feature_selection();
+        let mut features = selection.features;
+        features.sort();
+        features.dedup();
+        let feature_set = json!({
+            "features": features,
+            "all-features": selection.all_features,
+            "no-default-features": selection.no_default_features,
+        });
+        format!("{:08x}", stable_hash(&feature_set.to_string()) as u32)
+    }
+
+    pub fn feature_tagged_output(&self) -> bool {
+        self.feature_tagged_output
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
     pub fn manifest_path(&self) -> &Option<PathBuf> {
         &self.manifest_path
     }
 
+    pub fn manifest_out(&self) -> &Option<PathBuf> {
+        &self.manifest_out
+    }
+
+    pub fn metadata_file(&self) -> &Option<PathBuf> {
+        &self.metadata_file
+    }
+
+    pub fn junit(&self) -> &Option<PathBuf> {
+        &self.junit
+    }
+
+    pub fn tail(&self) -> usize {
+        self.tail
+    }
+
+    pub fn emit_boot_map(&self) -> &Option<PathBuf> {
+        &self.emit_boot_map
+    }
+
+    pub fn symbols_path(&self) -> &Option<PathBuf> {
+        &self.symbols_path
+    }
+
     pub fn bin_name(&self) -> &Option<String> {
         &self.bin_name
     }
 
+    pub fn example_name(&self) -> &Option<String> {
+        &self.example_name
+    }
+
     pub fn target(&self) -> &Option<String> {
         &self.target
     }
 
-    pub fn release(&self) -> bool {
-        self.release
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+
+    pub fn config_overrides(&self) -> &[String] {
+        &self.config_overrides
+    }
+
+    pub fn env_vars(&self) -> &BTreeMap<String, String> {
+        &self.env_vars
+    }
+
+    /// Returns the cargo features selected through the cargo arguments.
+    ///
+    /// The features of `--features` (or `-F`) can be separated by commas or spaces.
+    pub fn feature_selection(&self) -> config::FeatureSelection {
+        let mut selection = config::FeatureSelection::default();
+        let mut arg_iter = self.cargo_args.iter();
+        while let Some(arg) = arg_iter.next() {
+            let features = match arg.as_str() {
+                "--features" | "-F" => arg_iter.next().map(String::as_str),
+                "--all-features" => {
+                    selection.all_features = true;
+                    None
+                }
+                "--no-default-features" => {
+                    selection.no_default_features = true;
+                    None
+                }
+                arg => arg.strip_prefix("--features=").or_else(|| arg.strip_prefix("-F")),
+            };
+            if let Some(features) = features {
+                let features = features.split(|c: char| c == ',' || c.is_whitespace());
+                selection
+                    .features
+                    .extend(features.filter(|f| !f.is_empty()).map(String::from));
+            }
+        }
+        selection
+    }
+
+    /// Returns whether `--keep-going` is passed to cargo, in which case `bootimage test`
+    /// also runs the remaining tests after a test failed to build.
+    pub fn keep_going(&self) -> bool {
+        self.cargo_args.iter().any(|arg| arg == "--keep-going")
+    }
+
+    /// Returns whether cargo must not access the network, because `--offline` or
+    /// `--frozen` is passed or `CARGO_NET_OFFLINE` is set to `true`.
+    pub fn offline(&self) -> bool {
+        let offline_arg = self
+            .cargo_args
+            .iter()
+            .any(|arg| arg == "--offline" || arg == "--frozen");
+        offline_arg || env::var("CARGO_NET_OFFLINE").map(|v| v == "true").unwrap_or(false)
+    }
+
+    /// Returns the name of the subdirectory of the target directory that contains the
+    /// artifacts of the selected profile.
+    ///
+    /// This is the profile name, except for the built-in `dev` and `test` profiles (which
+    /// use `debug`) and the `bench` profile (which uses `release`).
+    pub fn profile_dir(&self) -> &str {
+        match self.profile.as_deref() {
+            Some("dev") | Some("test") => "debug",
+            Some("bench") => "release",
+            Some(profile) => profile,
+            None if self.release => "release",
+            None => "debug",
+        }
     }
 
     pub fn update_bootloader(&self) -> bool {
         self.update_bootloader
     }
 
-    pub fn set_target(&mut self, target: String) {
-        assert!(self.target.is_none());
+    pub fn strip(&self) -> bool {
+        self.strip
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn nocapture(&self) -> bool {
+        self.nocapture
+    }
+
+    pub fn keep_image(&self) -> bool {
+        self.keep_image
+    }
+
+    pub fn headless(&self) -> bool {
+        self.headless
+    }
+
+    pub fn no_drive(&self) -> bool {
+        self.no_drive
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn snapshot(&self) -> bool {
+        self.snapshot
+    }
+
+    pub fn print_pid(&self) -> bool {
+        self.print_pid
+    }
+
+    pub fn reuse_build(&self) -> bool {
+        self.reuse_build
+    }
+
+    pub fn no_build(&self) -> bool {
+        self.no_build
+    }
+
+    /// Returns whether the bootimage should be built reproducibly, because `--reproducible`
+    /// is passed or `SOURCE_DATE_EPOCH` is set.
+    pub fn reproducible(&self) -> bool {
+        self.reproducible || env::var_os("SOURCE_DATE_EPOCH").is_some()
+    }
+
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    pub fn print_target_dir(&self) -> bool {
+        self.print_target_dir
+    }
+
+    pub fn print_kernel_path(&self) -> bool {
+        self.print_kernel_path
+    }
+
+    pub fn list_tests(&self) -> bool {
+        self.list_tests
+    }
+
+    pub fn check_config(&self) -> bool {
+        self.check_config
+    }
+
+    pub fn serial_socket(&self) -> bool {
+        self.serial_socket
+    }
+
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Returns whether a JSON `--message-format` (e.g. `json` or
+    /// `json-render-diagnostics`) is passed to cargo.
+    pub fn message_format_json(&self) -> bool {
+        let mut cargo_args = self.cargo_args.iter();
+        while let Some(arg) = cargo_args.next() {
+            let format = if arg == "--message-format" {
+                cargo_args.next().map(String::as_str)
+            } else {
+                arg.strip_prefix("--message-format=")
+            };
+            if format.is_some_and(|f| f.starts_with("json")) {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn monitor(&self) -> bool {
+        self.monitor
+    }
+
+    pub fn monitor_port(&self) -> u16 {
+        self.monitor_port
+    }
+
+    pub fn max_image_size(&self) -> Option<u64> {
+        self.max_image_size
+    }
+
+    pub fn gdb(&self) -> bool {
+        self.gdb
+    }
+
+    pub fn gdb_port(&self) -> u16 {
+        self.gdb_port
+    }
+
+    pub fn gdb_attach(&self) -> bool {
+        self.gdb_attach
+    }
+
+    pub fn network(&self) -> Option<Network> {
+        self.network
+    }
+
+    pub fn net_device(&self) -> &str {
+        &self.net_device
+    }
+
+    pub fn pcap(&self) -> &Option<PathBuf> {
+        &self.pcap
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        self.image_format
+    }
+
+    pub fn checksum(&self) -> Option<ChecksumAlgorithm> {
+        self.checksum
+    }
+
+    pub fn partition_table(&self) -> PartitionTable {
+        self.partition_table
+    }
+
+    pub fn qemu_cwd(&self) -> &Option<PathBuf> {
+        &self.qemu_cwd
+    }
+
+    pub fn bios(&self) -> &Option<PathBuf> {
+        &self.bios
+    }
+
+    pub fn pflash(&self) -> &[PathBuf] {
+        &self.pflash
+    }
+
+    pub fn qemu_log(&self) -> &Option<String> {
+        &self.qemu_log
+    }
+
+    pub fn qemu_log_file(&self) -> &Option<PathBuf> {
+        &self.qemu_log_file
+    }
+
+    pub fn qemu_stdout(&self) -> &Option<OutputRouting> {
+        &self.qemu_stdout
+    }
+
+    pub fn qemu_stderr(&self) -> &Option<OutputRouting> {
+        &self.qemu_stderr
+    }
+
+    pub fn kernel(&self) -> &Option<PathBuf> {
+        &self.kernel
+    }
+
+    /// Selects the target chosen by bootimage, which fails if `--target` was passed.
+    pub fn set_target(&mut self, target: String) -> Result<(), Error> {
+        if let Some(ref existing) = self.target {
+            Err(format_err!(
+                "`--target {}` conflicts with the target `{}` selected by bootimage",
+                existing, target
+            ))?
+        }
         self.target = Some(target.clone());
         self.cargo_args.push("--target".into());
         self.cargo_args.push(target);
+        Ok(())
     }
 
-    pub fn set_bin_name(&mut self, bin_name: String) {
-        assert!(self.bin_name.is_none());
+    /// Selects the kernel binary chosen by bootimage, which fails if `--bin` was passed.
+    pub fn set_bin_name(&mut self, bin_name: String) -> Result<(), Error> {
+        if let Some(ref existing) = self.bin_name {
+            Err(format_err!(
+                "`--bin {}` conflicts with the kernel binary `{}` selected by bootimage",
+                existing, bin_name
+            ))?
+        }
         self.bin_name = Some(bin_name.clone());
         self.cargo_args.push("--bin".into());
         self.cargo_args.push(bin_name);
+        Ok(())
+    }
+}
+
+/// Returns the arguments of `bootimage build` with the given flags.
+#[cfg(test)]
+pub(crate) fn build_args(args: &[&str]) -> Args {
+    match parse_build_args("build", args.iter().map(|arg| arg.to_string())).unwrap() {
+        Command::Build(args) => args,
+        _ => panic!("expected the arguments of a build"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn parse(command: &'static str, args: &[&str]) -> Result<Args, Error> {
+        match parse_build_args(command, args.iter().map(|arg| arg.to_string()))? {
+            Command::Build(args) => Ok(args),
+            _ => panic!("expected the arguments of a build"),
+        }
+    }
+
+    #[test]
+    fn config_with_bootimage_prefix_is_override() {
+        let args = parse("build", &["--config", "bootimage.strip=true"]).unwrap();
+        assert_eq!(args.config_overrides(), ["bootimage.strip=true"]);
+        assert!(args.cargo_args.is_empty());
+    }
+
+    #[test]
+    fn other_config_is_passed_to_cargo() {
+        let rustflags = "build.rustflags=[\"-Cdebuginfo=0\"]";
+        let args = parse("build", &["--config", rustflags, "--config=net.offline=true"]).unwrap();
+        assert!(args.config_overrides().is_empty());
+        assert_eq!(args.cargo_args, ["--config", rustflags, "--config", "net.offline=true"]);
+    }
+
+    fn parse_error(command: &'static str, args: &[&str]) -> String {
+        match parse(command, args) {
+            Ok(_) => panic!("expected {:?} to be rejected", args),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn empty_flag_value_with_equals_sign_is_rejected() {
+        assert_eq!(parse_error("build", &["--bin="]), "'--bin' value must not be empty");
+        assert_eq!(parse_error("build", &["--profile="]), "'--profile' value must not be empty");
+        assert_eq!(parse_error("build", &["--package="]), "'--package' value must not be empty");
+        assert_eq!(parse_error("build", &["-p="]), "'--package' value must not be empty");
+        assert_eq!(parse_error("build", &["--manifest-out="]), "'--manifest-out' value must not be empty");
+        assert_eq!(parse_error("build", &["--emit-boot-map="]), "'--emit-boot-map' value must not be empty");
+        assert_eq!(parse_error("test", &["--junit="]), "'--junit' value must not be empty");
+    }
+
+    #[test]
+    fn empty_flag_value_as_separate_argument_is_rejected() {
+        assert_eq!(parse_error("build", &["--bin", ""]), "'--bin' value must not be empty");
+        assert_eq!(parse_error("build", &["--example", ""]), "'--example' value must not be empty");
+        assert_eq!(parse_error("build", &["--target", ""]), "'--target' value must not be empty");
+        assert_eq!(parse_error("build", &["-p", ""]), "'--package' value must not be empty");
+        assert_eq!(parse_error("build", &["--package", ""]), "'--package' value must not be empty");
+        assert_eq!(parse_error("test", &["--manifest-out", ""]), "'--manifest-out' value must not be empty");
+        assert_eq!(parse_error("build", &["--emit-boot-map", ""]), "'--emit-boot-map' value must not be empty");
+        assert_eq!(parse_error("test", &["--junit", ""]), "'--junit' value must not be empty");
+    }
+
+    #[test]
+    fn non_empty_flag_value_is_accepted() {
+        let args = parse("build", &["--bin=kernel"]).unwrap();
+        assert_eq!(*args.bin_name(), Some(String::from("kernel")));
+    }
+
+    #[test]
+    fn only_first_double_dash_starts_run_args() {
+        let args = parse("run", &["--release", "--", "-a", "--", "-b"]).unwrap();
+        assert_eq!(args.run_args, ["-a", "--", "-b"]);
+        assert_eq!(args.cargo_args, ["--release"]);
+    }
+
+    #[test]
+    fn double_dash_after_double_dash_is_run_arg() {
+        let args = parse("run", &["--", "--", "foo"]).unwrap();
+        assert_eq!(args.run_args, ["--", "foo"]);
+        assert!(args.cargo_args.is_empty());
+    }
+
+    fn executable() -> String {
+        env::current_exe().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn runner_drops_only_the_first_double_dash() {
+        let exe = executable();
+        let args = parse("runner", &[&exe, "--", "--", "-a"]).unwrap();
+        assert_eq!(args.run_args, ["--", "-a"]);
+        assert_eq!(*args.kernel(), Some(Path::new(&exe).canonicalize().unwrap()));
+    }
+
+    #[test]
+    fn runner_flags_after_the_executable_are_run_args() {
+        let exe = executable();
+        let args = parse("runner", &["--gdb-port=1234", &exe, "--gdb-port=5"]).unwrap();
+        assert_eq!(args.gdb_port(), 1234);
+        assert_eq!(args.run_args, ["--gdb-port=5"]);
+    }
+
+    #[test]
+    fn explicit_empty_run_arg_is_preserved() {
+        let args = parse("run", &["--", "-append", ""]).unwrap();
+        assert_eq!(args.run_args, ["-append", ""]);
+    }
+
+    #[test]
+    fn split_run_args_produce_no_empty_args() {
+        let args = parse("run", &["--run-args", "-m 256M '' ' '", "--", ""]).unwrap();
+        assert_eq!(args.run_args, ["-m", "256M", ""]);
+    }
+
+    #[test]
+    fn arg_file_produces_no_empty_args() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let path = dir.path().join("qemu.args");
+        fs::write(&path, "-m 256M ''\n\n' '\n-append 'a b'\n").unwrap();
+        let args = parse("run", &["--", &format!("@{}", path.display())]).unwrap();
+        assert_eq!(args.run_args, ["-m", "256M", "-append", "a b"]);
+    }
+
+    fn parse_argv(argv: &[&str]) -> Command {
+        parse_args_from(argv.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn run_args_of(command: Command) -> serde_json::Value {
+        match command {
+            Command::Run(args) => serde_json::to_value(args).unwrap(),
+            _ => panic!("expected the arguments of a run"),
+        }
+    }
+
+    #[test]
+    fn cargo_subcommand_argv_is_parsed_like_direct_invocation() {
+        let args = ["run", "--release", "--bin", "kernel", "--", "-m", "256M"];
+        let direct: Vec<_> = ["bootimage"].iter().chain(&args).cloned().collect();
+        let cargo: Vec<_> = ["cargo-bootimage", "bootimage"].iter().chain(&args).cloned().collect();
+        let cargo_path: Vec<_> = ["/home/user/.cargo/bin/cargo-bootimage", "bootimage"]
+            .iter()
+            .chain(&args)
+            .cloned()
+            .collect();
+        let expected = run_args_of(parse_argv(&direct));
+        assert_eq!(run_args_of(parse_argv(&cargo)), expected);
+        assert_eq!(run_args_of(parse_argv(&cargo_path)), expected);
+    }
+
+    #[test]
+    fn cargo_bootimage_without_subcommand_name_works() {
+        assert!(matches!(parse_argv(&["cargo-bootimage", "build"]), Command::Build(_)));
+        assert!(matches!(parse_argv(&["cargo-bootimage.exe", "bootimage", "test"]), Command::Test(_)));
+    }
+
+    #[test]
+    fn subcommand_name_is_only_skipped_for_cargo() {
+        assert!(matches!(parse_argv(&["bootimage", "bootimage", "build"]), Command::NoSubcommand));
+        assert!(matches!(parse_argv(&["cargo-bootimage"]), Command::NoSubcommand));
+        assert!(matches!(parse_argv(&["cargo-bootimage", "bootimage", "--version"]), Command::Version));
+    }
+
+    fn env_file(content: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let path = dir.path().join("test.env");
+        fs::write(&path, content).unwrap();
+        (dir, path)
+    }
+
+    fn env_vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn env_file_skips_comments_and_blank_lines() {
+        let (_dir, path) = env_file("# settings\n\nLOG=debug\n   # indented comment\n  \nSEED=42\n");
+        assert_eq!(parse_env_file(&path).unwrap(), env_vars(&[("LOG", "debug"), ("SEED", "42")]));
+    }
+
+    #[test]
+    fn env_file_unquotes_quoted_values() {
+        let (_dir, path) = env_file(
+            "GREETING=\"hello world\"\nPADDED='  a # b'\nESCAPED=\"say \\\"hi\\\"\"\nEMPTY=''\n",
+        );
+        assert_eq!(
+            parse_env_file(&path).unwrap(),
+            env_vars(&[
+                ("GREETING", "hello world"),
+                ("PADDED", "  a # b"),
+                ("ESCAPED", "say \"hi\""),
+                ("EMPTY", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn env_file_takes_unquoted_values_literally() {
+        let (_dir, path) = env_file("  KEY  =  a 'b' # c  \nURL=http://host/?a=1&b=2\nEMPTY=\n");
+        assert_eq!(
+            parse_env_file(&path).unwrap(),
+            env_vars(&[("KEY", "a 'b' # c"), ("URL", "http://host/?a=1&b=2"), ("EMPTY", "")])
+        );
+    }
+
+    #[test]
+    fn env_file_splits_at_first_equals_sign() {
+        let (_dir, path) = env_file("OPTS=a=b=c\nQUOTED=\"x=y\"\n");
+        assert_eq!(parse_env_file(&path).unwrap(), env_vars(&[("OPTS", "a=b=c"), ("QUOTED", "x=y")]));
+    }
+
+    #[test]
+    fn env_file_rejects_malformed_lines_with_location() {
+        let cases = [
+            ("A=1\nexport\n", "2: expected `KEY=VALUE`, got `export`"),
+            ("=value\n", "1: `=value` has an empty key"),
+            ("A=1\n\nB='unterminated\n", "3: invalid quoted value `'unterminated`"),
+            ("A=\"x\" y\n", "1: unexpected text after the quoted value `\"x\" y`"),
+        ];
+        for &(content, expected) in cases.iter() {
+            let (_dir, path) = env_file(content);
+            let err = parse_env_file(&path).unwrap_err().to_string();
+            assert_eq!(err, format!("{}:{}", path.display(), expected));
+        }
+    }
+
+    #[test]
+    fn missing_env_file_is_error() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        assert!(parse_env_file(&dir.path().join("missing.env")).is_err());
+    }
+
+    #[test]
+    fn selecting_passed_target_or_bin_is_error() {
+        let mut args = parse("build", &["--bin", "kernel", "--target", "x86_64-os.json"]).unwrap();
+        assert_eq!(
+            args.set_bin_name("test-basic".into()).unwrap_err().to_string(),
+            "`--bin kernel` conflicts with the kernel binary `test-basic` selected by bootimage"
+        );
+        assert!(args.set_target("x86_64-other.json".into()).is_err());
+
+        let mut args = parse("test", &[]).unwrap();
+        args.set_bin_name("test-basic".into()).unwrap();
+        args.set_target("x86_64-os.json".into()).unwrap();
+        assert_eq!(args.cargo_args, ["--bin", "test-basic", "--target", "x86_64-os.json"]);
+    }
+
+    #[test]
+    fn repeatable_flags_accumulate_in_order() {
+        let args = parse(
+            "run",
+            &[
+                "--run-args", "-m 256M",
+                "--config", "bootimage.strip=true",
+                "--run-args=-smp 2",
+                "--config", "bootimage.test-timeout=60",
+                "-p", "kernel",
+                "--cargo-args", "--locked",
+                "--package=driver",
+                "--", "-s",
+            ],
+        ).unwrap();
+        assert_eq!(args.run_args, ["-m", "256M", "-smp", "2", "-s"]);
+        assert_eq!(args.config_overrides(), ["bootimage.strip=true", "bootimage.test-timeout=60"]);
+        assert_eq!(args.cargo_args, ["-p", "kernel", "--locked", "--package", "driver"]);
+    }
+
+    #[test]
+    fn repeated_env_flags_are_all_set() {
+        let args = parse("run", &["--env", "LOG=debug", "--env=SEED=42"]).unwrap();
+        let vars: Vec<_> = args.env_vars().iter().collect();
+        assert_eq!(vars, [(&"LOG".to_owned(), &"debug".to_owned()), (&"SEED".to_owned(), &"42".to_owned())]);
+    }
+
+    #[test]
+    fn duplicated_singular_flags_are_rejected() {
+        assert_eq!(parse_error("build", &["--bin", "a", "--bin", "b"]), "`--bin` can only be passed once");
+        assert_eq!(parse_error("build", &["--release", "--release"]), "`--release` can only be passed once");
+        assert_eq!(
+            parse_error("build", &["--target=a.json", "--target", "b.json"]),
+            "`--target` can only be passed once"
+        );
+        // abbreviations and aliases count as the flag itself
+        assert_eq!(parse_error("run", &["--headless", "--nographic"]), "`--headless` can only be passed once");
+        assert_eq!(parse_error("build", &["--rel", "--release"]), "`--release` can only be passed once");
+    }
+
+    #[test]
+    fn opposite_flags_are_rejected() {
+        assert_eq!(
+            parse_error("test", &["--keep-image", "--no-keep-image"]),
+            "multiple arguments of same type provided"
+        );
+    }
+
+    #[test]
+    fn target_is_repeatable_with_multi_target() {
+        let args = parse("build", &["--multi-target", "--target", "a.json", "--target=b.json"]).unwrap();
+        assert_eq!(args.targets(), ["a.json", "b.json"]);
+    }
+
+    #[test]
+    fn fingerprint_depends_only_on_build_arguments() {
+        let fingerprint = |args: &[&str]| build_args(args).fingerprint();
+        let base = fingerprint(&["--bin", "kernel", "--release"]);
+        assert_eq!(base, fingerprint(&["--bin", "kernel", "--release"]));
+        assert_eq!(base, fingerprint(&["--bin", "kernel", "--release", "--force"]));
+        assert_ne!(base, fingerprint(&["--bin", "kernel"]));
+        assert_ne!(base, fingerprint(&["--example", "kernel", "--release"]));
+        assert_ne!(base, fingerprint(&["--bin", "kernel", "--release", "--feature-tagged-output"]));
+    }
+
+    #[test]
+    fn pcap_file_is_not_created_while_parsing() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let capture = dir.path().join("capture.pcap");
+        let args = parse("run", &["--pcap", capture.to_str().unwrap()]).unwrap();
+        assert_eq!(*args.pcap(), Some(dir.path().canonicalize().unwrap().join("capture.pcap")));
+        assert!(!capture.exists());
+
+        fs::write(&capture, "previous capture").unwrap();
+        parse("run", &["--pcap", capture.to_str().unwrap(), "--check-config"]).unwrap();
+        assert_eq!(fs::read_to_string(&capture).unwrap(), "previous capture");
+    }
+
+    #[test]
+    fn pcap_file_in_missing_directory_is_error() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let capture = dir.path().join("missing").join("capture.pcap");
+        let err = parse_error("run", &["--pcap", capture.to_str().unwrap()]);
+        assert!(err.starts_with("the directory of --pcap"), "{}", err);
+        assert!(err.ends_with("doesn't exist"), "{}", err);
+    }
+
+    #[test]
+    fn feature_tag_depends_on_feature_set_only() {
+        let tag = |args: &[&str]| build_args(args).feature_tag();
+        let base = tag(&["--features", "a,b"]);
+        assert_eq!(base.len(), 8);
+        assert_eq!(base, tag(&["--features", "b a"]));
+        assert_eq!(base, tag(&["--features", "b", "--features=a,a"]));
+        assert_ne!(base, tag(&["--features", "a"]));
+        assert_ne!(base, tag(&["--features", "a,b", "--no-default-features"]));
+        assert_ne!(tag(&[]), tag(&["--all-features"]));
+    }
+
+    #[test]
+    fn profile_dir_follows_cargo() {
+        let profile_dir = |args: &[&str]| build_args(args).profile_dir().to_owned();
+        assert_eq!(profile_dir(&[]), "debug");
+        assert_eq!(profile_dir(&["--profile", "dev"]), "debug");
+        assert_eq!(profile_dir(&["--profile=test"]), "debug");
+        assert_eq!(profile_dir(&["--release"]), "release");
+        assert_eq!(profile_dir(&["--profile", "release"]), "release");
+        assert_eq!(profile_dir(&["--profile", "bench"]), "release");
+        assert_eq!(profile_dir(&["--profile", "release-lto"]), "release-lto");
     }
 }
@@ -0,0 +1,159 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use args::Args;
+use build::{self, Bootimage};
+use failure::{Error, ResultExt};
+use serde_json;
+
+/// The version of the `--manifest-out` JSON format.
+const MANIFEST_VERSION: u32 = 1;
+
+/// A bootimage created in this invocation of bootimage.
+#[derive(Debug)]
+pub(crate) struct Artifact {
+    /// The name of the kernel binary.
+    bin: String,
+    /// The name of the target (the file stem of the target specification), if any.
+    target: Option<String>,
+    /// The firmware the bootimage is created for (currently always `bios`).
+    firmware: &'static str,
+    /// The name of the profile subdirectory (e.g. `debug` or `release`).
+    profile: String,
+    /// The absolute path of the bootimage.
+    path: PathBuf,
+    /// The size of the bootimage in bytes.
+    size: u64,
+    /// The `Args::fingerprint` of the build arguments.
+    fingerprint: String,
+}
+
+impl Artifact {
+    pub(crate) fn new(args: &Args, bootimage: &Bootimage) -> Result<Artifact, Error> {
+        let size = fs::metadata(&bootimage.path)
+            .context(format_err!("Failed to read bootimage {}", bootimage.path.display()))?
+            .len();
+        Ok(Artifact {
+            bin: bootimage.bin_name.clone(),
+            target: args.target().as_ref().map(|target| build::target_name(target)),
+            firmware: "bios",
+            profile: args.profile_dir().to_owned(),
+            path: fs::canonicalize(&bootimage.path).unwrap_or_else(|_| bootimage.path.clone()),
+            size,
+            fingerprint: args.fingerprint(),
+        })
+    }
+}
+
+/// Writes the JSON manifest of all artifacts to `path`.
+///
+/// The format of the file is:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "artifacts": [
+///     {
+///       "bin": "blog_os",
+///       "target": "x86_64-blog_os",
+///       "firmware": "bios",
+///       "profile": "debug",
+///       "path": "/home/user/blog_os/target/x86_64-blog_os/debug/bootimage-blog_os.bin",
+///       "size": 65536,
+///       "fingerprint": "4f1a9c2b7d3e8a60"
+///     }
+///   ]
+/// }
+/// ```
+///
+/// The file is written to a temporary file next to `path` first and then renamed, so that
+/// readers never see a partially written manifest.
+pub(crate) fn write_manifest(path: &Path, artifacts: &[Artifact]) -> Result<(), Error> {
+    let artifacts: Vec<_> = artifacts
+        .iter()
+        .map(|artifact| {
+            json!({
+                "bin": artifact.bin,
+                "target": artifact.target,
+                "firmware": artifact.firmware,
+                "profile": artifact.profile,
+                "path": artifact.path.to_string_lossy(),
+                "size": artifact.size,
+                "fingerprint": artifact.fingerprint,
+            })
+        })
+        .collect();
+    let manifest = json!({
+        "version": MANIFEST_VERSION,
+        "artifacts": artifacts,
+    });
+    let json = serde_json::to_string_pretty(&manifest).context("Failed to serialize artifact manifest")?;
+
+    write_atomically(path, json.as_bytes(), "artifact manifest")
+}
+
+/// Writes `contents` to a temporary file next to `path` and then renames it to `path`.
+///
+/// The `description` (e.g. `artifact manifest`) is used in error messages.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8], description: &str) -> Result<(), Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut file = File::create(&tmp_path)
+            .context(format_err!("Failed to create {} {}", description, tmp_path.display()))?;
+        file.write_all(contents)
+            .and_then(|()| file.sync_all())
+            .context(format_err!("Failed to write {} {}", description, tmp_path.display()))?;
+    }
+    fs::rename(&tmp_path, path)
+        .context(format_err!("Failed to move {} to {}", description, path.display()))?;
+    Ok(())
+}
+
+/// Copies the file at `from` to the output `path`, creating its parent directories.
+///
+/// The file is copied instead of renamed, since the output might be on another filesystem
+/// than `from` (e.g. a mounted volume in CI), and synced, so that write errors are reported
+/// here. The `description` (e.g. `kernel`) is used in error messages.
+pub(crate) fn copy_output(from: &Path, path: &Path, description: &str) -> Result<(), Error> {
+    let copy_error = || format_err!("Failed to copy {} {} to {}", description, from.display(), path.display());
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(copy_error())?;
+    }
+    fs::copy(from, path).context(copy_error())?;
+    File::open(path).and_then(|file| file.sync_all()).context(copy_error())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn copy_to_other_directory_keeps_bytes() {
+        let source_dir = TempDir::new("bootimage-test").unwrap();
+        let output_dir = TempDir::new("bootimage-test").unwrap();
+        let kernel: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 256) as u8).collect();
+        let from = source_dir.path().join("kernel");
+        fs::write(&from, &kernel).unwrap();
+        let path = output_dir.path().join("symbols").join("kernel.elf");
+        copy_output(&from, &path, "kernel").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), kernel);
+        // an existing output is replaced
+        copy_output(&from, &path, "kernel").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), kernel);
+    }
+
+    #[test]
+    fn copy_error_names_both_paths() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let from = dir.path().join("missing");
+        let path = dir.path().join("kernel.elf");
+        assert_eq!(
+            copy_output(&from, &path, "kernel").unwrap_err().to_string(),
+            format!("Failed to copy kernel {} to {}", from.display(), path.display())
+        );
+    }
+}
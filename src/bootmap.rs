@@ -0,0 +1,137 @@
+//! The boot map of `--emit-boot-map`, which describes the layout of a bootimage.
+
+use std::fs;
+use std::path::Path;
+use artifacts;
+use build::DiskLayout;
+use failure::{Error, ResultExt};
+use serde_json;
+use xmas_elf::{self, program};
+
+/// The version of the `--emit-boot-map` JSON format.
+const BOOT_MAP_VERSION: u32 = 1;
+
+/// Writes a JSON description of the layout of a raw bootimage to `path`.
+///
+/// The format of the file is (all numbers are bytes or addresses):
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "image-size": 1048576,
+///   "regions": [
+///     { "name": "bootloader", "offset": 0, "size": 40960, "load-address": 31744 },
+///     { "name": "kernel-info", "offset": 40960, "size": 512, "load-address": null },
+///     { "name": "kernel", "offset": 41472, "size": 912384, "load-address": null }
+///   ],
+///   "kernel-segments": [
+///     { "offset": 41472, "file-size": 4096, "memory-size": 4096,
+///       "virtual-address": 2097152, "physical-address": 2097152 }
+///   ]
+/// }
+/// ```
+///
+/// The bootloader is loaded to the address its `.bootloader` section is linked at. The
+/// kernel is loaded as a whole by the bootloader, which then maps each loadable segment
+/// (`kernel-segments`, with `offset` relative to the start of the image) to its virtual
+/// address. The offsets refer to the raw image, i.e. before an `--image-format` conversion.
+pub(crate) fn write_boot_map(
+    path: &Path,
+    layout: &DiskLayout,
+    bootloader_load_address: u64,
+    kernel_path: &Path,
+) -> Result<(), Error> {
+    let kernel = fs::read(kernel_path)
+        .context(format_err!("Failed to read kernel {}", kernel_path.display()))?;
+    let elf_file = xmas_elf::ElfFile::new(&kernel)
+        .map_err(|err| format_err!("Could not parse kernel ELF file: {}", err))?;
+    let mut segments = Vec::new();
+    for program_header in elf_file.program_iter() {
+        if program_header.get_type() != Ok(program::Type::Load) {
+            continue;
+        }
+        segments.push(json!({
+            "offset": layout.kernel_offset + program_header.offset(),
+            "file-size": program_header.file_size(),
+            "memory-size": program_header.mem_size(),
+            "virtual-address": program_header.virtual_addr(),
+            "physical-address": program_header.physical_addr(),
+        }));
+    }
+
+    let boot_map = json!({
+        "version": BOOT_MAP_VERSION,
+        "image-size": layout.image_size,
+        "regions": [
+            {
+                "name": "bootloader",
+                "offset": 0,
+                "size": layout.bootloader_size,
+                "load-address": bootloader_load_address,
+            },
+            {
+                "name": "kernel-info",
+                "offset": layout.kernel_info_offset,
+                "size": layout.kernel_offset - layout.kernel_info_offset,
+                "load-address": null,
+            },
+            {
+                "name": "kernel",
+                "offset": layout.kernel_offset,
+                "size": layout.kernel_size,
+                "load-address": null,
+            },
+        ],
+        "kernel-segments": segments,
+    });
+    let json = serde_json::to_string_pretty(&boot_map).context("Failed to serialize boot map")?;
+    artifacts::write_atomically(path, json.as_bytes(), "boot map")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build;
+    use serde_json::Value;
+    use tempdir::TempDir;
+
+    #[test]
+    fn boot_map_lists_kernel_segment() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let kernel_path = dir.path().join("kernel");
+        let kernel = build::stub_kernel(4096);
+        fs::write(&kernel_path, &kernel).unwrap();
+        let layout = DiskLayout {
+            bootloader_size: 40960,
+            kernel_info_offset: 40960,
+            kernel_offset: 41472,
+            kernel_size: kernel.len() as u64,
+            image_size: 41472 + 4608,
+        };
+        let path = dir.path().join("boot-map.json");
+        write_boot_map(&path, &layout, 0x7c00, &kernel_path).unwrap();
+
+        let boot_map: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(boot_map["version"], 1);
+        assert_eq!(boot_map["image-size"], 46080);
+        assert_eq!(
+            boot_map["regions"],
+            json!([
+                { "name": "bootloader", "offset": 0, "size": 40960, "load-address": 0x7c00 },
+                { "name": "kernel-info", "offset": 40960, "size": 512, "load-address": null },
+                { "name": "kernel", "offset": 41472, "size": kernel.len(), "load-address": null },
+            ])
+        );
+        // the segment data follows the ELF header and the program header
+        assert_eq!(
+            boot_map["kernel-segments"],
+            json!([{
+                "offset": 41472 + 120,
+                "file-size": 4096,
+                "memory-size": 4096 + 0x1000,
+                "virtual-address": 0x20_0000,
+                "physical-address": 0x20_0000,
+            }])
+        );
+    }
+}
@@ -1,84 +1,363 @@
 use std::fs::{self, File};
-use std::{io, process};
+use std::{env, io, process};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::io::Write;
 use byteorder::{ByteOrder, LittleEndian};
-use args::{self, Args};
+use args::{self, Args, ImageFormat, PartitionTable};
+use artifacts::{self, Artifact};
+use bootmap;
+use checksum;
 use config::{self, Config};
+use error::{self, ErrorKind};
+use flags;
+use gdb;
+use qemu::{self, ExitMechanism};
+use strip;
+use timings;
+use toolchain;
 use cargo_metadata::{self, Metadata as CargoMetadata, Package as CrateMetadata};
 use failure::{Error, ResultExt};
 use xmas_elf;
 use tempdir::TempDir;
+use semver::{Version, VersionReq};
+use serde_json;
 
 const BLOCK_SIZE: usize = 512;
+/// The offset of the partition table in the first sector (the master boot record).
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+/// The MBR partition type of the partition containing the bootimage ("non-FS data").
+const MBR_PARTITION_TYPE: u8 = 0xda;
+
+/// The bootloader versions that are compatible with the disk image layout of bootimage.
+pub(crate) const BOOTLOADER_VERSION_REQ: &str = ">=0.1.0, <0.3.0";
 type KernelInfoBlock = [u8; BLOCK_SIZE];
 
+/// How much newer than its inputs a bootimage must be to be considered up to date, to
+/// account for coarse file system timestamps (e.g. 2 seconds on FAT).
+const FRESHNESS_MARGIN: Duration = Duration::from_secs(2);
+
 pub(crate) fn build(args: Args) -> Result<(), Error> {
+    if !args.targets().is_empty() {
+        return build_multi_target(args);
+    }
+
     let (args, config, metadata, root_dir, out_dir) = common_setup(args)?;
 
-    build_impl(&args, &config, &metadata, &root_dir, &out_dir, true)?;
+    if args.print_target_dir() || args.print_kernel_path() {
+        return print_paths(&args, &config, &metadata, &out_dir);
+    }
+    if args.check_config() {
+        print_config_ok(&check_config(&args, &config, &metadata, &out_dir)?);
+        return Ok(());
+    }
+
+    let bootimage = build_impl(&args, &config, &metadata, &root_dir, &out_dir, true)?;
+    if let Some(algorithm) = args.checksum() {
+        checksum::write_sidecar(&bootimage.path, algorithm, args.quiet())?;
+    }
+    if let Some(ref manifest_out) = *args.manifest_out() {
+        artifacts::write_manifest(manifest_out, &[Artifact::new(&args, &bootimage)?])?;
+    }
+    Ok(())
+}
+
+/// Prints the paths requested through `--print-target-dir` and `--print-kernel-path`.
+///
+/// The paths are resolved like in `build_impl`, but nothing is built, so the kernel
+/// might not exist yet.
+fn print_paths(args: &Args, config: &Config, metadata: &CargoMetadata, out_dir: &Path) -> Result<(), Error> {
+    if args.print_target_dir() {
+        println!("{}", out_dir.display());
+    }
+    if args.print_kernel_path() {
+        let bin_name = kernel_bin_name(args, config, metadata)?;
+        println!("{}", kernel_path(out_dir, &bin_name, args).display());
+    }
     Ok(())
 }
 
+/// Resolves everything that the command of `args` depends on, but doesn't build or run
+/// anything (`--check-config`).
+///
+/// Returns the effective values as `(name, value)` pairs, or the first error. The
+/// kernel is not resolved for `bootimage test`, which resolves each test instead.
+pub(crate) fn check_config(
+    args: &Args,
+    config: &Config,
+    metadata: &CargoMetadata,
+    out_dir: &Path,
+) -> Result<Vec<(String, String)>, Error> {
+    check_image_options(args)?;
+    let mut values = vec![
+        ("manifest".to_owned(), config.manifest_path.display().to_string()),
+        ("target".to_owned(), args.target().clone().unwrap_or_else(|| String::from("(host)"))),
+        ("output directory".to_owned(), out_dir.display().to_string()),
+    ];
+    if args.command() != "test" {
+        let bin_name = kernel_bin_name(args, config, metadata)?;
+        values.push(("kernel".to_owned(), kernel_path(out_dir, &bin_name, args).display().to_string()));
+        values.push((
+            "bootimage".to_owned(),
+            image_path(out_dir, &bin_name, config, args).display().to_string(),
+        ));
+    }
+    let bootloader = &config.bootloader;
+    let source = match (&bootloader.path, &bootloader.git, &bootloader.version) {
+        (Some(path), _, _) => format!("path {}", path.display()),
+        (_, Some(git), _) => format!("git {}", git),
+        (_, _, Some(version)) => format!("version {}", version),
+        _ => String::from("latest version"),
+    };
+    values.push(("bootloader".to_owned(), format!("{} ({})", bootloader.name, source)));
+    values.push(("strip".to_owned(), (args.strip() || config.strip).to_string()));
+    values.push(("image format".to_owned(), args.image_format().name().to_owned()));
+    if args.command() == "run" {
+        let arch = qemu::Arch::of_target(args.target().as_deref());
+        let (run_command, _) = qemu::resolve_run_command(config, &arch, None)?;
+        values.push(("run command".to_owned(), run_command.join(" ")));
+    }
+    Ok(values)
+}
+
+/// Prints `configuration OK` and the effective values of a successful `--check-config`.
+pub(crate) fn print_config_ok(values: &[(String, String)]) {
+    println!("configuration OK");
+    let width = values.iter().map(|(name, _)| name.len() + 1).max().unwrap_or(0);
+    for (name, value) in values {
+        println!("    {:width$} {}", format!("{}:", name), value, width = width);
+    }
+}
+
+/// Creates a bootimage for each target passed in `--multi-target` mode.
+fn build_multi_target(args: Args) -> Result<(), Error> {
+    let mut output_paths = Vec::new();
+    let mut artifacts = Vec::new();
+    for target in args.targets() {
+        let mut target_args = args.clone();
+        target_args.set_target(target.clone()).context(ErrorKind::Args)?;
+        let (target_args, mut config, metadata, root_dir, out_dir) = common_setup(target_args)?;
+
+        // a configured output path would be overwritten by each target
+        if let Some(output) = config.output.take() {
+            config.output = Some(output_path_for_target(&output, target));
+        }
+
+        if args.check_config() {
+            print_config_ok(&check_config(&target_args, &config, &metadata, &out_dir)?);
+            continue;
+        }
+        let bootimage = build_impl(&target_args, &config, &metadata, &root_dir, &out_dir, true)?;
+        if let Some(algorithm) = args.checksum() {
+            checksum::write_sidecar(&bootimage.path, algorithm, args.quiet())?;
+        }
+        artifacts.push(Artifact::new(&target_args, &bootimage)?);
+        output_paths.push((target, bootimage.path));
+    }
+
+    if args.check_config() {
+        return Ok(());
+    }
+    println!("Created bootimages:");
+    for (target, output_path) in output_paths {
+        println!("    {}: {}", target, output_path.display());
+    }
+    if let Some(ref manifest_out) = *args.manifest_out() {
+        artifacts::write_manifest(manifest_out, &artifacts)?;
+    }
+    Ok(())
+}
+
+/// Appends the name of the target to the file stem of `output`.
+fn output_path_for_target(output: &Path, target: &str) -> PathBuf {
+    let mut file_name = output.file_stem().unwrap_or_default().to_os_string();
+    file_name.push("-");
+    file_name.push(target_name(target));
+    if let Some(extension) = output.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output.with_file_name(file_name)
+}
+
+/// Returns the name that cargo uses for the target subdirectory of the given target.
+///
+/// For paths to target specification files, this is the file stem, e.g.
+/// `x86_64-blog_os` for both `x86_64-blog_os.json` and `../targets/x86_64-blog_os.json`.
+/// Bare target triples such as `x86_64-unknown-none` are returned unchanged.
+pub fn target_name(target: &str) -> String {
+    if target.ends_with(".json") {
+        if let Some(stem) = Path::new(target).file_stem() {
+            return stem.to_string_lossy().into_owned();
+        }
+    }
+    target.to_owned()
+}
+
 pub(crate) fn run(args: Args) -> Result<(), Error> {
     let (args, config, metadata, root_dir, out_dir) = common_setup(args)?;
+    if args.check_config() {
+        print_config_ok(&check_config(&args, &config, &metadata, &out_dir)?);
+        return Ok(());
+    }
 
-    let output_path = build_impl(&args, &config, &metadata, &root_dir, &out_dir, true)?;
-    run_impl(&args, &config, &output_path)
+    let bootimage = build_impl(&args, &config, &metadata, &root_dir, &out_dir, true)?;
+    if let Some(ref manifest_out) = *args.manifest_out() {
+        artifacts::write_manifest(manifest_out, &[Artifact::new(&args, &bootimage)?])?;
+    }
+    run_impl(&args, &config, &bootimage).context(ErrorKind::Run)?;
+    Ok(())
+}
+
+/// Creates a bootimage for the kernel executable passed by cargo and runs it.
+///
+/// The executable was already built by cargo, so neither cargo nor the `pre-build-command`
+/// is run. The bootimage is placed next to the executable.
+pub(crate) fn runner(mut args: Args) -> Result<(), Error> {
+    let kernel = args
+        .kernel()
+        .clone()
+        .ok_or(format_err!("`bootimage runner` requires the path of a kernel executable"))
+        .context(ErrorKind::Args)?;
+    let manifest_path = manifest_path(&args)?;
+    let metadata = timings::time("metadata", || read_cargo_metadata(&args)).context(ErrorKind::Metadata)?;
+    let crate_root = PathBuf::from(&metadata.workspace_root);
+    let config = config::read_config(
+        manifest_path,
+        &crate_root,
+        &args.feature_selection(),
+        args.config_overrides(),
+    ).context(ErrorKind::Config)?;
+
+    let bin_name = kernel
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or(format_err!("Invalid kernel executable {}", kernel.display()))
+        .context(ErrorKind::Args)?;
+    args.set_bin_name(bin_name).context(ErrorKind::Args)?;
+    let out_dir = kernel.parent().unwrap_or_else(|| Path::new("")).to_owned();
+
+    let bootimage = build_impl(&args, &config, &metadata, &crate_root, &out_dir, true)?;
+    run_impl(&args, &config, &bootimage).context(ErrorKind::Run)?;
+    Ok(())
 }
 
 pub(crate) fn common_setup(mut args: Args) -> Result<(Args, Config, CargoMetadata, PathBuf, PathBuf), Error> {
     fn out_dir(args: &Args, metadata: &CargoMetadata) -> PathBuf {
         let target_dir = PathBuf::from(&metadata.target_directory);
         let mut out_dir = target_dir;
-        if let &Some(ref target) = args.target() {
-            out_dir.push(Path::new(target).file_stem().unwrap().to_str().unwrap());
-        }
-        if args.release() {
-            out_dir.push("release");
-        } else {
-            out_dir.push("debug");
+        if let Some(ref target) = *args.target() {
+            out_dir.push(target_name(target));
         }
+        out_dir.push(args.profile_dir());
         out_dir
     }
 
-    let metadata = read_cargo_metadata(&args)?;
+    let manifest_path = manifest_path(&args)?;
+    let metadata = timings::time("metadata", || read_cargo_metadata(&args)).context(ErrorKind::Metadata)?;
     let crate_root = PathBuf::from(&metadata.workspace_root);
-    let manifest_path = args.manifest_path().as_ref().map(Clone::clone).unwrap_or({
-        let mut path = crate_root.clone();
-        path.push("Cargo.toml");
-        path
-    });
-    let config = config::read_config(manifest_path)?;
+    let config = config::read_config(
+        manifest_path,
+        &crate_root,
+        &args.feature_selection(),
+        args.config_overrides(),
+    ).context(ErrorKind::Config)?;
 
     if args.target().is_none() {
-        if let Some(ref target) = config.default_target {
-            let mut canonicalized_target = crate_root.clone();
-            canonicalized_target.push(target);
-            args.set_target(canonicalized_target.to_string_lossy().into_owned());
+        if let Some(target) = default_target(&config, &crate_root) {
+            args.set_target(target).context(ErrorKind::Args)?;
         }
     }
 
-    if let &Some(ref target) = args.target() {
+    if let Some(ref target) = *args.target() {
         if !target.ends_with(".json") {
-            use std::io::{self, Write};
-            use std::process;
-
-            writeln!(
-                io::stderr(),
+            Err(format_err!(
                 "Please pass a path to `--target` (with `.json` extension`): `--target {}.json`",
                 target
-            ).unwrap();
-            process::exit(1);
+            )).context(ErrorKind::Args)?
         }
     }
 
+    check_bin_target(&args, &config, &metadata).context(ErrorKind::Args)?;
     let out_dir = out_dir(&args, &metadata);
 
+    // runs once per invocation, before any kernel is built (and not at all if only
+    // paths, tests, or the checked configuration are printed or no kernel is built)
+    let print_only =
+        args.print_target_dir() || args.print_kernel_path() || args.list_tests() || args.check_config();
+    if let Some(ref pre_build_command) = config.pre_build_command {
+        if !print_only && !args.no_build() {
+            run_pre_build_command(pre_build_command, &config.manifest_path, &args).context(ErrorKind::Build)?;
+        }
+    }
+
     Ok((args, config, metadata, crate_root, out_dir))
 }
 
+fn run_pre_build_command(pre_build_command: &[String], manifest_path: &Path, args: &Args) -> Result<(), Error> {
+    let mut command = process::Command::new(&pre_build_command[0]);
+    command.args(&pre_build_command[1..]);
+    command.envs(args.env_vars());
+    if let Some(manifest_dir) = manifest_path.parent() {
+        command.current_dir(manifest_dir);
+    }
+    let exit_status = command
+        .status()
+        .context(format_err!("Failed to execute pre-build command: {:?}", command))?;
+    if !exit_status.success() {
+        Err(format_err!("Pre-build command {:?} failed with {}", command, exit_status))?
+    }
+    Ok(())
+}
+
+/// Returns the passed `--manifest-path` or else the nearest `Cargo.toml` (see
+/// `find_manifest`), starting at the current directory.
+pub(crate) fn manifest_path(args: &Args) -> Result<PathBuf, Error> {
+    match *args.manifest_path() {
+        Some(ref manifest_path) => Ok(manifest_path.clone()),
+        None => {
+            let current_dir = env::current_dir().context("Failed to read the current directory")?;
+            find_manifest(&current_dir)
+        }
+    }
+}
+
+/// Returns the `Cargo.toml` in `start` or in the nearest parent directory, like cargo
+/// does without `--manifest-path`.
+fn find_manifest(start: &Path) -> Result<PathBuf, Error> {
+    for dir in start.ancestors() {
+        let manifest_path = dir.join("Cargo.toml");
+        if manifest_path.is_file() {
+            return Ok(manifest_path);
+        }
+    }
+    Err(format_err!(
+        "could not find 'Cargo.toml' in {} or any parent directory",
+        start.display()
+    ))
+}
+
+/// Returns the configured `default-target`, resolved relative to the crate root.
+pub(crate) fn default_target(config: &Config, crate_root: &Path) -> Option<String> {
+    config.default_target.as_ref().map(|target| {
+        let mut canonicalized_target = crate_root.to_owned();
+        canonicalized_target.push(target);
+        canonicalized_target.to_string_lossy().into_owned()
+    })
+}
+
+/// A bootimage created by `build_impl`.
+#[derive(Debug, Clone)]
+pub(crate) struct Bootimage {
+    /// The name of the kernel binary.
+    pub bin_name: String,
+    /// The path of the created disk image.
+    pub path: PathBuf,
+    /// The path of the kernel executable (with debug info, even if `--strip` is used).
+    pub kernel_path: PathBuf,
+}
+
 pub(crate) fn build_impl(
     args: &Args,
     config: &Config,
@@ -86,18 +365,49 @@ pub(crate) fn build_impl(
     root_dir: &Path,
     out_dir: &Path,
     verbose: bool,
-) -> Result<PathBuf, Error> {
-    let crate_ = metadata
-        .packages
-        .iter()
-        .find(|p| Path::new(&p.manifest_path) == config.manifest_path)
-        .expect("Could not read crate name from cargo metadata");
-    let bin_name: String = args.bin_name().as_ref().unwrap_or(&crate_.name).clone();
+) -> Result<Bootimage, Error> {
+    let bin_name = kernel_bin_name(args, config, metadata)?;
+    check_image_options(args)?;
 
-    let kernel = build_kernel(&out_dir, &bin_name, &args, verbose)?;
+    let unstripped_kernel_path = timings::time("kernel build", || build_kernel(out_dir, &bin_name, args, config, verbose))
+        .context(ErrorKind::Build)?;
+    if let Some(ref symbols_path) = *args.symbols_path() {
+        artifacts::copy_output(&unstripped_kernel_path, symbols_path, "kernel").context(ErrorKind::Build)?;
+    }
+    // the image contains the stripped kernel, so gdb needs the unstripped one for symbols
+    if (args.strip() || config.strip) && !args.quiet() {
+        let symbols_path = args.symbols_path().as_ref().unwrap_or(&unstripped_kernel_path);
+        println!("Debug symbols (for gdb): {}", symbols_path.display());
+    }
+
+    let image_path = image_path(out_dir, &bin_name, config, args);
+    if image_is_fresh(&image_path, &unstripped_kernel_path, args, config) {
+        if verbose {
+            println!(
+                "bootimage up to date ({})",
+                image_path.strip_prefix(root_dir).unwrap_or(&image_path).display()
+            );
+        }
+        if let Some(max_size) = args.max_image_size().or(config.max_image_size) {
+            check_image_size(&image_path, max_size, verbose)?;
+        }
+        return Ok(Bootimage {
+            bin_name,
+            path: image_path,
+            kernel_path: unstripped_kernel_path,
+        });
+    }
+
+    let mut kernel_path = unstripped_kernel_path.clone();
+    if args.strip() || config.strip {
+        kernel_path = strip::strip_kernel(&kernel_path, verbose).context(ErrorKind::Build)?;
+    }
+    let kernel = File::open(&kernel_path)
+        .context("Failed to open kernel output file")
+        .context(ErrorKind::Build)?;
 
     let kernel_size = kernel.metadata().context("Failed to read kernel output file")?.len();
-    let kernel_info_block = create_kernel_info_block(kernel_size);
+    let kernel_info_block = create_kernel_info_block(kernel_size).context(ErrorKind::Assembly)?;
 
     if args.update_bootloader() {
         let mut bootloader_cargo_lock = PathBuf::from(out_dir);
@@ -108,66 +418,629 @@ pub(crate) fn build_impl(
     }
 
     let tmp_dir = TempDir::new("bootloader").context("Failed to create a temporary directory")?;
-    let bootloader = build_bootloader(tmp_dir.path(), &config).context("Failed to build bootloader")?;
+    let bootloader = timings::time("bootloader build", || build_bootloader(tmp_dir.path(), config, args))
+        .context("Failed to build bootloader")
+        .context(ErrorKind::Build)?;
     tmp_dir.close().context("Failed to close temporary directory")?;
 
-    create_disk_image(root_dir, out_dir, &bin_name, &config, kernel, kernel_info_block, &bootloader, verbose)
+    let (output_path, layout) = timings::time("image assembly", || {
+        create_disk_image(
+            root_dir,
+            image_path,
+            config,
+            kernel,
+            kernel_info_block,
+            &bootloader.data,
+            args.partition_table(),
+            verbose,
+        )
+    }).context(ErrorKind::Assembly)?;
+    check_image_complete(&output_path, layout.bootloader_size, &kernel_path).context(ErrorKind::Assembly)?;
+    let fingerprint = fingerprint_file(args, &bootloader.elf_path);
+    artifacts::write_atomically(&fingerprint_path(&output_path), fingerprint.as_bytes(), "fingerprint")
+        .context(ErrorKind::Assembly)?;
+    if let Some(ref boot_map_path) = *args.emit_boot_map() {
+        bootmap::write_boot_map(boot_map_path, &layout, bootloader.load_address, &kernel_path)
+            .context(ErrorKind::Assembly)?;
+    }
+    if let Some(max_size) = args.max_image_size().or(config.max_image_size) {
+        check_image_size(&output_path, max_size, verbose)?;
+    }
+    let output_path = match args.image_format() {
+        ImageFormat::Raw => output_path,
+        format => convert_image(&output_path, format, verbose && !args.quiet()).context(ErrorKind::Assembly)?,
+    };
+    if let Some(time) = source_date_epoch()? {
+        File::options()
+            .write(true)
+            .open(&output_path)
+            .and_then(|image| image.set_modified(time))
+            .context(format_err!("Failed to set the modification time of {}", output_path.display()))?;
+    }
+    Ok(Bootimage {
+        bin_name,
+        path: output_path,
+        kernel_path: unstripped_kernel_path,
+    })
+}
+
+/// Checks that the image options in `args` can be used together.
+fn check_image_options(args: &Args) -> Result<(), Error> {
+    if args.reproducible() && args.image_format() == ImageFormat::Vmdk {
+        Err(format_err!(
+            "`--image-format vmdk` can't be used for reproducible builds, since `qemu-img` \
+             writes a random content ID into VMDK images; use `raw` or `qcow2` instead"
+        ))?
+    }
+    if args.partition_table() == PartitionTable::Gpt {
+        Err(format_err!(
+            "a GPT partition table requires UEFI firmware, but the bootimage is created for \
+             BIOS firmware; use `--partition-table mbr` instead"
+        ))?
+    }
+    Ok(())
 }
 
-fn run_impl(args: &Args, config: &Config, output_path: &Path) -> Result<(), Error> {
-    let command = &config.run_command[0];
-    let mut command = process::Command::new(command);
-    for arg in &config.run_command[1..] {
-        command.arg(
-            arg.replace(
-                "{}",
-                output_path
-                    .to_str()
-                    .expect("output must be valid unicode"),
-            ),
-        );
+/// Returns whether the bootimage at `image_path` can be reused without assembling it.
+///
+/// This is the case if the bootimage was assembled with the same arguments (according to
+/// the fingerprint file next to it) and is newer than all inputs by at least
+/// `FRESHNESS_MARGIN`. The inputs are the kernel, the `Cargo.toml`, the other config files
+/// (`Config::config_files`), the bootloader executable (whose path is recorded in the
+/// fingerprint file), and the bootimage executable. A local bootloader `path` is never
+/// considered fresh, since its sources might have changed without a new executable.
+/// Images with a boot map, in another `--image-format`, or of reproducible builds are
+/// always assembled, as are all images with `--force` or `--update-bootloader`.
+fn image_is_fresh(image_path: &Path, kernel_path: &Path, args: &Args, config: &Config) -> bool {
+    if args.force()
+        || args.reproducible()
+        || args.update_bootloader()
+        || args.emit_boot_map().is_some()
+        || args.image_format() != ImageFormat::Raw
+        || config.bootloader.path.is_some()
+    {
+        return false;
+    }
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let image_modified = match modified(image_path) {
+        Some(time) => time,
+        None => return false,
+    };
+    let fingerprint_file = match fs::read_to_string(fingerprint_path(image_path)) {
+        Ok(content) => content,
+        Err(_) => return false,
+    };
+    let mut lines = fingerprint_file.lines();
+    if lines.next() != Some(args.fingerprint().as_str()) {
+        return false;
+    }
+    let bootloader_path = match lines.next() {
+        Some(path) => PathBuf::from(path),
+        None => return false,
+    };
+    let mut inputs = vec![kernel_path.to_owned(), config.manifest_path.clone(), bootloader_path];
+    inputs.extend(config.config_files.iter().cloned());
+    inputs.extend(env::current_exe().ok());
+    inputs.iter().all(|input| match modified(input) {
+        Some(input_modified) => input_modified + FRESHNESS_MARGIN <= image_modified,
+        None => false,
+    })
+}
+
+/// Returns the content of the fingerprint file of an image (see `image_is_fresh`): the
+/// fingerprint of the arguments and the path of the bootloader executable, one per line.
+fn fingerprint_file(args: &Args, bootloader_path: &Path) -> String {
+    format!("{}\n{}\n", args.fingerprint(), bootloader_path.display())
+}
+
+/// Returns the time of `SOURCE_DATE_EPOCH`, which is used as modification time of the
+/// bootimage, or `None` if it is not set.
+fn source_date_epoch() -> Result<Option<SystemTime>, Error> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(seconds) => match seconds.parse() {
+            Ok(seconds) => Ok(Some(UNIX_EPOCH + Duration::from_secs(seconds))),
+            Err(_) => Err(format_err!("SOURCE_DATE_EPOCH `{}` is not a number of seconds", seconds)),
+        },
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(format_err!("SOURCE_DATE_EPOCH is not valid unicode")),
+    }
+}
+
+/// Returns the path of the file next to a bootimage that stores the fingerprint of the
+/// arguments it was assembled with (see `image_is_fresh`).
+fn fingerprint_path(image_path: &Path) -> PathBuf {
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".fingerprint");
+    PathBuf::from(path)
+}
+
+/// Checks that the target of `--bin` or `--example` exists in the package, so that a typo
+/// is reported with the available targets (and the closest one) before cargo runs.
+///
+/// The check is skipped if the package is not part of the cargo metadata.
+fn check_bin_target(args: &Args, config: &Config, metadata: &CargoMetadata) -> Result<(), Error> {
+    let (kind, name) = match (args.example_name(), args.bin_name()) {
+        (Some(name), _) => ("example", name),
+        (_, Some(name)) => ("bin", name),
+        _ => return Ok(()),
+    };
+    let crate_ = match metadata
+        .packages
+        .iter()
+        .find(|p| Path::new(&p.manifest_path) == config.manifest_path)
+    {
+        Some(crate_) => crate_,
+        None => return Ok(()),
+    };
+    let available: Vec<&str> = crate_
+        .targets
+        .iter()
+        .filter(|t| t.kind.iter().any(|k| k == kind))
+        .map(|t| t.name.as_str())
+        .collect();
+    if available.contains(&name.as_str()) {
+        return Ok(());
+    }
+    if available.is_empty() {
+        Err(format_err!("no {} target named '{}'; the package has no {} targets", kind, name, kind))?
+    }
+    let mut message = format!("no {} target named '{}'; available: {}", kind, name, available.join(", "));
+    if let Some(closest) = config::closest_match(name, &available) {
+        message.push_str(&format!(" (did you mean '{}'?)", closest));
+    }
+    Err(format_err!("{}", message))
+}
+
+/// Returns the name of the kernel binary, from `--bin` or `--example`, or else chosen by
+/// `default_bin_name`.
+fn kernel_bin_name(args: &Args, config: &Config, metadata: &CargoMetadata) -> Result<String, Error> {
+    if let Some(ref example_name) = *args.example_name() {
+        return Ok(example_name.clone());
+    }
+    let crate_ = metadata
+        .packages
+        .iter()
+        .find(|p| Path::new(&p.manifest_path) == config.manifest_path)
+        .ok_or(format_err!("Could not find crate in cargo metadata"))
+        .context(ErrorKind::Metadata)?;
+    match *args.bin_name() {
+        Some(ref bin_name) => Ok(bin_name.clone()),
+        None => Ok(default_bin_name(crate_, config).context(ErrorKind::Args)?),
+    }
+}
+
+/// Fails if the bootimage at `image_path` is larger than `max_size` bytes.
+fn check_image_size(image_path: &Path, max_size: u64, verbose: bool) -> Result<(), Error> {
+    let size = fs::metadata(image_path)
+        .context(format_err!("Could not read output bootimage file {}", image_path.display()))?
+        .len();
+    if size > max_size {
+        Err(format_err!(
+            "bootimage is {} bytes, exceeding the limit of {} bytes",
+            size, max_size
+        ))?
+    }
+    if verbose {
+        println!("Bootimage is {} bytes (limit: {} bytes)", size, max_size);
+    }
+    Ok(())
+}
+
+/// Fails if the bootimage at `image_path` is smaller than the bootloader plus the loadable
+/// segments of the kernel, which means that the assembly silently failed (e.g. because the
+/// image was truncated).
+fn check_image_complete(image_path: &Path, bootloader_size: u64, kernel_path: &Path) -> Result<(), Error> {
+    use xmas_elf::program;
+
+    let kernel = fs::read(kernel_path)
+        .context(format_err!("Failed to read kernel {}", kernel_path.display()))?;
+    let elf_file = xmas_elf::ElfFile::new(&kernel)
+        .map_err(|err| format_err!("Could not parse kernel ELF file: {}", err))?;
+    let segments_size: u64 = elf_file
+        .program_iter()
+        .filter(|program_header| program_header.get_type() == Ok(program::Type::Load))
+        .map(|program_header| program_header.file_size())
+        .sum();
+
+    let size = fs::metadata(image_path)
+        .context(format_err!("Could not read output bootimage file {}", image_path.display()))?
+        .len();
+    if size < bootloader_size + segments_size {
+        Err(format_err!(
+            "produced bootimage is suspiciously small ({} bytes); assembly likely failed",
+            size
+        ))?
     }
-    command.args(&args.run_args);
-    command.status().context(format_err!("Failed to execute run command: {:?}", command))?;
     Ok(())
 }
 
+/// Converts the raw bootimage to the given format through `qemu-img` and returns the path
+/// of the converted image.
+///
+/// The converted image is placed next to the raw image, with the format as extension.
+fn convert_image(raw_path: &Path, format: ImageFormat, verbose: bool) -> Result<PathBuf, Error> {
+    let converted_path = raw_path.with_extension(format.name());
+    let mut command = process::Command::new("qemu-img");
+    command.arg("convert");
+    command.arg("-f").arg("raw");
+    command.arg("-O").arg(format.name());
+    command.arg(raw_path).arg(&converted_path);
+    let exit_status = match command.status() {
+        Ok(exit_status) => exit_status,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Err(format_err!(
+            "`--image-format {}` requires `qemu-img`, but it was not found. Install QEMU \
+             (https://www.qemu.org/download/) and make sure that `qemu-img` is in your PATH.",
+            format.name()
+        ))?,
+        Err(err) => Err(err).context(format_err!("Failed to execute {:?}", command))?,
+    };
+    if !exit_status.success() {
+        Err(format_err!("Failed to convert bootimage: {:?} exited with {}", command, exit_status))?
+    }
+    if verbose {
+        println!("Converted bootimage to {} format at {}", format.name(), converted_path.display());
+    }
+    Ok(converted_path)
+}
+
+/// Chooses the kernel binary if no `--bin` argument is passed.
+///
+/// Like `cargo run`, the only binary or the `default-run` binary is chosen. For
+/// compatibility, a binary named like the crate is chosen if there are multiple ones.
+fn default_bin_name(crate_: &CrateMetadata, config: &Config) -> Result<String, Error> {
+    let bins: Vec<&str> = crate_
+        .targets
+        .iter()
+        .filter(|t| t.kind == ["bin"])
+        .map(|t| t.name.as_str())
+        .collect();
+    if bins.len() == 1 {
+        return Ok(bins[0].to_owned());
+    }
+    if let Some(ref default_run) = config.default_run {
+        return Ok(default_run.clone());
+    }
+    if bins.len() > 1 && !bins.contains(&crate_.name.as_str()) {
+        Err(format_err!(
+            "`bootimage` could not determine which binary to use. Use the `--bin` option \
+             or the `default-run` manifest key to specify one.\navailable binaries: {}",
+            bins.join(", ")
+        ))?
+    }
+    Ok(crate_.name.clone())
+}
+
+fn run_impl(args: &Args, config: &Config, bootimage: &Bootimage) -> Result<(), Error> {
+    let output_path = bootimage
+        .path
+        .to_str()
+        .ok_or(format_err!("bootimage path {} is not valid unicode", bootimage.path.display()))?;
+    // with `--no-drive`, the `{drive}` placeholders expand to nothing
+    let drive_args = if args.no_drive() {
+        Vec::new()
+    } else {
+        qemu::drive_args(output_path)
+    };
+
+    // a `{drive}` in the run arguments takes precedence over the one in the run command
+    let user_places_drive = args.run_args.iter().any(|arg| arg == qemu::DRIVE_PLACEHOLDER);
+    let arch = qemu::Arch::of_kernel(args, &bootimage.kernel_path);
+    let (run_command, _) = qemu::resolve_run_command(config, &arch, None)?;
+    let mut run_command_args = Vec::new();
+    for arg in &run_command[1..] {
+        if arg == qemu::DRIVE_PLACEHOLDER {
+            if !user_places_drive {
+                run_command_args.extend(drive_args.iter().cloned());
+            }
+        } else {
+            run_command_args.push(arg.replace("{}", output_path));
+        }
+    }
+    let run_args: Vec<String> = qemu::expand_drive_placeholder(&args.run_args, &drive_args)
+        .iter()
+        .map(|arg| arg.replace("{}", output_path))
+        .collect();
+
+    let existing_args: Vec<String> = run_command_args
+        .iter()
+        .chain(&run_args)
+        .cloned()
+        .collect();
+    let extra_args = qemu::extra_args(args, &existing_args);
+    let mut command = process::Command::new(&run_command[0]);
+    command.envs(args.env_vars());
+    command.args(&run_command_args);
+    command.args(&extra_args);
+    command.args(&run_args);
+    if let Some(dir) = qemu::working_dir(args, config)? {
+        command.current_dir(dir);
+    }
+    if let Some(ref routing) = *args.qemu_stdout() {
+        command.stdout(qemu::stdio(routing)?);
+    }
+    if let Some(ref routing) = *args.qemu_stderr() {
+        command.stderr(qemu::stdio(routing)?);
+    }
+
+    // without the image (e.g. after overriding the run command), QEMU boots an empty machine
+    let loads_image = run_command_args
+        .iter()
+        .chain(&extra_args)
+        .chain(&run_args)
+        .any(|arg| arg.contains(output_path));
+    if !loads_image && !args.quiet() {
+        eprintln!(
+            "warning: the QEMU command does not appear to load the bootimage ({})",
+            output_path
+        );
+        if args.no_drive() {
+            eprintln!("  `--no-drive` was passed, so use `{{}}` in the run command or run arguments");
+        } else {
+            eprintln!("  use `{{drive}}` or `{{}}` in the run command or run arguments");
+        }
+    }
+    if !args.quiet() {
+        let managed_drive = !args.no_drive()
+            && run_command[1..]
+                .iter()
+                .chain(&args.run_args)
+                .any(|arg| arg == qemu::DRIVE_PLACEHOLDER);
+        let argv: Vec<String> = command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        for warning in qemu::conflicting_args(&argv, output_path, managed_drive, true) {
+            eprintln!("warning: {}", warning);
+        }
+    }
+    let (exit_status, captured) = if args.gdb_attach() {
+        let exit_status = gdb::attach(config, &bootimage.kernel_path, args.gdb_port(), args.print_pid(), command)?;
+        (exit_status, None)
+    } else {
+        let mut qemu = timings::time("qemu startup", || command.spawn())
+            .context(format_err!("Failed to execute run command: {:?}", command))?;
+        if args.print_pid() {
+            qemu::print_pid(&qemu)?;
+        }
+        let capture = qemu::Capture::start(&mut qemu);
+        let exit_status = qemu.wait().context("Failed to wait for the run command")?;
+        (exit_status, Some(capture.finish()?))
+    };
+
+    // the exit code is only interpreted if the run command enables the exit mechanism
+    let exit_mechanism = ExitMechanism::new(config, &arch)?.filter(|m| m.is_enabled_in(&existing_args));
+    match qemu::exit_code(exit_status, exit_mechanism.as_ref()) {
+        0 => Ok(()),
+        code => {
+            // captured output is only shown on failure
+            if let Some((stdout, stderr)) = captured {
+                io::stdout().write_all(&stdout).context("Failed to print QEMU output")?;
+                io::stderr().write_all(&stderr).context("Failed to print QEMU output")?;
+            }
+            Err(error::Exit { code })?
+        }
+    }
+}
+
+/// The error of a kernel build that cargo reported as failed.
+///
+/// Cargo already printed the errors, so bootimage only exits with a failure (see
+/// `is_kernel_build_failure`).
+#[derive(Debug, Fail)]
+#[fail(display = "Failed to build the kernel")]
+pub(crate) struct KernelBuildFailed;
+
+/// Returns whether `err` is caused by a failed kernel build.
+pub(crate) fn is_kernel_build_failure(err: &Error) -> bool {
+    err.causes().any(|cause| cause.downcast_ref::<KernelBuildFailed>().is_some())
+}
+
 #[derive(Debug, Fail)]
 #[fail(display = "Failed to execute `cargo metadata`")]
 pub struct CargoMetadataError(Mutex<cargo_metadata::Error>);
 
-fn read_cargo_metadata(args: &Args) -> Result<CargoMetadata, Error> {
+/// Returns the metadata of the crate, read from the `--metadata-file` or else by running
+/// `cargo metadata`.
+pub(crate) fn read_cargo_metadata(args: &Args) -> Result<CargoMetadata, Error> {
+    if let Some(ref metadata_file) = *args.metadata_file() {
+        return read_metadata_file(metadata_file);
+    }
     let metadata = cargo_metadata::metadata(args.manifest_path().as_ref().map(PathBuf::as_path))
         .map_err(|e| CargoMetadataError(Mutex::new(e)))?;
     Ok(metadata)
 }
 
+/// Reads the output of `cargo metadata --format-version 1` from `path`.
+///
+/// The file is not checked for being up to date, so it must be regenerated whenever the
+/// manifest or the dependencies of the workspace change.
+fn read_metadata_file(path: &Path) -> Result<CargoMetadata, Error> {
+    let json = fs::read_to_string(path)
+        .context(format_err!("Failed to read metadata file {}", path.display()))?;
+    let metadata: CargoMetadata = serde_json::from_str(&json).context(format_err!(
+        "Metadata file {} is not valid `cargo metadata --format-version 1` output",
+        path.display()
+    ))?;
+    if metadata.packages.is_empty() || metadata.target_directory.is_empty() {
+        Err(format_err!(
+            "Metadata file {} contains no packages or no target directory",
+            path.display()
+        ))?
+    }
+    Ok(metadata)
+}
+
 fn build_kernel(
     out_dir: &Path,
     bin_name: &str,
     args: &args::Args,
+    config: &Config,
     verbose: bool,
-) -> Result<File, Error> {
+) -> Result<PathBuf, Error> {
+    let kernel_path = kernel_path(out_dir, bin_name, args);
+
+    // the executable passed to `bootimage runner` was already built by cargo
+    if args.kernel().is_some() {
+        return Ok(kernel_path);
+    }
+
+    if args.no_build() {
+        if !kernel_path.exists() {
+            Err(format_err!("kernel artifact {} not found; build first", kernel_path.display()))?
+        }
+        if verbose {
+            println!("Using kernel {}", kernel_path.display());
+        }
+        return Ok(kernel_path);
+    }
+
+    if args.reuse_build() {
+        if !kernel_path.exists() {
+            eprintln!(
+                "warning: `--reuse-build` was passed, but there is no kernel at {}; building it",
+                kernel_path.display()
+            );
+        } else if kernel_is_fresh(&kernel_path) {
+            if verbose {
+                println!("Reusing kernel {}", kernel_path.display());
+            }
+            return Ok(kernel_path);
+        } else {
+            eprintln!(
+                "warning: `--reuse-build` was passed, but the kernel at {} is older than its \
+                 sources; rebuilding it",
+                kernel_path.display()
+            );
+        }
+    }
+
     // compile kernel
     if verbose {
         println!("Building kernel");
     }
-    let exit_status = run_xbuild(&args.cargo_args)
-        .context("Failed to run `cargo xbuild`")?;
+    let mut command = xbuild_command(&args.cargo_args);
+    if config.build_std.is_some() && toolchain::is_nightly() == Some(false) {
+        eprintln!(
+            "warning: `build-std` requires a nightly toolchain, but the active toolchain \
+             is not nightly; cargo will most likely reject the `-Z build-std` flag"
+        );
+    }
+    command.args(build_std_args(config));
+    command.envs(args.env_vars());
+    let exit_status = run_xbuild(command).context("Failed to run `cargo xbuild`")?;
     if !exit_status.success() {
-        process::exit(1)
+        // runner flags before the `--` are passed to cargo, which most likely caused the failure
+        if args.command() == "run" || args.command() == "test" {
+            for flag in flags::likely_runner_flags(&args.cargo_args) {
+                eprintln!("note: '{}' looks like a QEMU flag; pass it after '--'", flag);
+            }
+        }
+        Err(KernelBuildFailed)?
+    }
+
+    Ok(kernel_path)
+}
+
+/// Returns the `-Z build-std` cargo arguments of the `build-std` and `build-std-features`
+/// keys (the features are ignored without `build-std`).
+fn build_std_args(config: &Config) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(ref crates) = config.build_std {
+        args.push(String::from("-Z"));
+        args.push(format!("build-std={}", crates.join(",")));
+        if let Some(ref features) = config.build_std_features {
+            args.push(String::from("-Z"));
+            args.push(format!("build-std-features={}", features.join(",")));
+        }
     }
+    args
+}
 
+/// Returns the path of the kernel executable that cargo places in `out_dir`.
+///
+/// Like cargo, an example selected through `--example` is in the `examples` subdirectory.
+fn kernel_path(out_dir: &Path, bin_name: &str, args: &Args) -> PathBuf {
     let mut kernel_path = out_dir.to_owned();
+    if args.example_name().is_some() {
+        kernel_path.push("examples");
+    }
     kernel_path.push(bin_name);
-    let kernel = File::open(kernel_path).context("Failed to open kernel output file")?;
-    Ok(kernel)
+    kernel_path
+}
+
+/// Checks whether the kernel is newer than all of its sources.
+///
+/// The sources are read from the dep-info file (`<kernel>.d`) that cargo writes next
+/// to the executable. Without a dep-info file, the kernel is assumed to be fresh.
+fn kernel_is_fresh(kernel_path: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let kernel_modified = match modified(kernel_path) {
+        Some(time) => time,
+        None => return false,
+    };
+    let deps = match dep_info(kernel_path) {
+        Some(deps) => deps,
+        None => return true,
+    };
+    deps.iter().all(|dep| match modified(dep) {
+        Some(time) => time <= kernel_modified,
+        None => false,
+    })
+}
+
+/// Returns the sources of the kernel at `kernel_path`, as listed in the dep-info file
+/// (`<kernel>.d`) that cargo writes next to the executable, or `None` if there is none.
+pub(crate) fn dep_info(kernel_path: &Path) -> Option<Vec<PathBuf>> {
+    let mut dep_info_path = kernel_path.as_os_str().to_owned();
+    dep_info_path.push(".d");
+    let dep_info = fs::read_to_string(PathBuf::from(dep_info_path)).ok()?;
+    let mut deps = Vec::new();
+    for line in dep_info.lines() {
+        // the format is `target: dep1 dep2 ...`, with spaces in paths escaped as `\ `
+        let line_deps = match line.find(": ") {
+            Some(index) => &line[index + 2..],
+            None => continue,
+        };
+        let line_deps = line_deps.replace("\\ ", "\0");
+        deps.extend(line_deps.split_whitespace().map(|dep| PathBuf::from(dep.replace('\0', " "))));
+    }
+    Some(deps)
+}
+
+/// Returns the target specification for the bootloader build.
+///
+/// The `bootloader-target` key is resolved relative to the kernel crate. Otherwise, the
+/// `bootloader.target` of the bootloader crate is used.
+fn bootloader_target(config: &Config, bootloader_dir: &Path) -> Result<PathBuf, Error> {
+    let target = match config.bootloader_target {
+        Some(ref target) => {
+            let manifest_dir = config.manifest_path.parent().unwrap_or_else(|| Path::new(""));
+            let target_path = manifest_dir.join(target);
+            if config.bootloader.precompiled {
+                eprintln!("warning: `bootloader-target` is ignored for a precompiled bootloader");
+            } else if !target_path.is_file() {
+                Err(format_err!(
+                    "`bootloader-target` {} does not exist (it is resolved relative to {})",
+                    target.display(),
+                    manifest_dir.display()
+                ))?
+            }
+            target_path
+        }
+        None => bootloader_dir.join(&config.bootloader.target),
+    };
+    Ok(target)
 }
 
-fn run_xbuild(args: &[String]) -> io::Result<process::ExitStatus> {
+/// Creates a `cargo xbuild` command with the given arguments.
+///
+/// Like all subprocesses, the command inherits the environment of bootimage, including
+/// `RUSTFLAGS` and `CARGO_ENCODED_RUSTFLAGS`.
+fn xbuild_command(args: &[String]) -> process::Command {
     let mut command = process::Command::new("cargo");
     command.arg("xbuild");
     command.args(args);
+    command
+}
+
+fn run_xbuild(mut command: process::Command) -> io::Result<process::ExitStatus> {
     let exit_status = command.status()?;
 
     if !exit_status.success() {
@@ -180,6 +1053,14 @@ fn run_xbuild(args: &[String]) -> io::Result<process::ExitStatus> {
                 let mut stderr = io::stderr();
                 writeln!(stderr, "Failed to run `cargo xbuild`. Perhaps it is not installed?")?;
                 writeln!(stderr, "Run `cargo install cargo-xbuild` to install it.")?;
+            } else if toolchain::has_rust_src() == Some(false) {
+                // without the source, the build fails with a confusing error about `core`
+                writeln!(
+                    io::stderr(),
+                    "note: the `rust-src` component of the active toolchain is not installed, \
+                     which is required for building `core`. Run `rustup component add rust-src` \
+                     to install it."
+                )?;
             }
         }
     }
@@ -187,20 +1068,24 @@ fn run_xbuild(args: &[String]) -> io::Result<process::ExitStatus> {
     Ok(exit_status)
 }
 
-fn create_kernel_info_block(kernel_size: u64) -> KernelInfoBlock {
-    let kernel_size = if kernel_size <= u64::from(u32::max_value()) {
+fn create_kernel_info_block(kernel_size: u64) -> Result<KernelInfoBlock, Error> {
+    let kernel_size = if kernel_size <= u64::from(u32::MAX) {
         kernel_size as u32
     } else {
-        panic!("Kernel can't be loaded by BIOS bootloader because is too big")
+        Err(format_err!("Kernel can't be loaded by BIOS bootloader because is too big"))?
     };
 
     let mut kernel_info_block = [0u8; BLOCK_SIZE];
     LittleEndian::write_u32(&mut kernel_info_block[0..4], kernel_size);
 
-    kernel_info_block
+    Ok(kernel_info_block)
 }
 
-fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMetadata, Error> {
+/// Downloads the configured bootloader crate and returns its metadata.
+///
+/// In `offline` mode, `cargo fetch` only uses the local cache, so the bootloader must
+/// have been downloaded before (or be vendored).
+pub(crate) fn download_bootloader(bootloader_dir: &Path, config: &Config, offline: bool) -> Result<CrateMetadata, Error> {
     use std::io::Write;
 
     let cargo_toml = {
@@ -237,7 +1122,7 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 config.bootloader.name
             ).as_bytes(),
         ).context("Failed to write to Cargo.toml for bootloader download crate")?;
-        if let &Some(ref version) = &config.bootloader.version {
+        if let Some(ref version) = config.bootloader.version {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -247,7 +1132,7 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 ).as_bytes(),
             ).context("Failed to write to Cargo.toml for bootloader download crate")?;
         }
-        if let &Some(ref git) = &config.bootloader.git {
+        if let Some(ref git) = config.bootloader.git {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -257,7 +1142,7 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 ).as_bytes(),
             ).context("Failed to write to Cargo.toml for bootloader download crate")?;
         }
-        if let &Some(ref branch) = &config.bootloader.branch {
+        if let Some(ref branch) = config.bootloader.branch {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -267,7 +1152,7 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 ).as_bytes(),
             ).context("Failed to write to Cargo.toml for bootloader download crate")?;
         }
-        if let &Some(ref path) = &config.bootloader.path {
+        if let Some(ref path) = config.bootloader.path {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -288,8 +1173,17 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
 
     let mut command = process::Command::new("cargo");
     command.arg("fetch");
+    if offline {
+        command.arg("--offline");
+    }
     command.current_dir(bootloader_dir);
     if !command.status()?.success() {
+        if offline {
+            Err(format_err!(
+                "bootloader source not available offline; run a build online first or vendor \
+                 dependencies"
+            ))?
+        }
         Err(format_err!("Bootloader download failed."))?
     }
 
@@ -307,35 +1201,140 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
     Ok(bootloader.clone())
 }
 
-fn build_bootloader(bootloader_dir: &Path, config: &Config) -> Result<Box<[u8]>, Error> {
+/// Returns the bootloader version requirement: the `bootloader-version-requirement` key
+/// if set, and else `BOOTLOADER_VERSION_REQ`.
+pub(crate) fn bootloader_version_req(config: &Config) -> &str {
+    config
+        .bootloader_version_requirement
+        .as_deref()
+        .unwrap_or(BOOTLOADER_VERSION_REQ)
+}
+
+/// Checks that the version of the bootloader crate is compatible with this bootimage version.
+///
+/// Incompatible versions would otherwise result in confusing errors when building the
+/// bootloader or booting the disk image.
+pub(crate) fn check_bootloader_version(bootloader: &CrateMetadata, config: &Config) -> Result<(), Error> {
+    let required = bootloader_version_req(config);
+    let required = VersionReq::parse(required)
+        .context(format_err!("Invalid bootloader version requirement `{}`", required))?;
+    let mut version = Version::parse(&bootloader.version).context(format_err!(
+        "Invalid version `{}` of bootloader crate `{}`",
+        bootloader.version,
+        bootloader.name
+    ))?;
+    // pre-releases such as `0.2.0-alpha` should match the same requirements as releases
+    version.pre.clear();
+    if !required.matches(&version) {
+        if config.bootloader_version_requirement.is_some() {
+            Err(format_err!(
+                "bootloader version {} does not match the `bootloader-version-requirement` `{}`",
+                bootloader.version,
+                bootloader_version_req(config)
+            ))?
+        }
+        Err(format_err!(
+            "bootloader version {} is incompatible with bootimage {} (requires `{}`); \
+             update `bootimage` or the `{}` crate",
+            bootloader.version,
+            env!("CARGO_PKG_VERSION"),
+            BOOTLOADER_VERSION_REQ,
+            bootloader.name
+        ))?
+    }
+    Ok(())
+}
+
+/// The `.bootloader` section of the bootloader executable.
+struct Bootloader {
+    data: Box<[u8]>,
+    /// The path of the bootloader executable.
+    elf_path: PathBuf,
+    /// The address the section is linked at, i.e. where the BIOS and the first stage load it.
+    load_address: u64,
+}
+
+/// Fails if memory hints (`kernel-stack-size` and `physical-memory-offset`) are configured
+/// for the bootloader of the given `version`.
+///
+/// The bootloaders of `BOOTLOADER_VERSION_REQ` only read the kernel size from the kernel
+/// info block and have no config section in the image, so no hint can be passed to them.
+/// Ignoring the hints would boot the kernel with another memory layout than configured.
+fn check_memory_hints(config: &Config, version: &str) -> Result<(), Error> {
+    let mut keys = Vec::new();
+    if config.kernel_stack_size.is_some() {
+        keys.push("`kernel-stack-size`");
+    }
+    if config.physical_memory_offset.is_some() {
+        keys.push("`physical-memory-offset`");
+    }
+    if !keys.is_empty() {
+        Err(format_err!(
+            "bootloader {} can't be configured with {} (it has no config section); remove the key",
+            version,
+            keys.join(" and ")
+        ))?
+    }
+    Ok(())
+}
+
+fn build_bootloader(bootloader_dir: &Path, config: &Config, args: &Args) -> Result<Bootloader, Error> {
     use std::io::Read;
 
-    let bootloader_metadata = download_bootloader(bootloader_dir, config)?;
+    let bootloader_metadata = download_bootloader(bootloader_dir, config, args.offline())?;
+    // the version check is only a heuristic, so a mismatch is fatal only with `--strict`
+    if let Err(err) = check_bootloader_version(&bootloader_metadata, config) {
+        if args.strict() {
+            return Err(err);
+        }
+        eprintln!("warning: {}", err);
+    }
+    check_memory_hints(config, &bootloader_metadata.version).context(ErrorKind::Config)?;
     let bootloader_dir = Path::new(&bootloader_metadata.manifest_path)
         .parent()
         .unwrap();
 
-    let mut bootloader_target_path = PathBuf::from(bootloader_dir);
-    bootloader_target_path.push(&config.bootloader.target);
+    let bootloader_target_path = bootloader_target(config, bootloader_dir)?;
 
     let bootloader_elf_path = if !config.bootloader.precompiled {
-        let args = &[
+        let mut xbuild_args = vec![
             String::from("--manifest-path"),
             bootloader_metadata.manifest_path.clone(),
             String::from("--target"),
             bootloader_target_path.display().to_string(),
             String::from("--release"),
         ];
+        // `--frozen` would also require an up-to-date lock file of the bootloader
+        if args.offline() {
+            xbuild_args.push(String::from("--offline"));
+        }
 
         println!("Building bootloader");
-        let exit_status = run_xbuild(args).context("Failed to run `cargo xbuild`")?;
+        let mut command = xbuild_command(&xbuild_args);
+        let mut rustflags = config.bootloader_rustflags.clone();
+        if args.reproducible() {
+            // the paths in panic messages would otherwise depend on the location of the
+            // cargo registry
+            let rustflags = rustflags.get_or_insert_with(|| {
+                env::var("RUSTFLAGS")
+                    .map(|flags| flags.split_whitespace().map(String::from).collect())
+                    .unwrap_or_default()
+            });
+            rustflags.push(format!("--remap-path-prefix={}=/bootloader", bootloader_dir.display()));
+        }
+        if let Some(ref rustflags) = rustflags {
+            // `CARGO_ENCODED_RUSTFLAGS` would take precedence over `RUSTFLAGS`
+            command.env("RUSTFLAGS", rustflags.join(" "));
+            command.env_remove("CARGO_ENCODED_RUSTFLAGS");
+        }
+        let exit_status = run_xbuild(command).context("Failed to run `cargo xbuild`")?;
         if !exit_status.success() {
-            process::exit(1)
+            Err(format_err!("`cargo xbuild` of the bootloader failed ({})", exit_status))?
         }
 
         let mut bootloader_elf_path = bootloader_dir.to_path_buf();
         bootloader_elf_path.push("target");
-        bootloader_elf_path.push(config.bootloader.target.file_stem().unwrap());
+        bootloader_elf_path.push(target_name(&bootloader_target_path.to_string_lossy()));
         bootloader_elf_path.push("release");
         bootloader_elf_path.push("bootloader");
         bootloader_elf_path
@@ -350,42 +1349,82 @@ fn build_bootloader(bootloader_dir: &Path, config: &Config) -> Result<Box<[u8]>,
     bootloader.read_to_end(&mut bootloader_elf_bytes).context("Could not read bootloader")?;
 
     // copy bootloader section of ELF file to bootloader_path
-    let elf_file = xmas_elf::ElfFile::new(&bootloader_elf_bytes).unwrap();
-    xmas_elf::header::sanity_check(&elf_file).unwrap();
+    let elf_file = xmas_elf::ElfFile::new(&bootloader_elf_bytes)
+        .map_err(|err| format_err!("Could not parse bootloader ELF file: {}", err))?;
+    xmas_elf::header::sanity_check(&elf_file)
+        .map_err(|err| format_err!("Invalid bootloader ELF file: {}", err))?;
     let bootloader_section = elf_file
         .find_section_by_name(".bootloader")
-        .expect("bootloader must have a .bootloader section");
+        .ok_or(format_err!("Bootloader must have a .bootloader section"))?;
 
-    Ok(Vec::from(bootloader_section.raw_data(&elf_file)).into_boxed_slice())
+    Ok(Bootloader {
+        data: Vec::from(bootloader_section.raw_data(&elf_file)).into_boxed_slice(),
+        load_address: bootloader_section.address(),
+        elf_path: bootloader_elf_path,
+    })
 }
 
+/// The layout of a raw disk image created by `create_disk_image`.
+///
+/// The image consists of the bootloader, the kernel info block, and the kernel executable,
+/// followed by padding up to the next block and up to the `minimum-image-size`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DiskLayout {
+    /// The size of the bootloader at offset 0.
+    pub bootloader_size: u64,
+    /// The offset of the kernel info block, which is `BLOCK_SIZE` bytes long.
+    pub kernel_info_offset: u64,
+    /// The offset of the kernel executable.
+    pub kernel_offset: u64,
+    pub kernel_size: u64,
+    /// The total size of the image, including padding.
+    pub image_size: u64,
+}
+
+/// Returns the path of the raw bootimage: the configured `output` or else
+/// `bootimage-<bin>.bin` in `out_dir`, or `bootimage-<bin>-<feature tag>.bin` with
+/// `--feature-tagged-output` (see `Args::feature_tag`).
+fn image_path(out_dir: &Path, bin_name: &str, config: &Config, args: &Args) -> PathBuf {
+    match config.output {
+        Some(ref output) => output.clone(),
+        None if args.feature_tagged_output() || config.feature_tagged_output => {
+            out_dir.join(format!("bootimage-{}-{}.bin", bin_name, args.feature_tag()))
+        }
+        None => out_dir.join(format!("bootimage-{}.bin", bin_name)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn create_disk_image(
     root_dir: &Path,
-    out_dir: &Path,
-    bin_name: &str,
+    output_path: PathBuf,
     config: &Config,
     mut kernel: File,
     kernel_info_block: KernelInfoBlock,
     bootloader_data: &[u8],
+    partition_table: PartitionTable,
     verbose: bool,
-) -> Result<PathBuf, Error> {
-    use std::io::{Read, Write};
+) -> Result<(PathBuf, DiskLayout), Error> {
+    use std::io::{Read, Seek, SeekFrom, Write};
 
-    let mut output_path = PathBuf::from(out_dir);
-    let file_name = format!("bootimage-{}.bin", bin_name);
-    output_path.push(file_name);
-
-    if let Some(ref output) = config.output {
-        output_path = output.clone();
-    }
 
     if verbose {
         println!("Creating disk image at {}",
             output_path.strip_prefix(root_dir).unwrap_or(output_path.as_path()).display());
     }
-    let mut output = File::create(&output_path).context("Could not create output bootimage file")?;
-    output.write_all(&bootloader_data).context("Could not write output bootimage file")?;
-    output.write_all(&kernel_info_block).context("Could not write output bootimage file")?;
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent).context(format_err!(
+            "Could not create directory {} for output bootimage file",
+            parent.display()
+        ))?;
+    }
+    let write_error = || format_err!("Could not write output bootimage file {}", output_path.display());
+    let mut output = File::create(&output_path).context(format_err!(
+        "Could not create output bootimage file {}",
+        output_path.display()
+    ))?;
+    output.write_all(bootloader_data).context(write_error())?;
+    output.write_all(&kernel_info_block).context(write_error())?;
 
     // write out kernel elf file
     let kernel_size = kernel.metadata()?.len();
@@ -398,13 +1437,13 @@ fn create_disk_image(
             Err(e) => Err(e)?,
         };
         if !interrupted {
-            output.write_all(&buffer[..n])?
+            output.write_all(&buffer[..n]).context(write_error())?
         }
     }
 
     let padding_size = ((512 - (kernel_size % 512)) % 512) as usize;
     let padding = [0u8; 512];
-    output.write_all(&padding[..padding_size]).context("Could not write output bootimage file")?;
+    output.write_all(&padding[..padding_size]).context(write_error())?;
 
     if let Some(min_size) = config.minimum_image_size {
         // we already wrote to output successfully,
@@ -414,5 +1453,513 @@ fn create_disk_image(
         }
     }
 
-    Ok(output_path)
+    if partition_table == PartitionTable::Mbr {
+        let image_size = output.metadata().context(write_error())?.len();
+        let entries = mbr_partition_table(bootloader_data, image_size)?;
+        output.seek(SeekFrom::Start(MBR_PARTITION_TABLE_OFFSET as u64)).context(write_error())?;
+        output.write_all(&entries).context(write_error())?;
+    }
+
+    // the output may be on a different filesystem than the target directory (e.g. a
+    // mounted volume in CI), so make sure that write errors are reported here
+    output.sync_all().context(write_error())?;
+
+    let bootloader_size = bootloader_data.len() as u64;
+    let layout = DiskLayout {
+        bootloader_size,
+        kernel_info_offset: bootloader_size,
+        kernel_offset: bootloader_size + BLOCK_SIZE as u64,
+        kernel_size,
+        image_size: output.metadata().context(write_error())?.len(),
+    };
+    Ok((output_path, layout))
+}
+
+/// Creates the partition table of `--partition-table mbr` for an image of the given size.
+///
+/// The layout of the image is not changed, since the bootloader loads its later stages
+/// and the kernel from fixed sectors. The first sector stays the boot sector of the
+/// bootloader, and the only partition is marked active and spans the rest of the image
+/// (from sector 1), with the type `0xda` ("non-FS data"). The other three entries are
+/// empty. This requires that the bootloader leaves the partition table area of its
+/// first sector (bytes 446 to 509) empty.
+fn mbr_partition_table(bootloader_data: &[u8], image_size: u64) -> Result<[u8; 64], Error> {
+    let table_area = bootloader_data
+        .get(MBR_PARTITION_TABLE_OFFSET..BLOCK_SIZE - 2)
+        .ok_or_else(|| format_err!("The bootloader is smaller than a sector"))?;
+    if table_area.iter().any(|&byte| byte != 0) {
+        Err(format_err!(
+            "The boot sector of the bootloader uses the area of the partition table, so \
+             `--partition-table mbr` is not supported with this bootloader"
+        ))?
+    }
+
+    let first_lba = 1u64;
+    let sectors = image_size / BLOCK_SIZE as u64 - first_lba;
+    if first_lba + sectors > u64::from(u32::MAX) {
+        Err(format_err!("The bootimage is too big for an MBR partition table"))?
+    }
+
+    let mut entries = [0u8; 64];
+    {
+        let entry = &mut entries[..16];
+        entry[0] = 0x80; // active
+        entry[1..4].copy_from_slice(&chs_address(first_lba));
+        entry[4] = MBR_PARTITION_TYPE;
+        entry[5..8].copy_from_slice(&chs_address(first_lba + sectors - 1));
+        LittleEndian::write_u32(&mut entry[8..12], first_lba as u32);
+        LittleEndian::write_u32(&mut entry[12..16], sectors as u32);
+    }
+    Ok(entries)
+}
+
+/// Returns the CHS address of the given sector for an MBR partition entry.
+///
+/// The common geometry of 255 heads and 63 sectors per track is used. Sectors beyond
+/// the CHS range get the maximum address, so that only their LBA address is used.
+fn chs_address(lba: u64) -> [u8; 3] {
+    const HEADS: u64 = 255;
+    const SECTORS: u64 = 63;
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS) % HEADS;
+    let sector = lba % SECTORS + 1;
+    [
+        head as u8,
+        (sector as u8) | (((cylinder >> 2) & 0xc0) as u8),
+        cylinder as u8,
+    ]
+}
+
+/// Returns a minimal x86_64 kernel executable with a single loadable segment of
+/// `segment_size` bytes at `0x200000`.
+#[cfg(test)]
+pub(crate) fn stub_kernel(segment_size: u64) -> Vec<u8> {
+    const HEADER_SIZE: usize = 64;
+    const PROGRAM_HEADER_SIZE: usize = 56;
+    let segment_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+    let mut kernel = vec![0u8; HEADER_SIZE + PROGRAM_HEADER_SIZE];
+    kernel[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    LittleEndian::write_u16(&mut kernel[16..18], 2); // executable
+    LittleEndian::write_u16(&mut kernel[18..20], 0x3e); // x86_64
+    LittleEndian::write_u32(&mut kernel[20..24], 1);
+    LittleEndian::write_u64(&mut kernel[24..32], 0x20_0000);
+    LittleEndian::write_u64(&mut kernel[32..40], HEADER_SIZE as u64);
+    LittleEndian::write_u16(&mut kernel[52..54], HEADER_SIZE as u16);
+    LittleEndian::write_u16(&mut kernel[54..56], PROGRAM_HEADER_SIZE as u16);
+    LittleEndian::write_u16(&mut kernel[56..58], 1);
+    {
+        let program_header = &mut kernel[HEADER_SIZE..];
+        LittleEndian::write_u32(&mut program_header[0..4], 1); // loadable
+        LittleEndian::write_u32(&mut program_header[4..8], 5); // readable and executable
+        LittleEndian::write_u64(&mut program_header[8..16], segment_offset);
+        LittleEndian::write_u64(&mut program_header[16..24], 0x20_0000);
+        LittleEndian::write_u64(&mut program_header[24..32], 0x20_0000);
+        LittleEndian::write_u64(&mut program_header[32..40], segment_size);
+        LittleEndian::write_u64(&mut program_header[40..48], segment_size + 0x1000);
+        LittleEndian::write_u64(&mut program_header[48..56], 8);
+    }
+    kernel.resize(segment_offset as usize + segment_size as usize, 0x90);
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_name_of_json_path_is_file_stem() {
+        assert_eq!(target_name("x86_64-blog_os.json"), "x86_64-blog_os");
+    }
+
+    #[test]
+    fn target_name_of_nested_json_path_is_file_stem() {
+        assert_eq!(target_name("../targets/x86_64-blog_os.json"), "x86_64-blog_os");
+        assert_eq!(target_name("/abs/path/to/x86_64-blog_os.json"), "x86_64-blog_os");
+    }
+
+    #[test]
+    fn target_name_of_bare_triple_is_unchanged() {
+        assert_eq!(target_name("x86_64-unknown-none"), "x86_64-unknown-none");
+        assert_eq!(target_name("thumbv7em-none-eabihf"), "thumbv7em-none-eabihf");
+    }
+
+    #[test]
+    fn target_name_without_json_extension_is_unchanged() {
+        assert_eq!(target_name("targets/x86_64-blog_os"), "targets/x86_64-blog_os");
+        assert_eq!(target_name("x86_64-blog_os.toml"), "x86_64-blog_os.toml");
+    }
+
+    /// Assembles a raw bootimage from fixed inputs and returns its contents.
+    fn assemble(dir: &Path, name: &str, partition_table: PartitionTable) -> Vec<u8> {
+        let kernel_path = dir.join("kernel");
+        if !kernel_path.exists() {
+            let kernel: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+            fs::write(&kernel_path, kernel).unwrap();
+        }
+        let mut bootloader = vec![0u8; 2 * BLOCK_SIZE];
+        bootloader[..4].copy_from_slice(b"boot");
+        bootloader[BLOCK_SIZE - 2..BLOCK_SIZE].copy_from_slice(&[0x55, 0xaa]);
+        let config = config::default_config(&dir.join("Cargo.toml"));
+        let kernel = File::open(&kernel_path).unwrap();
+        let kernel_size = kernel.metadata().unwrap().len();
+        let kernel_info_block = create_kernel_info_block(kernel_size).unwrap();
+        let (path, _) = create_disk_image(
+            dir,
+            dir.join(name),
+            &config,
+            kernel,
+            kernel_info_block,
+            &bootloader,
+            partition_table,
+            false,
+        ).unwrap();
+        fs::read(path).unwrap()
+    }
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(data).to_vec()
+    }
+
+    #[test]
+    fn assembly_of_identical_inputs_is_byte_identical() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        for &partition_table in &[PartitionTable::None, PartitionTable::Mbr] {
+            let first = assemble(dir.path(), "first.bin", partition_table);
+            // a later assembly must not depend on the time or on the previous output
+            std::thread::sleep(Duration::from_millis(10));
+            let second = assemble(dir.path(), "second.bin", partition_table);
+            assert_eq!(sha256(&first), sha256(&second));
+            assert_eq!(first.len() % BLOCK_SIZE, 0);
+        }
+    }
+
+    /// Returns cargo metadata with a package `kernel` at `/kernel/Cargo.toml`, which has
+    /// the given targets as `(name, kind)`.
+    fn metadata(targets: &[(&str, &str)]) -> CargoMetadata {
+        let targets: Vec<_> = targets
+            .iter()
+            .map(|&(name, kind)| {
+                json!({
+                    "name": name,
+                    "kind": [kind],
+                    "crate_types": ["bin"],
+                    "src_path": format!("/kernel/src/{}.rs", name),
+                })
+            })
+            .collect();
+        let metadata = json!({
+            "packages": [{
+                "name": "kernel",
+                "version": "0.1.0",
+                "id": "kernel 0.1.0 (path+file:///kernel)",
+                "dependencies": [],
+                "targets": targets,
+                "features": {},
+                "manifest_path": "/kernel/Cargo.toml",
+            }],
+            "workspace_members": [],
+            "resolve": null,
+            "workspace_root": "/kernel",
+            "target_directory": "/kernel/target",
+            "version": 1,
+        });
+        serde_json::from_value(metadata).unwrap()
+    }
+
+    fn check(args: &[&str], targets: &[(&str, &str)]) -> Result<(), Error> {
+        let config = config::default_config(Path::new("/kernel/Cargo.toml"));
+        check_bin_target(&args::build_args(args), &config, &metadata(targets))
+    }
+
+    const TARGETS: &[(&str, &str)] = &[
+        ("kernel", "bin"),
+        ("kernel-debug", "bin"),
+        ("basic_boot", "example"),
+        ("vga_demo", "example"),
+    ];
+
+    #[test]
+    fn existing_bin_or_example_target_is_accepted() {
+        check(&["--bin", "kernel-debug"], TARGETS).unwrap();
+        check(&["--example", "vga_demo"], TARGETS).unwrap();
+        check(&[], TARGETS).unwrap();
+    }
+
+    #[test]
+    fn wrong_bin_name_is_error_with_suggestion() {
+        assert_eq!(
+            check(&["--bin", "kernal"], TARGETS).unwrap_err().to_string(),
+            "no bin target named 'kernal'; available: kernel, kernel-debug (did you mean 'kernel'?)"
+        );
+    }
+
+    #[test]
+    fn wrong_example_name_is_error_with_suggestion() {
+        assert_eq!(
+            check(&["--example", "basic-boot"], TARGETS).unwrap_err().to_string(),
+            "no example target named 'basic-boot'; available: basic_boot, vga_demo (did you mean 'basic_boot'?)"
+        );
+    }
+
+    #[test]
+    fn target_of_other_kind_is_not_accepted() {
+        assert_eq!(
+            check(&["--bin", "vga_demo"], TARGETS).unwrap_err().to_string(),
+            "no bin target named 'vga_demo'; available: kernel, kernel-debug"
+        );
+    }
+
+    #[test]
+    fn missing_target_kind_is_error() {
+        assert_eq!(
+            check(&["--example", "vga_demo"], &[("kernel", "bin")]).unwrap_err().to_string(),
+            "no example target named 'vga_demo'; the package has no example targets"
+        );
+    }
+
+    #[test]
+    fn package_outside_metadata_is_not_checked() {
+        let config = config::default_config(Path::new("/other/Cargo.toml"));
+        let args = args::build_args(&["--bin", "kernal"]);
+        check_bin_target(&args, &config, &metadata(TARGETS)).unwrap();
+    }
+
+    #[test]
+    fn memory_hints_are_rejected() {
+        let mut config = config::default_config(Path::new("Cargo.toml"));
+        check_memory_hints(&config, "0.2.0").unwrap();
+        config.kernel_stack_size = Some(512 * 1024);
+        assert_eq!(
+            check_memory_hints(&config, "0.2.0").unwrap_err().to_string(),
+            "bootloader 0.2.0 can't be configured with `kernel-stack-size` (it has no config \
+             section); remove the key"
+        );
+        config.physical_memory_offset = Some(0xffff_8000_0000_0000);
+        let err = check_memory_hints(&config, "0.2.0").unwrap_err().to_string();
+        assert!(err.contains("`kernel-stack-size` and `physical-memory-offset`"), "{}", err);
+    }
+
+    fn set_modified(path: &Path, time: SystemTime) {
+        File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn image_is_reassembled_if_an_input_is_touched() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let inputs = ["kernel", "Cargo.toml", "bootloader", "user-config.toml", "workspace/Cargo.toml"];
+        fs::create_dir(path("workspace")).unwrap();
+        for input in &inputs {
+            fs::write(path(input), "").unwrap();
+        }
+        let mut config = config::default_config(&path("Cargo.toml"));
+        config.config_files = vec![path("user-config.toml"), path("workspace/Cargo.toml")];
+        let args = args::build_args(&[]);
+        let image = path("bootimage-kernel.bin");
+        fs::write(&image, "image").unwrap();
+        fs::write(fingerprint_path(&image), fingerprint_file(&args, &path("bootloader"))).unwrap();
+
+        // newer than the bootimage executable, which was just built
+        let assembled = SystemTime::now() + Duration::from_secs(3600);
+        set_modified(&image, assembled);
+        assert!(image_is_fresh(&image, &path("kernel"), &args, &config));
+        assert!(!image_is_fresh(&image, &path("kernel"), &args::build_args(&["--force"]), &config));
+        for input in &inputs {
+            set_modified(&path(input), assembled + Duration::from_secs(1));
+            assert!(!image_is_fresh(&image, &path("kernel"), &args, &config), "{} touched", input);
+            set_modified(&path(input), assembled - FRESHNESS_MARGIN);
+            assert!(image_is_fresh(&image, &path("kernel"), &args, &config), "{} restored", input);
+        }
+    }
+
+    #[test]
+    fn image_with_other_fingerprint_or_without_bootloader_is_reassembled() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let kernel = dir.path().join("kernel");
+        let manifest = dir.path().join("Cargo.toml");
+        let image = dir.path().join("bootimage-kernel.bin");
+        for path in &[&kernel, &manifest, &image] {
+            fs::write(path, "").unwrap();
+        }
+        set_modified(&image, SystemTime::now() + Duration::from_secs(3600));
+        let config = config::default_config(&manifest);
+        let args = args::build_args(&[]);
+        fs::write(fingerprint_path(&image), fingerprint_file(&args, &kernel)).unwrap();
+        assert!(image_is_fresh(&image, &kernel, &args, &config));
+        assert!(!image_is_fresh(&image, &kernel, &args::build_args(&["--release"]), &config));
+        fs::write(fingerprint_path(&image), args.fingerprint()).unwrap();
+        assert!(!image_is_fresh(&image, &kernel, &args, &config));
+        fs::remove_file(fingerprint_path(&image)).unwrap();
+        assert!(!image_is_fresh(&image, &kernel, &args, &config));
+    }
+
+    #[test]
+    fn mbr_image_has_partition_entry_and_boot_signature() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let image = assemble(dir.path(), "mbr.bin", PartitionTable::Mbr);
+        assert_eq!(&image[BLOCK_SIZE - 2..BLOCK_SIZE], [0x55, 0xaa]);
+        let sectors = (image.len() / BLOCK_SIZE - 1) as u32;
+        let entry = &image[MBR_PARTITION_TABLE_OFFSET..MBR_PARTITION_TABLE_OFFSET + 16];
+        assert_eq!(entry[0], 0x80);
+        assert_eq!(entry[1..4], chs_address(1));
+        assert_eq!(entry[4], MBR_PARTITION_TYPE);
+        assert_eq!(entry[5..8], chs_address(u64::from(sectors)));
+        assert_eq!(LittleEndian::read_u32(&entry[8..12]), 1);
+        assert_eq!(LittleEndian::read_u32(&entry[12..16]), sectors);
+        let other_entries = &image[MBR_PARTITION_TABLE_OFFSET + 16..BLOCK_SIZE - 2];
+        assert!(other_entries.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn bootloader_using_partition_table_area_is_rejected() {
+        let mut bootloader = vec![0u8; BLOCK_SIZE];
+        mbr_partition_table(&bootloader, 8 * BLOCK_SIZE as u64).unwrap();
+        bootloader[MBR_PARTITION_TABLE_OFFSET] = 1;
+        assert!(mbr_partition_table(&bootloader, 8 * BLOCK_SIZE as u64).is_err());
+        assert!(mbr_partition_table(&[0; 256], 8 * BLOCK_SIZE as u64).is_err());
+    }
+
+    #[test]
+    fn chs_address_is_clamped_beyond_1024_cylinders() {
+        assert_eq!(chs_address(0), [0, 1, 0]);
+        assert_eq!(chs_address(62), [0, 63, 0]);
+        assert_eq!(chs_address(63), [1, 1, 0]);
+        assert_eq!(chs_address(255 * 63), [0, 1, 1]);
+        // the upper two bits of the 10-bit cylinder are stored in the sector byte
+        assert_eq!(chs_address(256 * 255 * 63), [0, 0x41, 0]);
+        assert_eq!(chs_address(1024 * 255 * 63 - 1), [254, 0xff, 0xff]);
+        assert_eq!(chs_address(1024 * 255 * 63), [0xfe, 0xff, 0xff]);
+        assert_eq!(chs_address(u64::from(u32::MAX)), [0xfe, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn manifest_is_found_in_nearest_parent_directory() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let kernel_dir = dir.path().join("workspace").join("kernel");
+        let nested_dir = kernel_dir.join("src").join("bin");
+        fs::create_dir_all(&nested_dir).unwrap();
+        fs::write(dir.path().join("workspace").join("Cargo.toml"), "[workspace]").unwrap();
+        fs::write(kernel_dir.join("Cargo.toml"), "[package]").unwrap();
+        assert_eq!(find_manifest(&nested_dir).unwrap(), kernel_dir.join("Cargo.toml"));
+        assert_eq!(find_manifest(&kernel_dir).unwrap(), kernel_dir.join("Cargo.toml"));
+        // a directory named `Cargo.toml` is not a manifest
+        fs::create_dir(nested_dir.join("Cargo.toml")).unwrap();
+        assert_eq!(find_manifest(&nested_dir).unwrap(), kernel_dir.join("Cargo.toml"));
+    }
+
+    #[test]
+    fn image_path_is_feature_tagged_only_if_enabled() {
+        let out_dir = Path::new("/target/x86_64-blog_os/debug");
+        let mut config = config::default_config(Path::new("/kernel/Cargo.toml"));
+        let args = args::build_args(&["--features", "uefi"]);
+        assert_eq!(image_path(out_dir, "kernel", &config, &args), out_dir.join("bootimage-kernel.bin"));
+
+        let tagged = out_dir.join(format!("bootimage-kernel-{}.bin", args.feature_tag()));
+        let tagged_args = args::build_args(&["--features", "uefi", "--feature-tagged-output"]);
+        assert_eq!(image_path(out_dir, "kernel", &config, &tagged_args), tagged);
+        config.feature_tagged_output = true;
+        assert_eq!(image_path(out_dir, "kernel", &config, &args), tagged);
+        // a configured `output` is used as is
+        config.output = Some(PathBuf::from("/images/kernel.img"));
+        assert_eq!(image_path(out_dir, "kernel", &config, &tagged_args), Path::new("/images/kernel.img"));
+    }
+
+    #[test]
+    fn output_path_for_target_appends_target_name() {
+        assert_eq!(
+            output_path_for_target(Path::new("images/kernel.img"), "../x86_64-blog_os.json"),
+            Path::new("images/kernel-x86_64-blog_os.img")
+        );
+        assert_eq!(
+            output_path_for_target(Path::new("kernel"), "x86_64-unknown-none"),
+            Path::new("kernel-x86_64-unknown-none")
+        );
+    }
+
+    #[test]
+    fn build_std_is_passed_to_cargo() {
+        let mut config = config::default_config(Path::new("Cargo.toml"));
+        assert!(build_std_args(&config).is_empty());
+        config.build_std_features = Some(vec![String::from("compiler-builtins-mem")]);
+        assert!(build_std_args(&config).is_empty());
+        config.build_std = Some(vec![String::from("core"), String::from("alloc")]);
+        assert_eq!(
+            build_std_args(&config),
+            ["-Z", "build-std=core,alloc", "-Z", "build-std-features=compiler-builtins-mem"]
+        );
+    }
+
+    #[test]
+    fn undersized_image_is_reported() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let kernel_path = dir.path().join("kernel");
+        fs::write(&kernel_path, stub_kernel(3000)).unwrap();
+        let image_path = dir.path().join("bootimage-kernel.bin");
+        let bootloader_size = 2 * BLOCK_SIZE as u64;
+
+        fs::write(&image_path, vec![0u8; bootloader_size as usize + 3000]).unwrap();
+        check_image_complete(&image_path, bootloader_size, &kernel_path).unwrap();
+        fs::write(&image_path, vec![0u8; bootloader_size as usize + 2999]).unwrap();
+        assert_eq!(
+            check_image_complete(&image_path, bootloader_size, &kernel_path).unwrap_err().to_string(),
+            format!("produced bootimage is suspiciously small ({} bytes); assembly likely failed", bootloader_size + 2999)
+        );
+    }
+
+    #[test]
+    fn no_build_uses_existing_kernel_only() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let config = config::default_config(&dir.path().join("Cargo.toml"));
+        let args = args::build_args(&["--no-build"]);
+        let kernel_path = dir.path().join("kernel");
+        assert_eq!(
+            build_kernel(dir.path(), "kernel", &args, &config, false).unwrap_err().to_string(),
+            format!("kernel artifact {} not found; build first", kernel_path.display())
+        );
+        fs::write(&kernel_path, stub_kernel(16)).unwrap();
+        assert_eq!(build_kernel(dir.path(), "kernel", &args, &config, false).unwrap(), kernel_path);
+    }
+
+    #[test]
+    fn example_kernel_is_in_examples_directory() {
+        let out_dir = Path::new("/target/x86_64-blog_os/debug");
+        let example = args::build_args(&["--example", "vga_demo"]);
+        assert_eq!(kernel_path(out_dir, "vga_demo", &example), out_dir.join("examples").join("vga_demo"));
+        let bin = args::build_args(&["--bin", "kernel"]);
+        assert_eq!(kernel_path(out_dir, "kernel", &bin), out_dir.join("kernel"));
+    }
+
+    #[test]
+    fn default_run_selects_kernel_binary() {
+        let mut config = config::default_config(Path::new("/kernel/Cargo.toml"));
+        let no_args = args::build_args(&[]);
+        let bins = metadata(&[("boot", "bin"), ("boot-debug", "bin")]);
+        let err = default_bin_name(&bins.packages[0], &config).unwrap_err();
+        assert!(err.to_string().contains("could not determine which binary to use"), "{}", err);
+        assert_eq!(kernel_bin_name(&no_args, &config, &metadata(TARGETS)).unwrap(), "kernel");
+
+        config.default_run = Some(String::from("boot-debug"));
+        assert_eq!(kernel_bin_name(&no_args, &config, &bins).unwrap(), "boot-debug");
+        config.default_run = Some(String::from("kernel-debug"));
+        assert_eq!(kernel_bin_name(&no_args, &config, &metadata(TARGETS)).unwrap(), "kernel-debug");
+        let bin_args = args::build_args(&["--bin", "kernel"]);
+        assert_eq!(kernel_bin_name(&bin_args, &config, &metadata(TARGETS)).unwrap(), "kernel");
+    }
+
+    #[test]
+    fn image_over_size_limit_is_error() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let image_path = dir.path().join("bootimage-kernel.bin");
+        fs::write(&image_path, vec![0u8; 1440 * 1024]).unwrap();
+        check_image_size(&image_path, 1440 * 1024, false).unwrap();
+        check_image_size(&image_path, 2880 * 1024, false).unwrap();
+        assert_eq!(
+            check_image_size(&image_path, 1440 * 1024 - 1, false).unwrap_err().to_string(),
+            "bootimage is 1474560 bytes, exceeding the limit of 1474559 bytes"
+        );
+    }
 }
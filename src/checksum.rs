@@ -0,0 +1,113 @@
+//! The checksum sidecar file of `--checksum`.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use args::ChecksumAlgorithm;
+use artifacts;
+use blake3;
+use failure::{Error, ResultExt};
+use sha2::{Digest, Sha256};
+
+/// Computes the digest of the bootimage at `image_path` and writes it to the sidecar file
+/// `<image>.<algorithm>` next to it (e.g. `bootimage-kernel.bin.sha256`).
+///
+/// The sidecar contains a single line in the format of `sha256sum` and `b3sum`
+/// (`<hex digest>  <file name>`), so it can be verified with e.g. `sha256sum -c`. The
+/// image is read in chunks, so large images are never loaded into memory as a whole.
+pub(crate) fn write_sidecar(
+    image_path: &Path,
+    algorithm: ChecksumAlgorithm,
+    quiet: bool,
+) -> Result<(), Error> {
+    let image = File::open(image_path)
+        .context(format_err!("Failed to open bootimage {}", image_path.display()))?;
+    let digest = match algorithm {
+        ChecksumAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            feed(image, |chunk| hasher.update(chunk))
+                .context(format_err!("Failed to read bootimage {}", image_path.display()))?;
+            hex(&hasher.finalize())
+        }
+        ChecksumAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            feed(image, |chunk| {
+                hasher.update(chunk);
+            }).context(format_err!("Failed to read bootimage {}", image_path.display()))?;
+            hex(hasher.finalize().as_bytes())
+        }
+    };
+
+    let file_name = image_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut sidecar_path = image_path.as_os_str().to_owned();
+    sidecar_path.push(".");
+    sidecar_path.push(algorithm.name());
+    let sidecar_path = PathBuf::from(sidecar_path);
+    let line = format!("{}  {}\n", digest, file_name);
+    artifacts::write_atomically(&sidecar_path, line.as_bytes(), "checksum file")?;
+
+    if !quiet {
+        println!("{} {}", algorithm.name(), digest);
+    }
+    Ok(())
+}
+
+/// Passes the contents of `reader` to `update` in chunks.
+fn feed<R: Read, F: FnMut(&[u8])>(mut reader: R, mut update: F) -> io::Result<()> {
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => update(&buffer[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Formats `bytes` as lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn hex_is_lowercase_and_zero_padded() {
+        assert_eq!(hex(&[]), "");
+        assert_eq!(hex(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+    }
+
+    fn sidecar(algorithm: ChecksumAlgorithm, extension: &str) -> String {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let image_path = dir.path().join("bootimage-kernel.bin");
+        fs::write(&image_path, b"abc").unwrap();
+        write_sidecar(&image_path, algorithm, true).unwrap();
+        fs::read_to_string(dir.path().join(format!("bootimage-kernel.bin.{}", extension))).unwrap()
+    }
+
+    #[test]
+    fn sha256_sidecar_matches_sha256sum_format() {
+        // `printf abc | sha256sum`
+        assert_eq!(
+            sidecar(ChecksumAlgorithm::Sha256, "sha256"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  bootimage-kernel.bin\n"
+        );
+    }
+
+    #[test]
+    fn blake3_sidecar_matches_b3sum_format() {
+        // `printf abc | b3sum`
+        assert_eq!(
+            sidecar(ChecksumAlgorithm::Blake3, "blake3"),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85  bootimage-kernel.bin\n"
+        );
+    }
+}
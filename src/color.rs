@@ -0,0 +1,126 @@
+//! Colored output of bootimage.
+//!
+//! Whether colors are used is decided in this order: an explicit `--color always` or
+//! `--color never`, then the `NO_COLOR` environment variable (https://no-color.org),
+//! then whether the output stream is a terminal. If the terminal detection fails or is
+//! not supported on the platform, no colors are used, since escape codes in a log file
+//! are worse than missing colors in a terminal.
+
+use std::env;
+use std::sync::atomic::{AtomicU8, Ordering};
+use failure::Error;
+
+/// The `ColorChoice` passed through `--color`, encoded by `ColorChoice::encode` (0 if
+/// `--color` was not passed).
+static CHOICE: AtomicU8 = AtomicU8::new(0);
+
+/// The color setting passed through `--color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub(crate) fn parse(choice: &str) -> Result<ColorChoice, Error> {
+        match choice {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            other => Err(format_err!(
+                "invalid `--color` value `{}` (expected `auto`, `always`, or `never`)",
+                other
+            )),
+        }
+    }
+
+    fn encode(self) -> u8 {
+        match self {
+            ColorChoice::Auto => 1,
+            ColorChoice::Always => 2,
+            ColorChoice::Never => 3,
+        }
+    }
+
+    fn decode(value: u8) -> Option<ColorChoice> {
+        match value {
+            1 => Some(ColorChoice::Auto),
+            2 => Some(ColorChoice::Always),
+            3 => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Sets the color setting passed through `--color`.
+pub(crate) fn set_choice(choice: ColorChoice) {
+    CHOICE.store(choice.encode(), Ordering::Relaxed);
+}
+
+/// An output stream of bootimage.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A color for `paint`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Color {
+    Red,
+    Green,
+}
+
+/// Returns `text` in the given color (and bold) if colors are enabled for the stream.
+pub(crate) fn paint(stream: Stream, color: Color, text: &str) -> String {
+    if !enabled(stream) {
+        return text.to_owned();
+    }
+    let code = match color {
+        Color::Red => 31,
+        Color::Green => 32,
+    };
+    format!("\x1b[1;{}m{}\x1b[0m", code, text)
+}
+
+/// Returns whether colors should be used for the given stream.
+pub(crate) fn enabled(stream: Stream) -> bool {
+    match ColorChoice::decode(CHOICE.load(Ordering::Relaxed)) {
+        Some(ColorChoice::Always) => return true,
+        Some(ColorChoice::Never) => return false,
+        Some(ColorChoice::Auto) | None => {}
+    }
+    // any non-empty value disables colors, as specified by https://no-color.org
+    if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    if env::var_os("TERM").is_some_and(|term| term == "dumb") {
+        return false;
+    }
+    is_terminal(stream).unwrap_or(false)
+}
+
+/// Returns whether the stream is a terminal, or `None` if this can't be determined.
+#[cfg(unix)]
+fn is_terminal(stream: Stream) -> Option<bool> {
+    use std::io;
+
+    let fd = match stream {
+        Stream::Stdout => libc::STDOUT_FILENO,
+        Stream::Stderr => libc::STDERR_FILENO,
+    };
+    if unsafe { libc::isatty(fd) } == 1 {
+        return Some(true);
+    }
+    // any other error (e.g. `EBADF` for a closed stream) leaves the stream type unknown
+    match io::Error::last_os_error().raw_os_error() {
+        Some(libc::ENOTTY) | Some(libc::EINVAL) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_terminal(_stream: Stream) -> Option<bool> {
+    None
+}
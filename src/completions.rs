@@ -0,0 +1,195 @@
+use failure::Error;
+use flags::{self, Flag, Value, SUBCOMMANDS};
+
+/// A shell supported by `bootimage completions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl Shell {
+    pub(crate) const NAMES: &'static [&'static str] = &["bash", "zsh", "fish", "powershell"];
+
+    pub(crate) fn parse(name: &str) -> Result<Shell, Error> {
+        match name {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "powershell" => Ok(Shell::Powershell),
+            other => Err(format_err!(
+                "unsupported shell `{}` (supported: {})",
+                other,
+                Shell::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Prints the completion script for the given shell to stdout.
+pub(crate) fn completions(shell: Shell) -> Result<(), Error> {
+    let script = match shell {
+        Shell::Bash => bash(),
+        Shell::Zsh => zsh(),
+        Shell::Fish => fish(),
+        Shell::Powershell => powershell(),
+    };
+    print!("{}", script);
+    Ok(())
+}
+
+/// All names of a flag, i.e. the name and the alias.
+fn names(flag: &Flag) -> Vec<&'static str> {
+    let mut names = vec![flag.name];
+    names.extend(flag.alias);
+    names
+}
+
+/// The subcommands that accept BUILD_OPTS.
+fn build_subcommands() -> impl Iterator<Item = &'static str> {
+    SUBCOMMANDS
+        .iter()
+        .map(|&(name, _)| name)
+        .filter(|&name| name != "completions")
+}
+
+fn bash() -> String {
+    let mut script = String::from("_bootimage() {\n");
+    script.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    script.push_str("    local prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    let subcommands: Vec<_> = SUBCOMMANDS.iter().map(|&(name, _)| name).collect();
+    script.push_str("    if [ \"$COMP_CWORD\" -eq 1 ]; then\n");
+    script.push_str(&format!(
+        "        COMPREPLY=($(compgen -W \"{} --help --version\" -- \"$cur\"))\n",
+        subcommands.join(" ")
+    ));
+    script.push_str("        return\n    fi\n");
+
+    script.push_str("    case \"$prev\" in\n");
+    for flag in flags::FLAGS {
+        let completion = match flag.value {
+            Some(Value::Path) => String::from("COMPREPLY=($(compgen -f -- \"$cur\"))"),
+            Some(Value::OneOf(values)) => format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", values.join(" ")),
+            Some(Value::Other) => String::from("COMPREPLY=()"),
+            None => continue,
+        };
+        script.push_str(&format!("        {})\n            {}\n            return;;\n", names(flag).join("|"), completion));
+    }
+    script.push_str("    esac\n");
+
+    script.push_str("    case \"${COMP_WORDS[1]}\" in\n");
+    for command in build_subcommands() {
+        let flags: Vec<_> = flags::for_command(command).flat_map(names).collect();
+        script.push_str(&format!(
+            "        {})\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"));;\n",
+            command,
+            flags.join(" ")
+        ));
+    }
+    script.push_str(&format!(
+        "        completions)\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"));;\n",
+        Shell::NAMES.join(" ")
+    ));
+    script.push_str("    esac\n}\ncomplete -F _bootimage bootimage\n");
+    script
+}
+
+fn zsh() -> String {
+    let mut script = String::from("#compdef bootimage\n\n_bootimage() {\n");
+    script.push_str("    local -a subcommands\n    subcommands=(\n");
+    for &(name, description) in SUBCOMMANDS {
+        script.push_str(&format!("        '{}:{}'\n", name, description));
+    }
+    script.push_str("    )\n");
+    script.push_str("    if (( CURRENT == 2 )); then\n        _describe 'command' subcommands\n        return\n    fi\n");
+    script.push_str("    case $words[2] in\n");
+    for command in build_subcommands() {
+        script.push_str(&format!("        {})\n            _arguments", command));
+        for flag in flags::for_command(command) {
+            let value = match flag.value {
+                Some(Value::Path) => String::from(":file:_files"),
+                Some(Value::OneOf(values)) => format!(":value:({})", values.join(" ")),
+                Some(Value::Other) => String::from(":value:"),
+                None => String::new(),
+            };
+            // a `*` allows zsh to complete the flag again
+            let repeat = if flag.repeatable { "*" } else { "" };
+            for name in names(flag) {
+                script.push_str(&format!(
+                    " \\\n                '{}{}[{}]{}'",
+                    repeat, name, flag.description, value
+                ));
+            }
+        }
+        script.push_str("\n            ;;\n");
+    }
+    script.push_str(&format!(
+        "        completions)\n            _arguments ':shell:({})'\n            ;;\n",
+        Shell::NAMES.join(" ")
+    ));
+    script.push_str("    esac\n}\n\n_bootimage \"$@\"\n");
+    script
+}
+
+fn fish() -> String {
+    let mut script = String::from("complete -c bootimage -f\n");
+    for &(name, description) in SUBCOMMANDS {
+        script.push_str(&format!(
+            "complete -c bootimage -n '__fish_use_subcommand' -a {} -d '{}'\n",
+            name, description
+        ));
+    }
+    for flag in flags::FLAGS {
+        let condition = format!("__fish_seen_subcommand_from {}", flag.commands.join(" "));
+        let value = match flag.value {
+            Some(Value::Path) => String::from(" -r -F"),
+            Some(Value::OneOf(values)) => format!(" -x -a '{}'", values.join(" ")),
+            Some(Value::Other) => String::from(" -x"),
+            None => String::new(),
+        };
+        for name in names(flag) {
+            let option = match name.strip_prefix("--") {
+                Some(long) => format!("-l {}", long),
+                None => format!("-s {}", name.trim_start_matches('-')),
+            };
+            script.push_str(&format!(
+                "complete -c bootimage -n '{}' {}{} -d '{}'\n",
+                condition, option, value, flag.description
+            ));
+        }
+    }
+    script.push_str(&format!(
+        "complete -c bootimage -n '__fish_seen_subcommand_from completions' -a '{}'\n",
+        Shell::NAMES.join(" ")
+    ));
+    script
+}
+
+fn powershell() -> String {
+    let quote = |names: &[&str]| -> String {
+        names.iter().map(|name| format!("'{}'", name)).collect::<Vec<_>>().join(", ")
+    };
+    let mut script = String::from(
+        "Register-ArgumentCompleter -Native -CommandName bootimage -ScriptBlock {\n\
+         \x20   param($wordToComplete, $commandAst, $cursorPosition)\n\
+         \x20   $elements = @($commandAst.CommandElements | ForEach-Object { $_.ToString() })\n\
+         \x20   if ($elements.Count -eq 1 -or ($elements.Count -eq 2 -and $wordToComplete -ne '')) {\n",
+    );
+    let subcommands: Vec<_> = SUBCOMMANDS.iter().map(|&(name, _)| name).collect();
+    script.push_str(&format!("        $completions = @({}, '--help', '--version')\n", quote(&subcommands)));
+    script.push_str("    } else {\n        $completions = switch ($elements[1]) {\n");
+    for command in build_subcommands() {
+        let flags: Vec<_> = flags::for_command(command).flat_map(names).collect();
+        script.push_str(&format!("            '{}' {{ @({}) }}\n", command, quote(&flags)));
+    }
+    script.push_str(&format!("            'completions' {{ @({}) }}\n", quote(Shell::NAMES)));
+    script.push_str("            default { @() }\n        }\n    }\n");
+    script.push_str(
+        "    $completions | Where-Object { $_ -like \"$wordToComplete*\" } | ForEach-Object {\n\
+         \x20       [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)\n\
+         \x20   }\n}\n",
+    );
+    script
+}
@@ -1,15 +1,128 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
 use std::path::{Path, PathBuf};
+use error;
 use failure::{Error, ResultExt};
+use semver::VersionReq;
 use toml::Value;
+use toml::value::Table;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub manifest_path: PathBuf,
+    /// The other files the configuration was read from (the user-global config and the
+    /// workspace `Cargo.toml`, if they exist).
+    pub config_files: Vec<PathBuf>,
+    /// The `package.default-run` key of the `Cargo.toml`.
+    pub default_run: Option<String>,
     pub default_target: Option<String>,
     pub output: Option<PathBuf>,
     pub bootloader: BootloaderConfig,
+    /// The `RUSTFLAGS` for the bootloader build (the environment is inherited if `None`).
+    pub bootloader_rustflags: Option<Vec<String>>,
+    /// A target for the bootloader build, relative to the directory of the kernel's
+    /// `Cargo.toml` (takes precedence over `bootloader.target`).
+    pub bootloader_target: Option<PathBuf>,
+    /// A semver requirement for the bootloader version that replaces the built-in
+    /// `BOOTLOADER_VERSION_REQ` of the compatibility check.
+    pub bootloader_version_requirement: Option<String>,
     pub minimum_image_size: Option<u64>,
-    pub run_command: Vec<String>,
+    /// The maximum size of the bootimage in bytes.
+    pub max_image_size: Option<u64>,
+    pub strip: bool,
+    /// Whether the bootimage name contains a hash of the cargo features.
+    pub feature_tagged_output: bool,
+    /// The size of the kernel stack that the bootloader should map, in bytes (a multiple
+    /// of `PAGE_SIZE`).
+    pub kernel_stack_size: Option<u64>,
+    /// The virtual address at which the bootloader should map the physical memory
+    /// (aligned to `PAGE_SIZE`).
+    pub physical_memory_offset: Option<u64>,
+    /// The standard library crates that cargo builds from source (`-Z build-std`).
+    pub build_std: Option<Vec<String>>,
+    /// The features of the standard library crates (`-Z build-std-features`).
+    pub build_std_features: Option<Vec<String>>,
+    pub pre_build_command: Option<Vec<String>>,
+    /// The configured run command (the default depends on the architecture of the kernel,
+    /// see `qemu::Arch::default_run_command`).
+    pub run_command: Option<Vec<String>>,
+    /// The working directory of QEMU, relative to the directory of the kernel's `Cargo.toml`
+    /// (the working directory of bootimage is inherited if `None`).
+    pub qemu_cwd: Option<PathBuf>,
+    /// The debugger command for `--gdb-attach` (`rust-gdb` or else `gdb` if `None`).
+    pub gdb_command: Option<Vec<String>>,
+    /// How long to retry connecting to a socket of QEMU, in seconds.
+    pub socket_connect_timeout: u64,
+    /// The exit code that signals a successful test, as configured (it is interpreted by
+    /// the `qemu::ExitMechanism`).
+    pub test_success_exit_code: Option<i32>,
+    /// Whether `test_success_exit_code` is the shifted exit status of `isa-debug-exit`.
+    pub test_exit_code_shifted: bool,
+    /// The `test-exit-mechanism` (`isa-debug-exit` or `semihosting`), or `None` for the
+    /// default of the architecture.
+    pub test_exit_mechanism: Option<String>,
+    /// The timeout for a single test in seconds.
+    pub test_timeout: u64,
+    /// How long a timed out test may shut down gracefully before it is killed (in
+    /// milliseconds).
+    pub kill_grace_period: u64,
+    /// Additional QEMU arguments for tests.
+    pub test_args: Vec<String>,
+    /// A command that decides the result of a test based on its serial output.
+    pub test_result_parser: Option<Vec<String>>,
+    /// A command that runs a test instead of QEMU, e.g. on real hardware.
+    pub test_command: Option<Vec<String>>,
+    /// Whether the pass/fail result of the test is inverted. Only set by `for_test`, since
+    /// `should-panic` is only allowed in the table of a single test.
+    pub should_panic: bool,
+    /// The `[package.metadata.bootimage.test."name"]` tables, keyed by test name.
+    pub test_overrides: BTreeMap<String, TestOverride>,
+}
+
+/// Per-test configuration that overrides the global test configuration.
+#[derive(Debug, Clone, Default)]
+pub struct TestOverride {
+    pub test_success_exit_code: Option<i32>,
+    pub test_exit_code_shifted: Option<bool>,
+    pub test_timeout: Option<u64>,
+    pub test_args: Option<Vec<String>>,
+    pub test_result_parser: Option<Vec<String>>,
+    pub test_command: Option<Vec<String>>,
+    pub should_panic: Option<bool>,
+}
+
+impl Config {
+    /// Returns the configuration for the test with the given name.
+    ///
+    /// The keys of the `[package.metadata.bootimage.test."name"]` table take precedence
+    /// over the global test configuration.
+    pub fn for_test(&self, name: &str) -> Config {
+        let mut config = self.clone();
+        if let Some(test_override) = self.test_overrides.get(name) {
+            if let Some(code) = test_override.test_success_exit_code {
+                config.test_success_exit_code = Some(code);
+            }
+            if let Some(shifted) = test_override.test_exit_code_shifted {
+                config.test_exit_code_shifted = shifted;
+            }
+            if let Some(timeout) = test_override.test_timeout {
+                config.test_timeout = timeout;
+            }
+            if let Some(ref args) = test_override.test_args {
+                config.test_args = args.clone();
+            }
+            if let Some(ref parser) = test_override.test_result_parser {
+                config.test_result_parser = Some(parser.clone());
+            }
+            if let Some(ref command) = test_override.test_command {
+                config.test_command = Some(command.clone());
+            }
+            if let Some(should_panic) = test_override.should_panic {
+                config.should_panic = should_panic;
+            }
+        }
+        config
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -23,38 +136,113 @@ pub struct BootloaderConfig {
     pub path: Option<PathBuf>,
 }
 
-pub(crate) fn read_config(manifest_path: PathBuf) -> Result<Config, Error> {
-    use std::{fs::File, io::Read};
-    let cargo_toml: Value = {
-        let mut content = String::new();
-        File::open(&manifest_path).context("Failed to open Cargo.toml")?
-            .read_to_string(&mut content).context("Failed to read Cargo.toml")?;
-        content.parse::<Value>().context("Failed to parse Cargo.toml")?
-    };
+/// The cargo features selected through `--features`, `--all-features`, and
+/// `--no-default-features`.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureSelection {
+    pub features: Vec<String>,
+    pub all_features: bool,
+    pub no_default_features: bool,
+}
 
-    let metadata = cargo_toml
+/// Reads the bootimage configuration of the given `Cargo.toml`.
+///
+/// The configuration is layered from the following sources, where each source takes
+/// precedence over the ones before it:
+///
+/// 1. the user-global `bootimage/config.toml` in `$XDG_CONFIG_HOME` (or `~/.config`),
+/// 2. the `[workspace.metadata.bootimage]` table of the `Cargo.toml` in `workspace_root`,
+/// 3. the `[package.metadata.bootimage]` table of the given `Cargo.toml`,
+/// 4. the `[package.metadata.bootimage.feature."name"]` tables of the enabled `features`,
+/// 5. the `overrides`, which are `--config` arguments.
+///
+/// All sources use the same keys. Nested tables such as `bootloader` are merged key by
+/// key. With `BOOTIMAGE_LOG=debug`, the source of each effective key is printed.
+pub(crate) fn read_config(
+    manifest_path: PathBuf,
+    workspace_root: &Path,
+    features: &FeatureSelection,
+    overrides: &[String],
+) -> Result<Config, Error> {
+    read_layered_config(user_config_path(), manifest_path, workspace_root, features, overrides)
+}
+
+/// Reads the configuration like `read_config`, with the user-global configuration at
+/// `user_config` (ignored if it doesn't exist).
+fn read_layered_config(
+    user_config: Option<PathBuf>,
+    manifest_path: PathBuf,
+    workspace_root: &Path,
+    features: &FeatureSelection,
+    overrides: &[String],
+) -> Result<Config, Error> {
+    let cargo_toml = read_toml(&manifest_path)?;
+
+    let default_run = cargo_toml
         .get("package")
-        .and_then(|table| table.get("metadata"))
-        .and_then(|table| table.get("bootimage"));
-    let metadata = match metadata {
-        None => {
-            return Ok(ConfigBuilder {
-                manifest_path: Some(manifest_path),
-                ..Default::default()
-            }.into())
-        }
-        Some(metadata) => metadata.as_table().ok_or(format_err!(
-            "Bootimage configuration invalid: {:?}",
-            metadata
-        ))?,
-    };
+        .and_then(|table| table.get("default-run"))
+        .and_then(|value| value.as_str())
+        .map(String::from);
+
+    let mut layers = Vec::new();
+    let mut config_files = Vec::new();
+    if let Some(path) = user_config {
+        if path.is_file() {
+            let table = match read_toml(&path)? {
+                Value::Table(table) => table,
+                _ => Table::new(),
+            };
+            layers.push((path.display().to_string(), table));
+            config_files.push(path);
+        }
+    }
+    let workspace_manifest = workspace_root.join("Cargo.toml");
+    if workspace_manifest.is_file() {
+        let workspace_toml = read_toml(&workspace_manifest)?;
+        if let Some(table) = metadata_table(&workspace_toml, "workspace")? {
+            layers.push((format!("[workspace.metadata.bootimage] of {}", workspace_manifest.display()), table));
+        }
+        if workspace_manifest != manifest_path {
+            config_files.push(workspace_manifest);
+        }
+    }
+    if let Some(table) = metadata_table(&cargo_toml, "package")? {
+        layers.push((format!("[package.metadata.bootimage] of {}", manifest_path.display()), table));
+    }
+
+    let mut metadata = Table::new();
+    for (_, table) in &layers {
+        merge_table(&mut metadata, table.clone());
+    }
+    for config_override in overrides {
+        apply_override(&mut metadata, config_override)?;
+    }
+    let feature_tables = metadata.remove("feature");
+    let enabled = enabled_features(&cargo_toml, features);
+    if let Some(ref feature_tables) = feature_tables {
+        apply_feature_tables(&mut metadata, feature_tables.clone(), &enabled)?;
+        // the feature tables must not take precedence over `--config` arguments
+        for config_override in overrides {
+            if !config_override.trim_start().starts_with("bootimage.feature.") {
+                apply_override(&mut metadata, config_override)?;
+            }
+        }
+    }
+    if error::debug_logging() {
+        for key in metadata.keys() {
+            let source = key_source(key, &layers, feature_tables.as_ref(), &enabled, overrides);
+            eprintln!("debug: config key `{}` from {}", key, source);
+        }
+    }
 
     let mut config = ConfigBuilder {
         manifest_path: Some(manifest_path),
+        config_files,
+        default_run,
         ..Default::default()
     };
 
-    for (key, value) in metadata {
+    for (key, value) in &metadata {
         match (key.as_str(), value.clone()) {
             ("default-target", Value::String(s)) => config.default_target = From::from(s),
             ("output", Value::String(s)) => config.output = Some(PathBuf::from(s)),
@@ -75,15 +263,34 @@ pub(crate) fn read_config(manifest_path: PathBuf) -> Result<Config, Error> {
                         ("path", Value::String(s)) => {
                             bootloader_config.path = Some(Path::new(&s).canonicalize()?);
                         }
-                        (key, value) => Err(format_err!(
-                            "unexpected \
-                             `package.metadata.bootimage.bootloader` key `{}` with value `{}`",
-                            key, value
-                        ))?,
+                        (key, value) => unknown_key("bootloader.", key, &value, BOOTLOADER_KEYS)?,
                     }
                 }
                 config.bootloader = Some(bootloader_config);
             }
+            ("bootloader-rustflags", Value::Array(array)) => {
+                let mut flags = Vec::new();
+                for value in array {
+                    match value {
+                        Value::String(s) => flags.push(s),
+                        _ => Err(format_err!(
+                            "bootloader-rustflags must be a list of strings"
+                        ))?,
+                    }
+                }
+                config.bootloader_rustflags = Some(flags);
+            }
+            ("bootloader-target", Value::String(s)) => {
+                config.bootloader_target = Some(PathBuf::from(s));
+            }
+            ("bootloader-version-requirement", Value::String(s)) => {
+                VersionReq::parse(&s).context(format_err!(
+                    "unexpected `package.metadata.bootimage` key \
+                     `bootloader-version-requirement` with invalid value `{}`",
+                    s
+                ))?;
+                config.bootloader_version_requirement = Some(s);
+            }
             ("minimum-image-size", Value::Integer(x)) => {
                 if x >= 0 {
                     config.minimum_image_size = Some((x * 1024 * 1024) as u64); // MiB -> Byte
@@ -95,36 +302,784 @@ pub(crate) fn read_config(manifest_path: PathBuf) -> Result<Config, Error> {
                     ))?
                 }
             }
-            ("run-command", Value::Array(array)) => {
-                let mut command = Vec::new();
-                for value in array {
-                    match value {
-                        Value::String(s) => command.push(s),
-                        _ => Err(format_err!(
-                            "run-command must be a list of strings"
-                        ))?,
-                    }
+            ("max-image-size", Value::Integer(x)) => {
+                if x < 0 {
+                    Err(format_err!(
+                        "unexpected `package.metadata.bootimage` \
+                         key `max-image-size` with negative value `{}`",
+                        x
+                    ))?
+                }
+                config.max_image_size = Some(x as u64);
+            }
+            ("max-image-size", Value::String(s)) => {
+                config.max_image_size = Some(parse_size(&s).context(
+                    "unexpected `package.metadata.bootimage` key `max-image-size`",
+                )?);
+            }
+            ("kernel-stack-size", value @ (Value::Integer(_) | Value::String(_))) => {
+                config.kernel_stack_size = Some(kernel_stack_size(value)?);
+            }
+            ("physical-memory-offset", value @ (Value::Integer(_) | Value::String(_))) => {
+                config.physical_memory_offset = Some(physical_memory_offset(value)?);
+            }
+            ("strip", Value::Boolean(b)) => config.strip = Some(b),
+            ("feature-tagged-output", Value::Boolean(b)) => config.feature_tagged_output = Some(b),
+            ("build-std", value @ (Value::Array(_) | Value::String(_))) => {
+                let crates = crate_list("build-std", value)?;
+                if crates.is_empty() {
+                    Err(format_err!("build-std must list at least one crate (e.g. `[\"core\"]`)"))?
+                }
+                config.build_std = Some(crates);
+            }
+            ("build-std-features", value @ (Value::Array(_) | Value::String(_))) => {
+                config.build_std_features = Some(crate_list("build-std-features", value)?);
+            }
+            ("pre-build-command", value @ (Value::Array(_) | Value::String(_))) => {
+                let command = string_list("pre-build-command", value)?;
+                if command.is_empty() {
+                    Err(format_err!("pre-build-command must not be empty"))?
+                }
+                config.pre_build_command = Some(command);
+            }
+            ("run-command", value @ (Value::Array(_) | Value::String(_))) => {
+                let command = string_list("run-command", value)?;
+                if command.is_empty() {
+                    Err(format_err!("run-command must not be empty"))?
                 }
                 config.run_command = Some(command);
             }
-            (key, value) => Err(format_err!(
-                "unexpected `package.metadata.bootimage` \
-                 key `{}` with value `{}`",
-                key, value
-            ))?,
+            ("qemu-cwd", Value::String(s)) => config.qemu_cwd = Some(PathBuf::from(s)),
+            ("gdb-command", value @ (Value::Array(_) | Value::String(_))) => {
+                let command = string_list("gdb-command", value)?;
+                if command.is_empty() {
+                    Err(format_err!("gdb-command must not be empty"))?
+                }
+                config.gdb_command = Some(command);
+            }
+            ("socket-connect-timeout", Value::Integer(x)) => {
+                if x <= 0 {
+                    Err(format_err!(
+                        "unexpected `package.metadata.bootimage` key `socket-connect-timeout` \
+                         with non-positive value `{}`",
+                        x
+                    ))?
+                }
+                config.socket_connect_timeout = Some(x as u64);
+            }
+            ("test-success-exit-code", Value::Integer(x)) => {
+                config.test_success_exit_code = Some(x);
+            }
+            ("test-exit-code-kind", Value::String(s)) => match s.as_str() {
+                "raw" => config.test_exit_code_shifted = Some(false),
+                "shifted" => config.test_exit_code_shifted = Some(true),
+                _ => Err(format_err!(
+                    "unexpected `package.metadata.bootimage` key `test-exit-code-kind` \
+                     with value `{}` (expected `raw` or `shifted`)",
+                    value
+                ))?,
+            },
+            ("test-exit-mechanism", Value::String(s)) => match s.as_str() {
+                "isa-debug-exit" | "semihosting" => config.test_exit_mechanism = Some(s),
+                _ => Err(format_err!(
+                    "unexpected `package.metadata.bootimage` key `test-exit-mechanism` \
+                     with value `{}` (expected `isa-debug-exit` or `semihosting`)",
+                    s
+                ))?,
+            },
+            ("test-timeout", Value::Integer(x)) => {
+                config.test_timeout = Some(test_timeout("package.metadata.bootimage", x)?);
+            }
+            ("kill-grace-period", Value::Integer(x)) => {
+                if x < 0 {
+                    Err(format_err!(
+                        "unexpected `package.metadata.bootimage` key `kill-grace-period` \
+                         with negative value `{}`",
+                        x
+                    ))?
+                }
+                config.kill_grace_period = Some(x as u64);
+            }
+            ("test-args", value @ (Value::Array(_) | Value::String(_))) => {
+                config.test_args = Some(string_list("test-args", value)?);
+            }
+            ("test-result-parser", value @ (Value::Array(_) | Value::String(_))) => {
+                config.test_result_parser = Some(command("test-result-parser", value)?);
+            }
+            ("test-command", value @ (Value::Array(_) | Value::String(_))) => {
+                config.test_command = Some(command("test-command", value)?);
+            }
+            ("test", Value::Table(tests)) => {
+                for (name, value) in tests {
+                    let table = match value {
+                        Value::Table(table) => table,
+                        value => Err(format_err!(
+                            "`package.metadata.bootimage.test.{}` must be a table, got `{}`",
+                            name, value
+                        ))?,
+                    };
+                    let test_override = parse_test_override(&name, table)?;
+                    config.test_overrides.insert(name, test_override);
+                }
+            }
+            (key, value) => unknown_key("", key, &value, KEYS)?,
         }
     }
+    if config.build_std_features.is_some() && config.build_std.is_none() {
+        Err(format_err!("build-std-features requires build-std"))?
+    }
+    check_exit_code(config.test_success_exit_code)?;
+    for test_override in config.test_overrides.values() {
+        check_exit_code(test_override.test_success_exit_code)?;
+    }
     Ok(config.into())
 }
 
+/// Reads and parses the TOML file at `path`.
+fn read_toml(path: &Path) -> Result<Value, Error> {
+    use std::{fs::File, io::Read};
+    let mut content = String::new();
+    File::open(path)
+        .context(format_err!("Failed to open {}", path.display()))?
+        .read_to_string(&mut content)
+        .context(format_err!("Failed to read {}", path.display()))?;
+    Ok(content.parse::<Value>().context(format_err!("Failed to parse {}", path.display()))?)
+}
+
+/// Returns the path of the user-global configuration file
+/// (`$XDG_CONFIG_HOME/bootimage/config.toml` or `~/.config/bootimage/config.toml`).
+fn user_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("bootimage").join("config.toml"))
+}
+
+/// Returns the `[<section>.metadata.bootimage]` table of a `Cargo.toml`, where `section`
+/// is `package` or `workspace`.
+fn metadata_table(cargo_toml: &Value, section: &str) -> Result<Option<Table>, Error> {
+    let metadata = cargo_toml
+        .get(section)
+        .and_then(|table| table.get("metadata"))
+        .and_then(|table| table.get("bootimage"));
+    match metadata {
+        None => Ok(None),
+        Some(metadata) => Ok(Some(metadata.as_table().ok_or(format_err!(
+            "Bootimage configuration invalid: {:?}",
+            metadata
+        ))?.clone())),
+    }
+}
+
+/// Returns a description of the source that supplied the effective value of the
+/// top-level key `key` (for `BOOTIMAGE_LOG=debug`).
+///
+/// For nested tables such as `bootloader`, this is the source with the highest
+/// precedence that sets any key of the table.
+fn key_source(
+    key: &str,
+    layers: &[(String, Table)],
+    feature_tables: Option<&Value>,
+    enabled: &BTreeSet<String>,
+    overrides: &[String],
+) -> String {
+    let overridden = overrides.iter().any(|config_override| {
+        let name = config_override.split('=').next().unwrap_or("").trim();
+        name.strip_prefix("bootimage.")
+            .and_then(|path| path.split('.').next())
+            == Some(key)
+    });
+    if overridden {
+        return String::from("`--config`");
+    }
+    let feature = feature_tables
+        .and_then(Value::as_table)
+        .into_iter()
+        .flat_map(|tables| tables.iter())
+        .rev()
+        .find(|&(name, table)| enabled.contains(name) && table.get(key).is_some())
+        .map(|(name, _)| name);
+    if let Some(feature) = feature {
+        return format!("the table of feature `{}`", feature);
+    }
+    layers
+        .iter()
+        .rev()
+        .find(|(_, table)| table.contains_key(key))
+        .map(|(source, _)| source.clone())
+        .unwrap_or_else(|| String::from("an unknown source"))
+}
+
+/// Returns the features of the crate that are enabled by the given selection.
+///
+/// Like cargo, the `default` feature is enabled unless `--no-default-features` is
+/// passed, and features enable the features they list in the `[features]` table.
+fn enabled_features(cargo_toml: &Value, selection: &FeatureSelection) -> BTreeSet<String> {
+    let empty = Table::new();
+    let feature_table = cargo_toml
+        .get("features")
+        .and_then(Value::as_table)
+        .unwrap_or(&empty);
+    let mut pending = selection.features.clone();
+    if !selection.no_default_features {
+        pending.push(String::from("default"));
+    }
+    if selection.all_features {
+        pending.extend(feature_table.keys().cloned());
+    }
+    let mut enabled = BTreeSet::new();
+    while let Some(feature) = pending.pop() {
+        if let Some(implied) = feature_table.get(&feature).and_then(Value::as_array) {
+            // `dep:name` and `name/feature` entries refer to dependencies
+            let implied = implied
+                .iter()
+                .filter_map(Value::as_str)
+                .filter(|f| !f.contains(':') && !f.contains('/'));
+            for implied_feature in implied {
+                if !enabled.contains(implied_feature) {
+                    pending.push(implied_feature.to_owned());
+                }
+            }
+        }
+        enabled.insert(feature);
+    }
+    enabled
+}
+
+/// Merges the `[package.metadata.bootimage.feature."name"]` tables of the enabled
+/// features into the bootimage metadata table.
+///
+/// The keys of a feature table take precedence over the keys of the metadata table.
+/// Nested tables such as `bootloader` are merged key by key. If multiple enabled features
+/// set the same key, an error is returned, since neither value is more specific than the
+/// other.
+fn apply_feature_tables(metadata: &mut Table, feature_tables: Value, enabled: &BTreeSet<String>) -> Result<(), Error> {
+    let feature_tables = match feature_tables {
+        Value::Table(table) => table,
+        value => Err(format_err!(
+            "`package.metadata.bootimage.feature` must be a table, got `{}`",
+            value
+        ))?,
+    };
+    // the enabled feature that set each key, by dotted key path
+    let mut key_features: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in feature_tables {
+        let table = match value {
+            Value::Table(table) => table,
+            value => Err(format_err!(
+                "`package.metadata.bootimage.feature.{}` must be a table, got `{}`",
+                name, value
+            ))?,
+        };
+        if table.contains_key("feature") {
+            Err(format_err!(
+                "`package.metadata.bootimage.feature.{}` must not contain a `feature` table",
+                name
+            ))?
+        }
+        if enabled.contains(&name) {
+            let mut keys = Vec::new();
+            leaf_keys(&table, "", &mut keys);
+            for key in keys {
+                if let Some(other) = key_features.insert(key.clone(), name.clone()) {
+                    Err(format_err!(
+                        "the enabled features `{}` and `{}` both set `{}`; set it in only one \
+                         of their `package.metadata.bootimage.feature` tables",
+                        other, name, key
+                    ))?
+                }
+            }
+            merge_table(metadata, table);
+        }
+    }
+    Ok(())
+}
+
+/// Appends the dotted paths of the keys of `table` that aren't tables themselves to `keys`.
+fn leaf_keys(table: &Table, prefix: &str, keys: &mut Vec<String>) {
+    for (key, value) in table {
+        let path = format!("{}{}", prefix, key);
+        match *value {
+            Value::Table(ref table) => leaf_keys(table, &format!("{}.", path), keys),
+            _ => keys.push(path),
+        }
+    }
+}
+
+/// Merges `overlay` into `base`, recursing into tables that are present in both.
+fn merge_table(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(&mut Value::Table(ref mut base_table)), Value::Table(table)) => {
+                merge_table(base_table, table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional `K`, `M`, or `G` suffix (e.g. `1440K`).
+///
+/// The suffixes are binary, i.e. `1K` is 1024 bytes.
+pub(crate) fn parse_size(size: &str) -> Result<u64, Error> {
+    let (number, factor) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1024),
+        Some('M') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('G') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format_err!("invalid size `{}` (expected e.g. `1474560` or `1440K`)", size))?;
+    number
+        .checked_mul(factor)
+        .ok_or(format_err!("size `{}` is too large", size))
+}
+
+/// The page size of the memory hints (`kernel-stack-size` and `physical-memory-offset`).
+const PAGE_SIZE: u64 = 4096;
+
+/// Parses the value of a memory hint, given either as non-negative integer or as string
+/// that is parsed with `parse`.
+fn memory_hint<F>(key: &str, value: Value, parse: F) -> Result<u64, Error>
+where
+    F: FnOnce(&str) -> Result<u64, Error>,
+{
+    match value {
+        Value::Integer(x) if x < 0 => Err(format_err!(
+            "unexpected `package.metadata.bootimage` key `{}` with negative value `{}`",
+            key, x
+        ))?,
+        Value::Integer(x) => Ok(x as u64),
+        Value::String(s) => Ok(parse(&s)
+            .context(format_err!("unexpected `package.metadata.bootimage` key `{}`", key))?),
+        _ => Err(format_err!("{} must be an integer or a string", key)),
+    }
+}
+
+/// Parses the `kernel-stack-size`, which must be a non-zero multiple of `PAGE_SIZE`.
+fn kernel_stack_size(value: Value) -> Result<u64, Error> {
+    let size = memory_hint("kernel-stack-size", value, parse_size)?;
+    if size == 0 || size % PAGE_SIZE != 0 {
+        Err(format_err!(
+            "kernel-stack-size must be a non-zero multiple of the page size ({} bytes), got `{}`",
+            PAGE_SIZE, size
+        ))?
+    }
+    Ok(size)
+}
+
+/// Parses the `physical-memory-offset`, which must be aligned to `PAGE_SIZE`.
+fn physical_memory_offset(value: Value) -> Result<u64, Error> {
+    let offset = memory_hint("physical-memory-offset", value, parse_address)?;
+    if offset % PAGE_SIZE != 0 {
+        Err(format_err!(
+            "physical-memory-offset must be aligned to the page size ({} bytes), got `{:#x}`",
+            PAGE_SIZE, offset
+        ))?
+    }
+    Ok(offset)
+}
+
+/// Parses an address given in decimal or, with a `0x` prefix, in hexadecimal (e.g.
+/// `0xffff800000000000`, which doesn't fit into a TOML integer). Underscores are ignored.
+fn parse_address(address: &str) -> Result<u64, Error> {
+    let digits = address.replace('_', "");
+    let parsed = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    parsed.map_err(|_| format_err!("invalid address `{}` (expected e.g. `0xffff800000000000`)", address))
+}
+
+/// The keys of the `[package.metadata.bootimage]` table.
+const KEYS: &[&str] = &[
+    "default-target",
+    "output",
+    "bootloader",
+    "bootloader-rustflags",
+    "bootloader-target",
+    "bootloader-version-requirement",
+    "minimum-image-size",
+    "max-image-size",
+    "kernel-stack-size",
+    "physical-memory-offset",
+    "strip",
+    "feature-tagged-output",
+    "build-std",
+    "build-std-features",
+    "pre-build-command",
+    "run-command",
+    "qemu-cwd",
+    "gdb-command",
+    "socket-connect-timeout",
+    "test-success-exit-code",
+    "test-exit-code-kind",
+    "test-exit-mechanism",
+    "test-timeout",
+    "kill-grace-period",
+    "test-args",
+    "test-result-parser",
+    "test-command",
+    "test",
+    "feature",
+];
+
+/// The keys of the `[package.metadata.bootimage.bootloader]` table.
+const BOOTLOADER_KEYS: &[&str] = &["name", "precompiled", "target", "version", "git", "branch", "path"];
+
+/// The keys of the `[package.metadata.bootimage.test."name"]` tables.
+const TEST_KEYS: &[&str] = &[
+    "test-success-exit-code",
+    "test-exit-code-kind",
+    "test-timeout",
+    "test-args",
+    "test-result-parser",
+    "test-command",
+    "should-panic",
+];
+
+/// Handles a key that didn't match any expected key and value type.
+///
+/// A known key has a value of the wrong type, which is an error. Unknown keys are only
+/// reported as warning (with a suggestion for typos), since they might be used by newer
+/// versions of bootimage. The `prefix` is the path of the table below `bootimage`.
+fn unknown_key(prefix: &str, key: &str, value: &Value, known: &[&str]) -> Result<(), Error> {
+    if known.contains(&key) {
+        Err(format_err!(
+            "unexpected `package.metadata.bootimage.{}{}` value `{}`",
+            prefix, key, value
+        ))?
+    }
+    eprintln!("warning: {}", unknown_key_warning(prefix, key, known));
+    Ok(())
+}
+
+/// Returns the warning for the unknown key `key`, with a suggestion of the closest of the
+/// `known` keys.
+fn unknown_key_warning(prefix: &str, key: &str, known: &[&str]) -> String {
+    match closest_match(key, known) {
+        Some(known_key) => format!(
+            "unknown bootimage config key '{0}{1}' (did you mean '{0}{2}'?)",
+            prefix, key, known_key
+        ),
+        None => format!("unknown bootimage config key '{}{}'", prefix, key),
+    }
+}
+
+/// Returns the candidate that is closest to `name` (a likely typo of it), if any is close
+/// enough.
+pub(crate) fn closest_match<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|&(distance, _)| distance <= 3)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Checks that the `path` of a `--config` key below `bootimage` (e.g. `["test-timeout"]`)
+/// names a known key.
+///
+/// Unlike unknown keys in the `Cargo.toml`, which are only a warning, an unknown override
+/// is an error: it comes from the command line, so it is most likely a typo.
+fn check_override_path(key: &str, path: &[&str]) -> Result<(), Error> {
+    let (name, known) = match *path {
+        [name] => (name, KEYS),
+        ["bootloader", name] => (name, BOOTLOADER_KEYS),
+        ["test", _, name] => (name, TEST_KEYS),
+        ["feature", _, ref rest @ ..] if !rest.is_empty() => return check_override_path(key, rest),
+        _ => Err(format_err!("unknown `--config` key `{}`", key))?,
+    };
+    if known.contains(&name) {
+        return Ok(());
+    }
+    match closest_match(name, known) {
+        Some(closest) => {
+            let suggestion = format!("{}{}", &key[..key.len() - name.len()], closest);
+            Err(format_err!("unknown `--config` key `{}` (did you mean `{}`?)", key, suggestion))?
+        }
+        None => Err(format_err!("unknown `--config` key `{}`", key))?,
+    }
+}
+
+/// Computes the Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Parses a `[package.metadata.bootimage.test."name"]` table.
+fn parse_test_override(name: &str, table: Table) -> Result<TestOverrideBuilder, Error> {
+    let table_name = format!("package.metadata.bootimage.test.{}", name);
+    let prefix = format!("test.{}.", name);
+    let mut test_override = TestOverrideBuilder::default();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("test-success-exit-code", Value::Integer(x)) => {
+                test_override.test_success_exit_code = Some(x);
+            }
+            ("test-exit-code-kind", Value::String(s)) => match s.as_str() {
+                "raw" => test_override.test_exit_code_shifted = Some(false),
+                "shifted" => test_override.test_exit_code_shifted = Some(true),
+                _ => Err(format_err!(
+                    "unexpected `{}` key `test-exit-code-kind` \
+                     with value `{}` (expected `raw` or `shifted`)",
+                    table_name, s
+                ))?,
+            },
+            ("test-timeout", Value::Integer(x)) => {
+                test_override.test_timeout = Some(test_timeout(&table_name, x)?);
+            }
+            ("test-args", value @ (Value::Array(_) | Value::String(_))) => {
+                test_override.test_args = Some(string_list("test-args", value)?);
+            }
+            ("test-result-parser", value @ (Value::Array(_) | Value::String(_))) => {
+                test_override.test_result_parser = Some(command("test-result-parser", value)?);
+            }
+            ("test-command", value @ (Value::Array(_) | Value::String(_))) => {
+                test_override.test_command = Some(command("test-command", value)?);
+            }
+            ("should-panic", Value::Boolean(b)) => {
+                test_override.should_panic = Some(b);
+            }
+            (key, value) => unknown_key(&prefix, key, &value, TEST_KEYS)?,
+        }
+    }
+    Ok(test_override)
+}
+
+/// Parses a command key that must contain at least the program (e.g. `test-result-parser`).
+fn command(key: &str, value: Value) -> Result<Vec<String>, Error> {
+    let command = string_list(key, value)?;
+    if command.is_empty() {
+        Err(format_err!("{} must not be empty", key))?
+    }
+    Ok(command)
+}
+
+fn test_timeout(table_name: &str, seconds: i64) -> Result<u64, Error> {
+    if seconds <= 0 {
+        Err(format_err!(
+            "unexpected `{}` key `test-timeout` with non-positive value `{}`",
+            table_name, seconds
+        ))?
+    }
+    Ok(seconds as u64)
+}
+
+/// Parses a list of arguments, given either as array or as shell-style string.
+///
+/// The array form is unambiguous, while the string form is split at whitespace like
+/// a shell would (e.g. `"-m 256M -append 'a b'"` -> `["-m", "256M", "-append", "a b"]`).
+///
+/// Empty words of the string form are dropped (see `split_args`), while the elements of
+/// the array form are taken as they are, so an empty argument can be given as `""` there.
+fn string_list(key: &str, value: Value) -> Result<Vec<String>, Error> {
+    match value {
+        Value::Array(array) => string_array(key, array),
+        Value::String(s) => Ok(split_args(&s).context(format_err!("Invalid {} `{}`", key, s))?),
+        _ => Err(format_err!("{} must be a list of strings or a string", key)),
+    }
+}
+
+/// Splits `s` into arguments with `split_shell_words`, dropping empty and whitespace-only
+/// words.
+///
+/// Such words (e.g. of a quoted `''` or `' '`) are usually left over from editing, and
+/// QEMU rejects empty arguments. An intentionally empty argument has to be given
+/// explicitly instead, e.g. as `""` after `--` on the command line.
+pub(crate) fn split_args(s: &str) -> Result<Vec<String>, Error> {
+    let mut words = split_shell_words(s)?;
+    words.retain(|word| !word.trim().is_empty());
+    Ok(words)
+}
+
+/// Splits `s` into words like a POSIX shell, without any expansions.
+///
+/// Single quotes preserve everything literally. In double quotes, a backslash only
+/// escapes `"`, `\`, `$`, and `` ` ``. Outside of quotes, a backslash escapes any
+/// character.
+pub(crate) fn split_shell_words(s: &str) -> Result<Vec<String>, Error> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => Err(format_err!("unterminated single quote"))?,
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') | Some(c @ '`') => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => Err(format_err!("unterminated double quote"))?,
+                        },
+                        Some(c) => word.push(c),
+                        None => Err(format_err!("unterminated double quote"))?,
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => Err(format_err!("trailing backslash"))?,
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Parses a list of names for cargo's `-Z build-std` flags, given either as array of
+/// strings or as comma-separated string (e.g. `"core,alloc"`), like on the command line.
+fn crate_list(key: &str, value: Value) -> Result<Vec<String>, Error> {
+    match value {
+        Value::Array(array) => string_array(key, array),
+        Value::String(s) => Ok(s
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()),
+        _ => Err(format_err!("{} must be a list of strings", key)),
+    }
+}
+
+fn string_array(key: &str, array: Vec<Value>) -> Result<Vec<String>, Error> {
+    let mut strings = Vec::new();
+    for value in array {
+        match value {
+            Value::String(s) => strings.push(s),
+            _ => Err(format_err!("{} must be a list of strings", key))?,
+        }
+    }
+    Ok(strings)
+}
+
+/// Applies a `--config bootimage.KEY=VALUE` argument to the bootimage metadata table.
+///
+/// The value is parsed as TOML value, so that numbers, booleans, and arrays can be
+/// specified. Values that are no valid TOML are treated as strings. Nested keys such
+/// as `bootimage.bootloader.name` are supported.
+fn apply_override(metadata: &mut Table, config_override: &str) -> Result<(), Error> {
+    let separator = config_override.find('=').ok_or(format_err!(
+        "`--config` argument `{}` must be of the form `bootimage.KEY=VALUE`",
+        config_override
+    ))?;
+    let key = config_override[..separator].trim();
+    let value = &config_override[separator + 1..];
+    let path = key.strip_prefix("bootimage.").ok_or(format_err!(
+        "unknown `--config` key `{}` (keys must start with `bootimage.`)",
+        key
+    ))?;
+    check_override_path(key, &path.split('.').collect::<Vec<_>>())?;
+
+    let value = format!("value = {}", value)
+        .parse::<Value>()
+        .ok()
+        .and_then(|table| table.get("value").cloned())
+        .unwrap_or_else(|| Value::String(value.to_owned()));
+
+    let mut parts: Vec<&str> = path.split('.').collect();
+    let last = parts.pop().unwrap();
+    let mut table = metadata;
+    for part in parts {
+        table = table
+            .entry(part.to_owned())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or(format_err!("`--config` key `{}`: `{}` is not a table", key, part))?;
+    }
+    table.insert(last.to_owned(), value);
+    Ok(())
+}
+
+/// Checks that a `test-success-exit-code` is a possible exit status.
+///
+/// The range of valid codes depends on the exit mechanism, which is checked when the
+/// mechanism is chosen (see `qemu::ExitMechanism::new`).
+fn check_exit_code(code: Option<i64>) -> Result<(), Error> {
+    match code {
+        Some(code) if !(0..=255).contains(&code) => Err(format_err!(
+            "`test-success-exit-code` must be between 0 and 255, got `{}`",
+            code
+        )),
+        _ => Ok(()),
+    }
+}
+
 #[derive(Default)]
 struct ConfigBuilder {
     manifest_path: Option<PathBuf>,
+    config_files: Vec<PathBuf>,
+    default_run: Option<String>,
     default_target: Option<String>,
     output: Option<PathBuf>,
     bootloader: Option<BootloaderConfigBuilder>,
+    bootloader_rustflags: Option<Vec<String>>,
+    bootloader_target: Option<PathBuf>,
+    bootloader_version_requirement: Option<String>,
     minimum_image_size: Option<u64>,
+    max_image_size: Option<u64>,
+    kernel_stack_size: Option<u64>,
+    physical_memory_offset: Option<u64>,
+    strip: Option<bool>,
+    feature_tagged_output: Option<bool>,
+    build_std: Option<Vec<String>>,
+    build_std_features: Option<Vec<String>>,
+    pre_build_command: Option<Vec<String>>,
     run_command: Option<Vec<String>>,
+    qemu_cwd: Option<PathBuf>,
+    gdb_command: Option<Vec<String>>,
+    socket_connect_timeout: Option<u64>,
+    test_success_exit_code: Option<i64>,
+    test_exit_code_shifted: Option<bool>,
+    test_exit_mechanism: Option<String>,
+    test_timeout: Option<u64>,
+    kill_grace_period: Option<u64>,
+    test_args: Option<Vec<String>>,
+    test_result_parser: Option<Vec<String>>,
+    test_command: Option<Vec<String>>,
+    test_overrides: BTreeMap<String, TestOverrideBuilder>,
+}
+
+#[derive(Default)]
+struct TestOverrideBuilder {
+    test_success_exit_code: Option<i64>,
+    test_exit_code_shifted: Option<bool>,
+    test_timeout: Option<u64>,
+    test_args: Option<Vec<String>>,
+    test_result_parser: Option<Vec<String>>,
+    test_command: Option<Vec<String>>,
+    should_panic: Option<bool>,
 }
 
 #[derive(Default)]
@@ -138,44 +1093,323 @@ struct BootloaderConfigBuilder {
     path: Option<PathBuf>,
 }
 
-impl Into<Config> for ConfigBuilder {
-    fn into(self) -> Config {
+impl From<ConfigBuilder> for Config {
+    fn from(builder: ConfigBuilder) -> Config {
         let default_bootloader_config = BootloaderConfigBuilder {
             precompiled: Some(true),
             ..Default::default()
         };
         Config {
-            manifest_path: self.manifest_path.expect("manifest path must be set"),
-            default_target: self.default_target,
-            output: self.output,
-            bootloader: self.bootloader.unwrap_or(default_bootloader_config).into(),
-            minimum_image_size: self.minimum_image_size,
-            run_command: self.run_command.unwrap_or(vec![
-                "qemu-system-x86_64".into(),
-                "-drive".into(),
-                "format=raw,file={}".into(),
-            ]),
+            manifest_path: builder.manifest_path.expect("manifest path must be set"),
+            config_files: builder.config_files,
+            default_run: builder.default_run,
+            default_target: builder.default_target,
+            output: builder.output,
+            bootloader: builder.bootloader.unwrap_or(default_bootloader_config).into(),
+            bootloader_rustflags: builder.bootloader_rustflags,
+            bootloader_target: builder.bootloader_target,
+            bootloader_version_requirement: builder.bootloader_version_requirement,
+            minimum_image_size: builder.minimum_image_size,
+            max_image_size: builder.max_image_size,
+            kernel_stack_size: builder.kernel_stack_size,
+            physical_memory_offset: builder.physical_memory_offset,
+            strip: builder.strip.unwrap_or(false),
+            feature_tagged_output: builder.feature_tagged_output.unwrap_or(false),
+            build_std: builder.build_std,
+            build_std_features: builder.build_std_features,
+            pre_build_command: builder.pre_build_command,
+            run_command: builder.run_command,
+            qemu_cwd: builder.qemu_cwd,
+            gdb_command: builder.gdb_command,
+            socket_connect_timeout: builder.socket_connect_timeout.unwrap_or(5),
+            test_success_exit_code: builder.test_success_exit_code.map(|c| c as i32),
+            test_exit_code_shifted: builder.test_exit_code_shifted.unwrap_or(false),
+            test_exit_mechanism: builder.test_exit_mechanism,
+            test_timeout: builder.test_timeout.unwrap_or(60),
+            kill_grace_period: builder.kill_grace_period.unwrap_or(300),
+            test_args: builder.test_args.unwrap_or_default(),
+            test_result_parser: builder.test_result_parser,
+            test_command: builder.test_command,
+            should_panic: false,
+            test_overrides: builder
+                .test_overrides
+                .into_iter()
+                .map(|(name, test_override)| (name, test_override.into()))
+                .collect(),
+        }
+    }
+}
+
+impl From<TestOverrideBuilder> for TestOverride {
+    fn from(builder: TestOverrideBuilder) -> TestOverride {
+        TestOverride {
+            test_success_exit_code: builder.test_success_exit_code.map(|c| c as i32),
+            test_exit_code_shifted: builder.test_exit_code_shifted,
+            test_timeout: builder.test_timeout,
+            test_args: builder.test_args,
+            test_result_parser: builder.test_result_parser,
+            test_command: builder.test_command,
+            should_panic: builder.should_panic,
         }
     }
 }
 
-impl Into<BootloaderConfig> for BootloaderConfigBuilder {
-    fn into(self) -> BootloaderConfig {
-        let precompiled = self.precompiled.unwrap_or(false);
+impl From<BootloaderConfigBuilder> for BootloaderConfig {
+    fn from(builder: BootloaderConfigBuilder) -> BootloaderConfig {
+        let precompiled = builder.precompiled.unwrap_or(false);
         let default_name = if precompiled {
             "bootloader_precompiled"
         } else {
             "bootloader"
         };
         BootloaderConfig {
-            name: self.name.unwrap_or(default_name.into()),
+            name: builder.name.unwrap_or(default_name.into()),
             precompiled,
-            target: self.target
+            target: builder.target
                 .unwrap_or(PathBuf::from("x86_64-bootloader.json")),
-            version: self.version,
-            git: self.git,
-            branch: self.branch,
-            path: self.path,
+            version: builder.version,
+            git: builder.git,
+            branch: builder.branch,
+            path: builder.path,
         }
     }
 }
+
+/// Returns the default configuration of a package whose `Cargo.toml` has no bootimage keys.
+#[cfg(test)]
+pub(crate) fn default_config(manifest_path: &Path) -> Config {
+    ConfigBuilder {
+        manifest_path: Some(manifest_path.to_owned()),
+        ..Default::default()
+    }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn apply(config_override: &str) -> Result<Table, Error> {
+        let mut metadata = Table::new();
+        apply_override(&mut metadata, config_override)?;
+        Ok(metadata)
+    }
+
+    #[test]
+    fn closest_match_suggests_typos_only() {
+        assert_eq!(closest_match("tset-timeout", KEYS), Some("test-timeout"));
+        assert_eq!(closest_match("strp", KEYS), Some("strip"));
+        assert_eq!(closest_match("completely-different", KEYS), None);
+    }
+
+    #[test]
+    fn known_override_keys_are_applied() {
+        let metadata = apply("bootimage.test-timeout=60").unwrap();
+        assert_eq!(metadata["test-timeout"], Value::Integer(60));
+        let metadata = apply("bootimage.bootloader.name=\"bootloader\"").unwrap();
+        assert_eq!(metadata["bootloader"]["name"], Value::String("bootloader".into()));
+        apply("bootimage.test.test-basic.test-timeout=5").unwrap();
+        apply("bootimage.feature.uefi.run-command=\"qemu\"").unwrap();
+        apply("bootimage.feature.uefi.bootloader.precompiled=false").unwrap();
+    }
+
+    #[test]
+    fn unknown_override_key_is_error_with_suggestion() {
+        let err = apply("bootimage.tset-timeout=60").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown `--config` key `bootimage.tset-timeout` (did you mean `bootimage.test-timeout`?)"
+        );
+        let err = apply("bootimage.bootloader.nmae=\"x\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown `--config` key `bootimage.bootloader.nmae` (did you mean `bootimage.bootloader.name`?)"
+        );
+    }
+
+    #[test]
+    fn unknown_override_key_without_close_match_is_error() {
+        let err = apply("bootimage.completely-different=1").unwrap_err();
+        assert_eq!(err.to_string(), "unknown `--config` key `bootimage.completely-different`");
+        assert!(apply("bootimage.test.test-basic=1").is_err());
+        assert!(apply("bootimage.feature.uefi=1").is_err());
+    }
+
+    #[test]
+    fn string_form_splitting_produces_no_empty_args() {
+        let value = Value::String("  -m 256M  '' ' ' -append 'a b'  ".into());
+        assert_eq!(string_list("test-args", value).unwrap(), ["-m", "256M", "-append", "a b"]);
+        assert!(string_list("test-args", Value::String("'' \"\"".into())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn array_form_keeps_empty_args() {
+        let value = Value::Array(vec![Value::String("-append".into()), Value::String("".into())]);
+        assert_eq!(string_list("test-args", value).unwrap(), ["-append", ""]);
+    }
+
+    #[test]
+    fn valid_memory_hints_are_parsed() {
+        assert_eq!(kernel_stack_size(Value::String("512K".into())).unwrap(), 512 * 1024);
+        assert_eq!(kernel_stack_size(Value::Integer(8192)).unwrap(), 8192);
+        let offset = Value::String("0xffff_8000_0000_0000".into());
+        assert_eq!(physical_memory_offset(offset).unwrap(), 0xffff_8000_0000_0000);
+        assert_eq!(physical_memory_offset(Value::Integer(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn invalid_memory_hints_are_rejected() {
+        assert_eq!(
+            kernel_stack_size(Value::String("1000".into())).unwrap_err().to_string(),
+            "kernel-stack-size must be a non-zero multiple of the page size (4096 bytes), got `1000`"
+        );
+        assert!(kernel_stack_size(Value::Integer(0)).is_err());
+        assert!(kernel_stack_size(Value::Integer(-4096)).is_err());
+        assert!(kernel_stack_size(Value::String("lots".into())).is_err());
+        assert_eq!(
+            physical_memory_offset(Value::String("0x1001".into())).unwrap_err().to_string(),
+            "physical-memory-offset must be aligned to the page size (4096 bytes), got `0x1001`"
+        );
+        assert!(physical_memory_offset(Value::String("0xfffff_0000_0000_0000".into())).is_err());
+        assert_eq!(
+            physical_memory_offset(Value::Boolean(true)).unwrap_err().to_string(),
+            "physical-memory-offset must be an integer or a string"
+        );
+    }
+
+    /// Writes the user config, a workspace, and a package in it with the given
+    /// `bootimage` tables, and reads the config of the package.
+    fn read_layers(user: &str, workspace: &str, package: &str, overrides: &[&str]) -> Config {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let user_config = dir.path().join("config.toml");
+        fs::write(&user_config, user).unwrap();
+        let workspace_root = dir.path().join("workspace");
+        fs::create_dir_all(workspace_root.join("kernel")).unwrap();
+        fs::write(
+            workspace_root.join("Cargo.toml"),
+            format!("[workspace]\nmembers = [\"kernel\"]\n[workspace.metadata.bootimage]\n{}", workspace),
+        ).unwrap();
+        let manifest_path = workspace_root.join("kernel").join("Cargo.toml");
+        fs::write(
+            &manifest_path,
+            format!("[package]\nname = \"kernel\"\nversion = \"0.1.0\"\n[package.metadata.bootimage]\n{}", package),
+        ).unwrap();
+        let overrides: Vec<_> = overrides.iter().map(|o| o.to_string()).collect();
+        let features = FeatureSelection::default();
+        read_layered_config(Some(user_config), manifest_path, &workspace_root, &features, &overrides).unwrap()
+    }
+
+    #[test]
+    fn later_config_layers_take_precedence() {
+        let config = read_layers(
+            "test-timeout = 1\nkill-grace-period = 1\nsocket-connect-timeout = 1\nstrip = true\n",
+            "test-timeout = 2\nkill-grace-period = 2\nsocket-connect-timeout = 2\n",
+            "test-timeout = 3\nkill-grace-period = 3\n",
+            &["bootimage.test-timeout=4"],
+        );
+        assert_eq!(config.test_timeout, 4);
+        assert_eq!(config.kill_grace_period, 3);
+        assert_eq!(config.socket_connect_timeout, 2);
+        assert!(config.strip);
+        assert_eq!(config.config_files.len(), 2);
+    }
+
+    #[test]
+    fn nested_tables_are_merged_across_layers() {
+        let config = read_layers(
+            "[bootloader]\nname = \"user-bootloader\"\nversion = \"0.2\"\n",
+            "",
+            "bootloader = { name = \"bootloader\", precompiled = false }\n",
+            &[],
+        );
+        assert_eq!(config.bootloader.name, "bootloader");
+        assert_eq!(config.bootloader.version, Some("0.2".into()));
+        assert!(!config.bootloader.precompiled);
+    }
+
+    fn apply_features(feature_tables: &str, enabled: &[&str]) -> Result<Table, Error> {
+        let mut metadata = Table::new();
+        let feature_tables = feature_tables.parse::<Value>().unwrap();
+        let enabled = enabled.iter().map(|feature| feature.to_string()).collect();
+        apply_feature_tables(&mut metadata, feature_tables, &enabled)?;
+        Ok(metadata)
+    }
+
+    #[test]
+    fn feature_tables_of_enabled_features_are_merged() {
+        let feature_tables = "[a]\ntest-timeout = 1\n[a.bootloader]\nname = \"a\"\n\
+                              [b]\nstrip = true\n[b.bootloader]\nprecompiled = false\n\
+                              [c]\ntest-timeout = 3\n";
+        let metadata = apply_features(feature_tables, &["a", "b"]).unwrap();
+        assert_eq!(metadata["test-timeout"], Value::Integer(1));
+        assert_eq!(metadata["strip"], Value::Boolean(true));
+        assert_eq!(metadata["bootloader"]["name"], Value::String("a".into()));
+        assert_eq!(metadata["bootloader"]["precompiled"], Value::Boolean(false));
+    }
+
+    #[test]
+    fn key_set_by_several_enabled_features_is_error() {
+        let feature_tables = "[a]\ntest-timeout = 1\n[c]\ntest-timeout = 3\n";
+        assert_eq!(
+            apply_features(feature_tables, &["a", "c"]).unwrap_err().to_string(),
+            "the enabled features `a` and `c` both set `test-timeout`; set it in only one of \
+             their `package.metadata.bootimage.feature` tables"
+        );
+        let feature_tables = "[a.bootloader]\nname = \"a\"\n[b.bootloader]\nname = \"b\"\n";
+        let err = apply_features(feature_tables, &["a", "b"]).unwrap_err().to_string();
+        assert!(err.contains("both set `bootloader.name`"), "{}", err);
+    }
+
+    #[test]
+    fn test_table_overrides_only_the_named_test() {
+        let config = read_layers(
+            "",
+            "",
+            "test-timeout = 300\ntest-args = [\"-m\", \"64M\"]\n\
+             [package.metadata.bootimage.test.test-slow]\ntest-timeout = 900\nshould-panic = true\n",
+            &[],
+        );
+        let slow = config.for_test("test-slow");
+        assert_eq!(slow.test_timeout, 900);
+        assert!(slow.should_panic);
+        assert_eq!(slow.test_args, ["-m", "64M"]);
+        let basic = config.for_test("test-basic");
+        assert_eq!(basic.test_timeout, 300);
+        assert!(!basic.should_panic);
+    }
+
+    #[test]
+    fn invalid_keys_in_test_table_are_reported() {
+        let table = |toml: &str| toml.parse::<Value>().unwrap().as_table().unwrap().clone();
+        let err = parse_test_override("test-slow", table("test-timeout = \"long\"")).map(|_| ()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unexpected `package.metadata.bootimage.test.test-slow.test-timeout` value `\"long\"`"
+        );
+        // unknown keys are a warning, since they might be supported by later versions
+        let test_override = parse_test_override("test-slow", table("tset-timeout = 900")).unwrap();
+        assert_eq!(test_override.test_timeout, None);
+        assert_eq!(
+            unknown_key_warning("test.test-slow.", "tset-timeout", TEST_KEYS),
+            "unknown bootimage config key 'test.test-slow.tset-timeout' (did you mean \
+             'test.test-slow.test-timeout'?)"
+        );
+    }
+
+    #[test]
+    fn sizes_accept_binary_suffixes() {
+        assert_eq!(parse_size("1474560").unwrap(), 1_474_560);
+        assert_eq!(parse_size("1440K").unwrap(), 1440 * 1024);
+        assert_eq!(parse_size("1440k").unwrap(), 1440 * 1024);
+        assert_eq!(parse_size("16M").unwrap(), 16 * 1024 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(
+            parse_size("1.44M").unwrap_err().to_string(),
+            "invalid size `1.44M` (expected e.g. `1474560` or `1440K`)"
+        );
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1K").is_err());
+        assert_eq!(parse_size("18446744073709551615K").unwrap_err().to_string(), "size `18446744073709551615K` is too large");
+    }
+}
@@ -0,0 +1,248 @@
+use std::path::{Path, PathBuf};
+use std::process;
+use args::Args;
+use build;
+use config::{self, Config};
+use error;
+use qemu::Arch;
+use failure::{Error, ResultExt};
+use tempdir::TempDir;
+use toolchain;
+
+/// The result of a single environment check.
+struct Check {
+    name: &'static str,
+    /// Whether a failure of this check makes `bootimage build` impossible.
+    critical: bool,
+    outcome: Result<String, Problem>,
+}
+
+/// A failed check, together with a hint on how to fix it.
+struct Problem {
+    message: String,
+    hint: String,
+}
+
+impl Problem {
+    fn new<M: Into<String>, H: Into<String>>(message: M, hint: H) -> Problem {
+        Problem {
+            message: message.into(),
+            hint: hint.into(),
+        }
+    }
+}
+
+pub(crate) fn doctor(args: Args) -> Result<(), Error> {
+    let mut critical_failure = false;
+    let mut report = |check: Check| {
+        match check.outcome {
+            Ok(message) => println!("OK    {}: {}", check.name, message),
+            Err(problem) => {
+                if check.critical {
+                    critical_failure = true;
+                }
+                println!("FAIL  {}: {}", check.name, problem.message);
+                println!("      hint: {}", problem.hint);
+            }
+        }
+    };
+
+    report(Check {
+        name: "rustc",
+        critical: true,
+        outcome: tool_version("rustc", &["--version"]).map_err(|_| {
+            Problem::new(
+                "`rustc` could not be executed",
+                "install a nightly Rust toolchain through rustup (https://rustup.rs)",
+            )
+        }),
+    });
+    report(Check {
+        name: "cargo",
+        critical: true,
+        outcome: tool_version("cargo", &["--version"]).map_err(|_| {
+            Problem::new(
+                "`cargo` could not be executed",
+                "install a nightly Rust toolchain through rustup (https://rustup.rs)",
+            )
+        }),
+    });
+    report(Check {
+        name: "cargo-xbuild",
+        critical: true,
+        outcome: check_xbuild(),
+    });
+    // not critical, since the source might be provided differently (e.g. vendored)
+    report(Check {
+        name: "rust-src",
+        critical: false,
+        outcome: check_rust_src(),
+    });
+
+    let project = read_project(&args);
+    report(Check {
+        name: "QEMU",
+        critical: false,
+        outcome: check_qemu(&args, &project),
+    });
+
+    match project {
+        Ok((config, crate_root)) => {
+            report(Check {
+                name: "target",
+                critical: true,
+                outcome: check_target(&args, &config, &crate_root),
+            });
+            report(Check {
+                name: "bootloader",
+                critical: true,
+                outcome: check_bootloader(&config, args.offline()),
+            });
+        }
+        Err(err) => report(Check {
+            name: "Cargo.toml",
+            critical: true,
+            outcome: Err(Problem::new(
+                format!("{}", err),
+                "run `bootimage doctor` inside your kernel crate or pass `--manifest-path`",
+            )),
+        }),
+    }
+
+    if critical_failure {
+        Err(error::Exit { code: 1 })?
+    }
+    Ok(())
+}
+
+fn check_rust_src() -> Result<String, Problem> {
+    match toolchain::has_rust_src() {
+        Some(true) => Ok(String::from("installed")),
+        Some(false) => Err(Problem::new(
+            "the `rust-src` component is not installed (needed for building `core`)",
+            "run `rustup component add rust-src`",
+        )),
+        None => Err(Problem::new(
+            "the sysroot of the active toolchain could not be determined",
+            "make sure that `rustc --print sysroot` works",
+        )),
+    }
+}
+
+/// Reads the bootimage configuration and returns it together with the crate root.
+fn read_project(args: &Args) -> Result<(Config, PathBuf), Error> {
+    let manifest_path = build::manifest_path(args)?;
+    let metadata = build::read_cargo_metadata(args)?;
+    let crate_root = PathBuf::from(&metadata.workspace_root);
+    let config = config::read_config(
+        manifest_path,
+        &crate_root,
+        &args.feature_selection(),
+        args.config_overrides(),
+    )?;
+    Ok((config, crate_root))
+}
+
+/// Runs the given tool and returns the first line of its output.
+fn tool_version(tool: &str, args: &[&str]) -> Result<String, Error> {
+    let output = process::Command::new(tool)
+        .args(args)
+        .output()
+        .context(format_err!("Failed to execute `{}`", tool))?;
+    if !output.status.success() {
+        Err(format_err!("`{}` exited with {}", tool, output.status))?
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().next().unwrap_or("").trim().to_owned())
+}
+
+fn check_xbuild() -> Result<String, Problem> {
+    let status = process::Command::new("cargo")
+        .arg("xbuild")
+        .arg("--help")
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status();
+    match status {
+        Ok(ref status) if status.success() => Ok(String::from("installed")),
+        _ => Err(Problem::new(
+            "`cargo xbuild` is not available",
+            "run `cargo install cargo-xbuild` to install it",
+        )),
+    }
+}
+
+fn check_qemu(args: &Args, project: &Result<(Config, PathBuf), Error>) -> Result<String, Problem> {
+    let qemu = match *project {
+        Ok((ref config, ref crate_root)) => match config.run_command {
+            Some(ref run_command) => run_command[0].clone(),
+            None => {
+                let target = args.target().clone().or_else(|| build::default_target(config, crate_root));
+                let arch = Arch::of_target(target.as_deref());
+                match arch.qemu_command() {
+                    Some(command) => command[0].clone(),
+                    None => {
+                        return Err(Problem::new(
+                            format!("there is no default QEMU for the `{}` architecture", arch.name()),
+                            "set the `run-command` key in `[package.metadata.bootimage]`",
+                        ))
+                    }
+                }
+            }
+        },
+        Err(_) => String::from("qemu-system-x86_64"),
+    };
+    tool_version(&qemu, &["--version"]).map_err(|_| {
+        Problem::new(
+            format!("`{}` not found on PATH (needed for `bootimage run/test`)", qemu),
+            "install QEMU (https://www.qemu.org/download/) and make sure it is in your PATH",
+        )
+    })
+}
+
+fn check_target(args: &Args, config: &Config, crate_root: &Path) -> Result<String, Problem> {
+    let target = match args.target().clone().or_else(|| build::default_target(config, crate_root)) {
+        Some(target) => target,
+        None => {
+            return Err(Problem::new(
+                "no target specified",
+                "pass `--target` or set `default-target` in `[package.metadata.bootimage]`",
+            ))
+        }
+    };
+    if !target.ends_with(".json") {
+        return Err(Problem::new(
+            format!("target `{}` is not a path to a target specification", target),
+            format!("pass a path to the `.json` file instead (e.g. `{}.json`)", target),
+        ));
+    }
+    if !Path::new(&target).is_file() {
+        return Err(Problem::new(
+            format!("target specification `{}` does not exist", target),
+            "check the `--target` argument or the `default-target` key",
+        ));
+    }
+    Ok(target)
+}
+
+fn check_bootloader(config: &Config, offline: bool) -> Result<String, Problem> {
+    let hint = "check the `[package.metadata.bootimage.bootloader]` table in your Cargo.toml";
+    let tmp_dir = TempDir::new("bootloader")
+        .map_err(|err| Problem::new(format!("failed to create a temporary directory: {}", err), hint))?;
+    let bootloader = build::download_bootloader(tmp_dir.path(), config, offline).map_err(|err| {
+        Problem::new(
+            format!("failed to resolve bootloader crate `{}`: {}", config.bootloader.name, err),
+            hint,
+        )
+    })?;
+    build::check_bootloader_version(&bootloader, config).map_err(|err| {
+        Problem::new(
+            format!("{}", err),
+            format!(
+                "set `version` in `[package.metadata.bootimage.bootloader]` to a version matching `{}`",
+                build::bootloader_version_req(config)
+            ),
+        )
+    })?;
+    Ok(format!("{} {}", bootloader.name, bootloader.version))
+}
@@ -0,0 +1,77 @@
+use std::env;
+use build;
+use color::{self, Color, Stream};
+use failure::Error;
+
+/// The phase of bootimage in which an error occurred.
+///
+/// The kind is attached as the outermost context of an error, so that the chain printed
+/// by `print_error` starts with a short summary, followed by the underlying causes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "invalid arguments")]
+    Args,
+    #[fail(display = "invalid bootimage configuration")]
+    Config,
+    #[fail(display = "failed to read cargo metadata")]
+    Metadata,
+    #[fail(display = "failed to build")]
+    Build,
+    #[fail(display = "failed to create the bootimage")]
+    Assembly,
+    #[fail(display = "failed to run the bootimage")]
+    Run,
+}
+
+/// A failure that was already reported, after which bootimage exits with `code`.
+///
+/// It is returned instead of calling `process::exit`, so that destructors (e.g. the one
+/// removing the bootimage of a test for `--no-keep-image`) still run before `main` exits.
+#[derive(Debug, Fail)]
+#[fail(display = "exit code {}", code)]
+pub(crate) struct Exit {
+    pub code: i32,
+}
+
+/// Returns the exit code of bootimage for `err`, and whether `err` still needs to be
+/// printed.
+///
+/// A failed kernel build was already reported by cargo.
+pub(crate) fn exit_code(err: &Error) -> (i32, bool) {
+    if let Some(exit) = err.downcast_ref::<Exit>() {
+        return (exit.code, false);
+    }
+    (1, !build::is_kernel_build_failure(err))
+}
+
+/// Prints the error and its causes to stderr.
+///
+/// The debug representation (including a backtrace if `RUST_BACKTRACE` is set) is
+/// only printed if the `BOOTIMAGE_LOG` environment variable is set to `debug`.
+pub(crate) fn print_error(err: &Error) {
+    eprintln!("{} {}", color::paint(Stream::Stderr, Color::Red, "error:"), err);
+    for cause in err.causes().skip(1) {
+        eprintln!("  caused by: {}", cause);
+    }
+    if debug_logging() {
+        eprintln!();
+        eprintln!("{:?}", err);
+    }
+}
+
+/// Returns whether the `BOOTIMAGE_LOG` environment variable is set to `debug`.
+pub(crate) fn debug_logging() -> bool {
+    env::var("BOOTIMAGE_LOG").map(|l| l == "debug").unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_code_of_reported_failure_is_not_printed() {
+        assert_eq!(exit_code(&Exit { code: 3 }.into()), (3, false));
+        assert_eq!(exit_code(&build::KernelBuildFailed.into()), (1, false));
+        assert_eq!(exit_code(&format_err!("invalid arguments")), (1, true));
+    }
+}
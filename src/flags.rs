@@ -0,0 +1,351 @@
+//! The subcommands and flags of bootimage.
+//!
+//! The argument parser consults this table to split `--flag=value` arguments and to
+//! reject flags for subcommands that don't support them. The shell completions are
+//! generated from it, so that they stay in sync with the parser.
+
+use failure::Error;
+
+/// The subcommands of bootimage, together with a short description.
+pub(crate) const SUBCOMMANDS: &[(&str, &str)] = &[
+    ("build", "Create a bootable disk image"),
+    ("run", "Build and run a disk image"),
+    ("test", "Run integration tests"),
+    ("doctor", "Diagnose the build environment"),
+    ("runner", "Run a kernel executable as cargo runner"),
+    ("completions", "Print a shell completion script"),
+];
+
+/// The subcommands that accept BUILD_OPTS.
+const ALL: &[&str] = &["build", "run", "test", "doctor"];
+const BUILDING: &[&str] = &["build", "run", "test"];
+/// The subcommands that run QEMU interactively.
+const RUNNING: &[&str] = &["run", "runner"];
+/// `ALL` and `BUILDING` together with `runner`, which doesn't build the kernel itself.
+const ALL_AND_RUNNER: &[&str] = &["build", "run", "test", "doctor", "runner"];
+const ASSEMBLING: &[&str] = &["build", "run", "test", "runner"];
+
+/// A flag that is interpreted by bootimage.
+///
+/// `FLAGS` lists all flags and is the one place that categorizes them as singular or
+/// `repeatable`. `--target` is singular, except with `--multi-target`.
+///
+/// Flags that are only passed through to cargo (e.g. `--features`) are not listed.
+pub(crate) struct Flag {
+    pub name: &'static str,
+    /// An alternative name, e.g. `-p` for `--package`.
+    pub alias: Option<&'static str>,
+    /// The kind of value the flag takes, if any.
+    pub value: Option<Value>,
+    /// The subcommands that accept the flag.
+    pub commands: &'static [&'static str],
+    pub description: &'static str,
+    /// Whether the flag may be passed several times. All other flags are singular, i.e.
+    /// passing them twice is an error.
+    pub repeatable: bool,
+}
+
+/// The value of a flag, used as hint for shell completions.
+pub(crate) enum Value {
+    /// A path to a file.
+    Path,
+    /// One of the given values.
+    OneOf(&'static [&'static str]),
+    /// Any other value (e.g. a name or number).
+    Other,
+}
+
+const fn flag(name: &'static str, commands: &'static [&'static str], description: &'static str) -> Flag {
+    Flag {
+        name,
+        alias: None,
+        value: None,
+        commands,
+        description,
+        repeatable: false,
+    }
+}
+
+const fn value_flag(
+    name: &'static str,
+    value: Value,
+    commands: &'static [&'static str],
+    description: &'static str,
+) -> Flag {
+    Flag {
+        name,
+        alias: None,
+        value: Some(value),
+        commands,
+        description,
+        repeatable: false,
+    }
+}
+
+const fn with_alias(flag: Flag, alias: &'static str) -> Flag {
+    Flag {
+        alias: Some(alias),
+        ..flag
+    }
+}
+
+const fn repeatable(flag: Flag) -> Flag {
+    Flag {
+        repeatable: true,
+        ..flag
+    }
+}
+
+pub(crate) const FLAGS: &[Flag] = &[
+    with_alias(flag("--help", ALL_AND_RUNNER, "Print help information"), "-h"),
+    flag("--version", ALL_AND_RUNNER, "Print version information"),
+    value_flag("--bin", Value::Other, &["build", "run", "doctor"], "The kernel binary"),
+    value_flag("--example", Value::Other, &["build", "run", "doctor"], "The example to use as kernel"),
+    value_flag("--target", Value::Path, ALL, "The target specification"),
+    flag("--multi-target", &["build"], "Create a bootimage for each passed target"),
+    repeatable(with_alias(value_flag("--package", Value::Other, ALL, "The package to build"), "-p")),
+    value_flag("--manifest-path", Value::Path, ALL_AND_RUNNER, "Path to the Cargo.toml"),
+    value_flag("--manifest-out", Value::Path, BUILDING, "Write a JSON list of the created bootimages"),
+    repeatable(value_flag("--cargo-args", Value::Other, BUILDING, "Additional cargo arguments, split like a shell")),
+    value_flag("--metadata-file", Value::Path, ALL_AND_RUNNER, "Read the `cargo metadata` JSON from a file"),
+    flag("--release", ALL, "Build in release mode"),
+    value_flag("--profile", Value::Other, ALL, "Build with the given cargo profile"),
+    with_alias(flag("--quiet", ASSEMBLING, "Suppress reports and run command warnings"), "-q"),
+    flag("--update-bootloader", ASSEMBLING, "Update the bootloader dependency"),
+    flag("--strip", ASSEMBLING, "Strip debug info from the kernel"),
+    value_flag(
+        "--symbols-path",
+        Value::Path,
+        &["build", "run", "runner"],
+        "Copy the unstripped kernel to PATH",
+    ),
+    flag("--strict", ASSEMBLING, "Fail on an incompatible bootloader version"),
+    flag("--reuse-build", BUILDING, "Skip cargo if the kernel is up to date"),
+    flag("--no-build", BUILDING, "Never run cargo; fail if the kernel doesn't exist"),
+    flag("--feature-tagged-output", ASSEMBLING, "Add a hash of the cargo features to the bootimage name"),
+    flag("--reproducible", ASSEMBLING, "Create a byte-identical bootimage for identical inputs"),
+    flag("--force", ASSEMBLING, "Assemble the bootimage even if it is up to date"),
+    flag("--profile-startup", ASSEMBLING, "Print how long each phase took"),
+    value_flag("--max-image-size", Value::Other, ASSEMBLING, "Fail if the bootimage is larger"),
+    value_flag(
+        "--image-format",
+        Value::OneOf(&["raw", "vmdk", "qcow2"]),
+        &["build"],
+        "The container format of the bootimage",
+    ),
+    value_flag(
+        "--partition-table",
+        Value::OneOf(&["none", "mbr", "gpt"]),
+        ASSEMBLING,
+        "The partition table of the bootimage",
+    ),
+    value_flag(
+        "--checksum",
+        Value::OneOf(&["sha256", "blake3"]),
+        &["build"],
+        "Write a checksum file next to the bootimage",
+    ),
+    value_flag("--emit-boot-map", Value::Path, &["build"], "Write the layout of the bootimage as JSON"),
+    flag("--print-target-dir", &["build"], "Print the artifact directory without building"),
+    flag("--print-kernel-path", &["build"], "Print the kernel path without building"),
+    flag("--list-tests", &["test"], "Print the names of the integration tests without running them"),
+    flag("--check-config", BUILDING, "Check the configuration without building anything"),
+    flag("--serial-socket", &["test"], "Read the serial output of tests from a Unix domain socket"),
+    flag("--changed", &["test"], "Only run tests that changed since their last successful run"),
+    flag("--nocapture", &["test"], "Print the serial output of each test live"),
+    flag("--keep-image", &["test"], "Keep the bootimage of each test (default)"),
+    flag("--no-keep-image", &["test"], "Delete the bootimage of each test after it ran"),
+    value_flag("--junit", Value::Path, &["test"], "Write a JUnit XML report"),
+    value_flag("--tail", Value::Other, &["test"], "The number of serial lines printed for a failed test"),
+    with_alias(flag("--headless", RUNNING, "Run QEMU without a display"), "--nographic"),
+    flag("--no-drive", RUNNING, "Do not attach the bootimage as boot drive"),
+    flag("--snapshot", &["run", "runner", "test"], "Discard all disk writes of QEMU"),
+    flag("--print-pid", RUNNING, "Print the process id of QEMU after starting it"),
+    flag("--monitor", RUNNING, "Expose the QEMU monitor on a TCP port"),
+    value_flag("--monitor-port", Value::Other, RUNNING, "The TCP port of the QEMU monitor"),
+    flag("--gdb", RUNNING, "Wait for gdb on startup"),
+    value_flag("--gdb-port", Value::Other, RUNNING, "The TCP port of the gdb stub"),
+    flag("--gdb-attach", RUNNING, "Launch gdb and attach it to QEMU"),
+    value_flag("--net", Value::OneOf(&["user", "none"]), RUNNING, "The network setup of QEMU"),
+    value_flag("--net-device", Value::Other, RUNNING, "The emulated network card"),
+    value_flag("--pcap", Value::Path, RUNNING, "Capture the network traffic to a pcap file"),
+    value_flag("--qemu-cwd", Value::Path, &["run", "runner", "test"], "The working directory of QEMU"),
+    repeatable(value_flag("--run-args", Value::Other, RUNNING, "Arguments for the run command, split like a shell")),
+    value_flag("--bios", Value::Path, &["run", "runner", "test"], "Boot QEMU with the given firmware (`-bios`)"),
+    repeatable(value_flag("--pflash", Value::Path, &["run", "runner", "test"], "Attach a firmware flash image")),
+    value_flag("--qemu-log", Value::Other, &["run", "runner", "test"], "Enable QEMU log categories (`-d`)"),
+    value_flag("--qemu-log-file", Value::Path, &["run", "runner", "test"], "Write the QEMU log to a file (`-D`)"),
+    value_flag("--qemu-stdout", Value::Path, RUNNING, "Route the stdout of QEMU (inherit, capture, or a file)"),
+    value_flag(
+        "--qemu-stderr",
+        Value::Path,
+        &["run", "runner", "test"],
+        "Route the stderr of QEMU (inherit, capture, or a file)",
+    ),
+    value_flag(
+        "--color",
+        Value::OneOf(&["auto", "always", "never"]),
+        ALL_AND_RUNNER,
+        "Whether to use colored output",
+    ),
+    repeatable(value_flag("--env", Value::Other, ASSEMBLING, "Set an environment variable for cargo and QEMU")),
+    repeatable(value_flag("--env-file", Value::Path, ASSEMBLING, "Read environment variables from a file")),
+    repeatable(value_flag("--config", Value::Other, ALL_AND_RUNNER, "Override a bootimage (or cargo) config key")),
+];
+
+/// Returns the flag with the given name or alias.
+pub(crate) fn find(name: &str) -> Option<&'static Flag> {
+    FLAGS
+        .iter()
+        .find(|flag| flag.name == name || flag.alias == Some(name))
+}
+
+/// Returns the flags accepted by the given subcommand.
+pub(crate) fn for_command(command: &'static str) -> impl Iterator<Item = &'static Flag> {
+    FLAGS.iter().filter(move |flag| flag.commands.contains(&command))
+}
+
+/// Resolves an abbreviation of a long flag of the given subcommand, e.g. `--rel` for
+/// `--release`.
+///
+/// Returns `None` for full flag names (including those of cargo) and for arguments that
+/// abbreviate no flag of the subcommand; these are passed through unchanged (e.g. to
+/// cargo). An abbreviation that matches several flags of the subcommand is an error.
+/// Only the flags of bootimage are considered, so `--bi` resolves to `--bin` although
+/// cargo also has `--bins`.
+pub(crate) fn resolve_abbreviation(command: &'static str, arg: &str) -> Result<Option<&'static str>, Error> {
+    if !arg.starts_with("--") || arg == "--" || find(arg).is_some() || CARGO_FLAGS.contains(&arg) {
+        return Ok(None);
+    }
+    let candidates: Vec<&str> = for_command(command)
+        .flat_map(|flag| Some(flag.name).into_iter().chain(flag.alias))
+        .filter(|name| name.starts_with(arg))
+        .collect();
+    if candidates.len() > 1 {
+        let list: Vec<String> = candidates.iter().map(|name| format!("`{}`", name)).collect();
+        Err(format_err!("`{}` is ambiguous; it could be {}", arg, list.join(", ")))?
+    }
+    Ok(candidates.first().cloned())
+}
+
+/// The flags of `cargo build` that bootimage passes through without interpreting them.
+const CARGO_FLAGS: &[&str] = &[
+    "--features",
+    "-F",
+    "--all-features",
+    "--no-default-features",
+    "--lib",
+    "--bins",
+    "--examples",
+    "--test",
+    "--tests",
+    "--bench",
+    "--benches",
+    "--all-targets",
+    "--workspace",
+    "--all",
+    "--exclude",
+    "--jobs",
+    "-j",
+    "--keep-going",
+    "--verbose",
+    "--frozen",
+    "--locked",
+    "--offline",
+    "--target-dir",
+    "--message-format",
+    "--timings",
+    "--ignore-rust-version",
+    "--future-incompat-report",
+    "--unit-graph",
+    "--build-plan",
+    "-Z",
+];
+
+/// The flags of `CARGO_FLAGS` that take a separate value (e.g. `--features foo`).
+const CARGO_VALUE_FLAGS: &[&str] = &[
+    "--features",
+    "-F",
+    "--exclude",
+    "--jobs",
+    "-j",
+    "--target-dir",
+    "--message-format",
+    "-Z",
+];
+
+/// Returns whether `arg` is a cargo flag whose value is the next argument.
+///
+/// The value is passed to cargo unchanged, even if it looks like a bootimage flag.
+pub(crate) fn is_cargo_value_flag(arg: &str) -> bool {
+    CARGO_VALUE_FLAGS.contains(&arg)
+}
+
+/// Returns the arguments in `cargo_args` that are neither bootimage nor cargo flags.
+///
+/// Such arguments were most likely meant for the run command (e.g. `-serial`), but were
+/// passed before the `--` delimiter.
+pub(crate) fn likely_runner_flags(cargo_args: &[String]) -> Vec<&str> {
+    cargo_args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| arg.starts_with('-'))
+        .filter(|arg| {
+            let name = arg.split('=').next().unwrap_or(arg);
+            // short flags with an attached value (`-j4`, `-Zbuild-std`) or repeated (`-vv`)
+            let short = if name.starts_with("--") { name } else { name.get(..2).unwrap_or(name) };
+            let verbose = name.len() > 1 && name[1..].chars().all(|c| c == 'v');
+            find(name).is_none() && !CARGO_FLAGS.contains(&short) && !verbose
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_prefix_resolves() {
+        assert_eq!(resolve_abbreviation("build", "--rel").unwrap(), Some("--release"));
+        assert_eq!(resolve_abbreviation("run", "--gdb-a").unwrap(), Some("--gdb-attach"));
+        // cargo's `--bins` is not a candidate
+        assert_eq!(resolve_abbreviation("build", "--bi").unwrap(), Some("--bin"));
+    }
+
+    #[test]
+    fn only_flags_of_the_subcommand_are_candidates() {
+        // `--tail` is only a flag of `bootimage test`
+        assert!(resolve_abbreviation("test", "--ta").is_err());
+        assert_eq!(resolve_abbreviation("test", "--tai").unwrap(), Some("--tail"));
+        assert_eq!(resolve_abbreviation("build", "--ta").unwrap(), Some("--target"));
+        assert_eq!(resolve_abbreviation("build", "--tai").unwrap(), None);
+    }
+
+    #[test]
+    fn ambiguous_prefix_is_error() {
+        let err = resolve_abbreviation("build", "--pr").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "`--pr` is ambiguous; it could be `--profile`, `--profile-startup`, \
+             `--print-target-dir`, `--print-kernel-path`"
+        );
+    }
+
+    #[test]
+    fn full_names_and_cargo_flags_are_not_expanded() {
+        assert_eq!(resolve_abbreviation("build", "--release").unwrap(), None);
+        assert_eq!(resolve_abbreviation("run", "--nographic").unwrap(), None);
+        for command in ALL_AND_RUNNER {
+            for flag in CARGO_FLAGS {
+                assert_eq!(resolve_abbreviation(command, flag).unwrap(), None, "{}", flag);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_prefix_falls_through() {
+        assert_eq!(resolve_abbreviation("build", "--frobnicate").unwrap(), None);
+        assert_eq!(resolve_abbreviation("build", "-x").unwrap(), None);
+        assert_eq!(resolve_abbreviation("build", "--").unwrap(), None);
+    }
+}
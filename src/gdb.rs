@@ -0,0 +1,131 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::{self, Child, ExitStatus};
+use std::time::Duration;
+use config::Config;
+use qemu;
+use failure::{Error, ResultExt};
+
+/// Launches QEMU through `qemu_command`, attaches gdb to its gdb stub, and returns the
+/// exit status of QEMU.
+///
+/// gdb runs in the foreground and receives the keyboard input, including Ctrl-C for
+/// interrupting the kernel. QEMU is started in its own process group without stdin, so
+/// that Ctrl-C doesn't terminate it. When gdb exits, QEMU is killed if it is still
+/// running.
+pub(crate) fn attach(
+    config: &Config,
+    kernel_path: &Path,
+    port: u16,
+    print_pid: bool,
+    mut qemu_command: process::Command,
+) -> Result<ExitStatus, Error> {
+    let gdb_command = match config.gdb_command {
+        Some(ref gdb_command) => gdb_command.clone(),
+        None => vec![default_gdb()],
+    };
+
+    qemu_command.stdin(process::Stdio::null());
+    detach_from_terminal(&mut qemu_command);
+    let mut qemu = qemu_command
+        .spawn()
+        .context(format_err!("Failed to execute run command: {:?}", qemu_command))?;
+    if print_pid {
+        qemu::print_pid(&qemu)?;
+    }
+
+    // gdb fails right away if the gdb stub is not listening yet
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    let timeout = Duration::from_secs(config.socket_connect_timeout);
+    if let Err(err) = qemu::wait_for_socket(address, timeout, &mut qemu) {
+        kill(&mut qemu)?;
+        return Err(err);
+    }
+
+    let mut command = process::Command::new(&gdb_command[0]);
+    command.args(&gdb_command[1..]);
+    command.arg("-ex").arg(format!("file {}", kernel_path.display()));
+    command.arg("-ex").arg(format!("target remote :{}", port));
+    let gdb_status = {
+        let _guard = IgnoreInterrupts::new();
+        command.status()
+    };
+    let gdb_status = match gdb_status {
+        Ok(gdb_status) => gdb_status,
+        Err(err) => {
+            kill(&mut qemu)?;
+            Err(format_err!(
+                "Failed to launch debugger `{}`: {}. Install gdb or set the `gdb-command` key \
+                 in `[package.metadata.bootimage]`.",
+                gdb_command[0],
+                err
+            ))?
+        }
+    };
+
+    match qemu.try_wait().context("Failed to query QEMU process")? {
+        Some(exit_status) => Ok(exit_status),
+        None => {
+            // the session was ended from gdb, so that's not an error of the kernel
+            kill(&mut qemu)?;
+            Ok(gdb_status)
+        }
+    }
+}
+
+/// Returns `rust-gdb` if it is installed, since it pretty-prints Rust types, and else `gdb`.
+fn default_gdb() -> String {
+    let rust_gdb = process::Command::new("rust-gdb")
+        .arg("--version")
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status();
+    match rust_gdb {
+        Ok(ref status) if status.success() => String::from("rust-gdb"),
+        _ => String::from("gdb"),
+    }
+}
+
+fn kill(qemu: &mut Child) -> Result<(), Error> {
+    // fails if QEMU already exited, which is fine
+    let _ = qemu.kill();
+    qemu.wait().context("Failed to wait for QEMU process")?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn detach_from_terminal(command: &mut process::Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn detach_from_terminal(_command: &mut process::Command) {}
+
+/// Ignores Ctrl-C in bootimage while it exists, so that it is only handled by gdb.
+struct IgnoreInterrupts {
+    #[cfg(unix)]
+    previous: libc::sighandler_t,
+}
+
+impl IgnoreInterrupts {
+    #[cfg(unix)]
+    fn new() -> IgnoreInterrupts {
+        let previous = unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+        IgnoreInterrupts { previous }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> IgnoreInterrupts {
+        IgnoreInterrupts {}
+    }
+}
+
+impl Drop for IgnoreInterrupts {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::signal(libc::SIGINT, self.previous);
+        }
+    }
+}
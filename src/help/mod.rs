@@ -4,6 +4,8 @@ const HELP: &str = include_str!("help.txt");
 const BUILD_HELP: &str = include_str!("build_help.txt");
 const RUN_HELP: &str = include_str!("run_help.txt");
 const TEST_HELP: &str = include_str!("test_help.txt");
+const DOCTOR_HELP: &str = include_str!("doctor_help.txt");
+const RUNNER_HELP: &str = include_str!("runner_help.txt");
 
 pub(crate) fn help() {
     print!("{}", HELP);
@@ -21,6 +23,14 @@ pub(crate) fn test_help() {
     print!("{}", TEST_HELP);
 }
 
+pub(crate) fn doctor_help() {
+    print!("{}", DOCTOR_HELP);
+}
+
+pub(crate) fn runner_help() {
+    print!("{}", RUNNER_HELP);
+}
+
 pub(crate) fn no_subcommand() -> ! {
     println!("Please invoke `bootimage` with a subcommand (e.g. `bootimage build`).");
     println!();
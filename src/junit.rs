@@ -0,0 +1,149 @@
+//! The JUnit XML report of `bootimage test --junit`.
+
+use std::path::Path;
+use std::time::Duration;
+use artifacts;
+use failure::Error;
+
+/// The result of a single integration test, as reported in the JUnit report.
+#[derive(Debug)]
+pub(crate) struct TestCase {
+    /// The name of the test executable (e.g. `test-basic-boot`).
+    pub name: String,
+    /// The time QEMU ran for the test (without building it).
+    pub duration: Duration,
+    /// The reason of the failure, or `None` if the test passed.
+    pub failure: Option<Failure>,
+    /// The serial output of the test (empty if it couldn't be captured).
+    pub output: String,
+}
+
+/// The failure of a `TestCase`.
+#[derive(Debug)]
+pub(crate) struct Failure {
+    /// The kind of failure: `failed`, `timeout`, `invalid-output`, or `build-failed`.
+    pub kind: &'static str,
+    /// A short description, e.g. the exit status of QEMU.
+    pub message: String,
+}
+
+/// Writes a JUnit XML report of the given test cases to `path`.
+///
+/// All tests of a crate form a single `<testsuite>` named after the crate. Each test
+/// executable is a `<testcase>` with the test name as `name` and the crate name as
+/// `classname`, so that CI dashboards show e.g. `blog_os.test-basic-boot`. Failed and
+/// timed out tests contain a `<failure>` element with the kind as `type`, a short
+/// description as `message`, and the serial output as text. The durations are in seconds.
+///
+/// The report is written atomically, so CI never reads a partially written report.
+pub(crate) fn write_report(path: &Path, suite_name: &str, test_cases: &[TestCase]) -> Result<(), Error> {
+    let failures = test_cases.iter().filter(|t| t.failure.is_some()).count();
+    let total_time: Duration = test_cases.iter().map(|t| t.duration).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        test_cases.len(),
+        failures,
+        total_time.as_secs_f64()
+    ));
+    xml.push_str(&format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"0\" time=\"{:.3}\">\n",
+        escape(suite_name),
+        test_cases.len(),
+        failures,
+        total_time.as_secs_f64()
+    ));
+    for test_case in test_cases {
+        let attributes = format!(
+            "name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&test_case.name),
+            escape(suite_name),
+            test_case.duration.as_secs_f64()
+        );
+        match test_case.failure {
+            None => xml.push_str(&format!("    <testcase {}/>\n", attributes)),
+            Some(ref failure) => {
+                xml.push_str(&format!("    <testcase {}>\n", attributes));
+                xml.push_str(&format!(
+                    "      <failure type=\"{}\" message=\"{}\">{}</failure>\n",
+                    failure.kind,
+                    escape(&failure.message),
+                    escape(&test_case.output)
+                ));
+                xml.push_str("    </testcase>\n");
+            }
+        }
+    }
+    xml.push_str("  </testsuite>\n");
+    xml.push_str("</testsuites>\n");
+
+    artifacts::write_atomically(path, xml.as_bytes(), "JUnit report")
+}
+
+/// Escapes `text` for use in XML attributes and text.
+///
+/// Control characters other than tab, newline, and carriage return are not allowed in
+/// XML 1.0 (not even escaped), so they are replaced with U+FFFD. Kernels commonly print
+/// them over the serial port, e.g. as terminal escape sequences.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn test_case(name: &str, millis: u64, failure: Option<(&'static str, &str)>, output: &str) -> TestCase {
+        TestCase {
+            name: name.into(),
+            duration: Duration::from_millis(millis),
+            failure: failure.map(|(kind, message)| Failure { kind, message: message.into() }),
+            output: output.into(),
+        }
+    }
+
+    #[test]
+    fn report_contains_failures_and_counts() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let path = dir.path().join("junit.xml");
+        let test_cases = [
+            test_case("test-basic-boot", 500, None, "ok\n"),
+            test_case("test-panic", 250, Some(("failed", "exit code 3 <expected 33>")), "panicked at 'a & b'\n"),
+            test_case("test-hang", 1250, Some(("timeout", "timed out after 1s")), "\x1b[0m"),
+        ];
+        write_report(&path, "blog_os", &test_cases).unwrap();
+        let xml = fs::read_to_string(&path).unwrap();
+
+        assert!(xml.contains("<testsuites tests=\"3\" failures=\"2\" time=\"2.000\">"), "{}", xml);
+        assert!(xml.contains(
+            "<testsuite name=\"blog_os\" tests=\"3\" failures=\"2\" errors=\"0\" skipped=\"0\" time=\"2.000\">"
+        ), "{}", xml);
+        assert!(xml.contains(
+            "<testcase name=\"test-basic-boot\" classname=\"blog_os\" time=\"0.500\"/>"
+        ), "{}", xml);
+        assert!(xml.contains(
+            "<failure type=\"failed\" message=\"exit code 3 &lt;expected 33&gt;\">\
+             panicked at &apos;a &amp; b&apos;\n</failure>"
+        ), "{}", xml);
+        assert!(xml.contains(
+            "<failure type=\"timeout\" message=\"timed out after 1s\">\u{fffd}[0m</failure>"
+        ), "{}", xml);
+        assert_eq!(xml.matches("<failure ").count(), 2);
+    }
+}
@@ -1,19 +1,49 @@
+// the `Serialize`/`Deserialize` impls of `serde_derive` 1.0 are wrapped in a `const`
+#![allow(non_local_definitions)]
+
 extern crate byteorder;
+extern crate semver;
 extern crate cargo_metadata;
 extern crate tempdir;
 extern crate toml;
 extern crate xmas_elf;
 extern crate wait_timeout;
+extern crate sha2;
+extern crate blake3;
+#[cfg(unix)]
+extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 #[macro_use]
 extern crate failure;
 
-use std::{io, process};
+use std::process;
 use args::Args;
+use error::ErrorKind;
+use failure::ResultExt;
 
 mod args;
+mod artifacts;
+mod bootmap;
+mod checksum;
+mod error;
+mod color;
 mod config;
+mod flags;
+mod completions;
 mod build;
 mod test;
+mod test_state;
+mod junit;
+mod doctor;
+mod gdb;
+mod qemu;
+mod strip;
+mod timings;
+mod toolchain;
 mod help;
 
 enum Command {
@@ -21,32 +51,67 @@ enum Command {
     Build(Args),
     Run(Args),
     Test(Args),
+    Doctor(Args),
+    Runner(Args),
     Help,
     BuildHelp,
     RunHelp,
     TestHelp,
+    DoctorHelp,
+    RunnerHelp,
+    Completions(completions::Shell),
     Version,
 }
 
 pub fn main() {
-    use std::io::Write;
-    if let Err(err) = run() {
-        writeln!(io::stderr(), "Error: {:?}", err).unwrap();
-        process::exit(1);
+    let result = run();
+    timings::report();
+    if let Err(err) = result {
+        let (code, print) = error::exit_code(&err);
+        if print {
+            error::print_error(&err);
+        }
+        process::exit(code);
     }
 }
 
 fn run() -> Result<(), failure::Error> {
-    let command = args::parse_args();
+    let command = args::parse_args().context(ErrorKind::Args)?;
     match command {
         Command::NoSubcommand => help::no_subcommand(),
         Command::Build(args) => build::build(args),
         Command::Run(args) => build::run(args),
         Command::Test(args) => test::test(args),
-        Command::Help => Ok(help::help()),
-        Command::BuildHelp => Ok(help::build_help()),
-        Command::RunHelp => Ok(help::run_help()),
-        Command::TestHelp => Ok(help::test_help()),
-        Command::Version => Ok(println!("bootimage {}", env!("CARGO_PKG_VERSION"))),
+        Command::Doctor(args) => doctor::doctor(args),
+        Command::Runner(args) => build::runner(args),
+        Command::Help => {
+            help::help();
+            Ok(())
+        }
+        Command::BuildHelp => {
+            help::build_help();
+            Ok(())
+        }
+        Command::RunHelp => {
+            help::run_help();
+            Ok(())
+        }
+        Command::TestHelp => {
+            help::test_help();
+            Ok(())
+        }
+        Command::DoctorHelp => {
+            help::doctor_help();
+            Ok(())
+        }
+        Command::RunnerHelp => {
+            help::runner_help();
+            Ok(())
+        }
+        Command::Completions(shell) => completions::completions(shell),
+        Command::Version => {
+            println!("bootimage {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
     }
 }
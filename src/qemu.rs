@@ -0,0 +1,1106 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use failure::{Error, ResultExt};
+use args::{Args, Network, OutputRouting};
+use build;
+use config::Config;
+use error;
+use serde_json;
+#[cfg(unix)]
+use wait_timeout::ChildExt;
+
+/// Marks the position of the boot drive arguments in the run command or run arguments.
+pub(crate) const DRIVE_PLACEHOLDER: &str = "{drive}";
+
+/// The architecture of the kernel, which determines the defaults for QEMU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Arch {
+    X86_64,
+    Aarch64,
+    /// An architecture without defaults, which requires a configured `run-command`.
+    Other(String),
+}
+
+impl Arch {
+    fn from_name(name: &str) -> Arch {
+        match name {
+            "x86_64" => Arch::X86_64,
+            "aarch64" => Arch::Aarch64,
+            other => Arch::Other(other.to_owned()),
+        }
+    }
+
+    /// Returns the architecture of the given `--target`.
+    ///
+    /// The architecture is read from the `arch` key of a target specification file. If the
+    /// file can't be read, it is the first component of the target name (e.g. `aarch64`
+    /// for `aarch64-kernel.json`). Without a target, x86_64 is assumed.
+    pub(crate) fn of_target(target: Option<&str>) -> Arch {
+        let target = match target {
+            Some(target) => target,
+            None => return Arch::X86_64,
+        };
+        let spec_arch = fs::read_to_string(target)
+            .ok()
+            .and_then(|spec| serde_json::from_str::<serde_json::Value>(&spec).ok())
+            .and_then(|spec| spec.get("arch").and_then(|arch| arch.as_str()).map(String::from));
+        match spec_arch {
+            Some(arch) => Arch::from_name(&arch),
+            None => Arch::from_name(build::target_name(target).split('-').next().unwrap_or("")),
+        }
+    }
+
+    /// Returns the architecture of the kernel at `kernel_path`, built for `args`.
+    ///
+    /// `bootimage runner` gets no `--target` from cargo, so the architecture is derived
+    /// from the target subdirectory of the kernel (`target/<target>/<profile>/<kernel>`).
+    pub(crate) fn of_kernel(args: &Args, kernel_path: &Path) -> Arch {
+        if args.target().is_some() || args.kernel().is_none() {
+            return Arch::of_target(args.target().as_deref());
+        }
+        let target_dir_name = kernel_path
+            .parent()
+            .and_then(Path::parent)
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned());
+        match target_dir_name {
+            // a kernel built without `--target` is in `target/<profile>`
+            Some(ref name) if name.contains('-') => Arch::of_target(Some(name)),
+            _ => Arch::X86_64,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        match *self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Other(ref name) => name,
+        }
+    }
+
+    /// Returns the QEMU binary and machine arguments for the architecture, if there is
+    /// a sensible default.
+    ///
+    /// On aarch64, QEMU has no default machine, so the generic `virt` machine with a
+    /// 64-bit CPU is used. Its default block interface is virtio, so the `-drive` of the
+    /// bootimage works like on x86_64.
+    pub(crate) fn qemu_command(&self) -> Option<Vec<String>> {
+        match *self {
+            Arch::X86_64 => Some(vec!["qemu-system-x86_64".into()]),
+            Arch::Aarch64 => Some(vec![
+                "qemu-system-aarch64".into(),
+                "-machine".into(),
+                "virt".into(),
+                "-cpu".into(),
+                "cortex-a57".into(),
+            ]),
+            Arch::Other(_) => None,
+        }
+    }
+
+    /// Returns the default `run-command` for the architecture.
+    pub(crate) fn default_run_command(&self) -> Result<Vec<String>, Error> {
+        let mut command = self.qemu_command().ok_or_else(|| {
+            format_err!(
+                "there is no default run command for the `{}` architecture; set the \
+                 `run-command` key in `[package.metadata.bootimage]`",
+                self.name()
+            )
+        })?;
+        command.push(DRIVE_PLACEHOLDER.into());
+        Ok(command)
+    }
+}
+
+/// The source of the command that `resolve_run_command` selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CommandSource {
+    /// The `test-command` of the `[package.metadata.bootimage.test."name"]` table.
+    TestTable,
+    /// The top-level `test-command`.
+    TestCommand,
+    /// The top-level `run-command`.
+    RunCommand,
+    /// The built-in QEMU command of the architecture.
+    Default,
+}
+
+impl CommandSource {
+    /// Returns whether the command is a `test-command`, which replaces QEMU and gets none
+    /// of its arguments.
+    pub(crate) fn is_test_command(self) -> bool {
+        self == CommandSource::TestTable || self == CommandSource::TestCommand
+    }
+
+    fn description(self, test: Option<&str>) -> String {
+        match self {
+            CommandSource::TestTable => format!("the table of test `{}`", test.unwrap_or("")),
+            CommandSource::TestCommand => String::from("`test-command`"),
+            CommandSource::RunCommand => String::from("`run-command`"),
+            CommandSource::Default => String::from("the default of the architecture"),
+        }
+    }
+}
+
+/// Returns the command that runs the bootimage of `bootimage run`, or of the integration
+/// test `test` for `bootimage test`, and where it comes from.
+///
+/// The first of these is used:
+///
+/// 1. the `test-command` of the `[package.metadata.bootimage.test."name"]` table (tests only)
+/// 2. the top-level `test-command` (tests only)
+/// 3. the top-level `run-command` (`run` and `runner` only)
+/// 4. the built-in QEMU command of the architecture
+///
+/// Tests don't use the `run-command`, since they add their own QEMU arguments; the
+/// built-in command of a test doesn't contain the `{drive}` placeholder for the same
+/// reason. With `BOOTIMAGE_LOG=debug`, the source of the command is printed.
+pub(crate) fn resolve_run_command(
+    config: &Config,
+    arch: &Arch,
+    test: Option<&str>,
+) -> Result<(Vec<String>, CommandSource), Error> {
+    let test_table_command = test
+        .and_then(|name| config.test_overrides.get(name))
+        .and_then(|test_override| test_override.test_command.as_ref());
+    let (command, source) = match (test, test_table_command) {
+        (Some(_), Some(command)) => (command.clone(), CommandSource::TestTable),
+        (Some(_), None) => match config.test_command {
+            Some(ref command) => (command.clone(), CommandSource::TestCommand),
+            None => {
+                let command = arch.qemu_command().ok_or_else(|| {
+                    format_err!(
+                        "`bootimage test` does not support the `{}` architecture (only x86_64 \
+                         and aarch64); set `test-command` to run the tests without QEMU",
+                        arch.name()
+                    )
+                })?;
+                (command, CommandSource::Default)
+            }
+        },
+        (None, _) => match config.run_command {
+            Some(ref command) => (command.clone(), CommandSource::RunCommand),
+            None => (arch.default_run_command()?, CommandSource::Default),
+        },
+    };
+    if error::debug_logging() {
+        match test {
+            Some(name) => eprintln!("debug: command of test `{}` from {}", name, source.description(test)),
+            None => eprintln!("debug: run command from {}", source.description(test)),
+        }
+    }
+    Ok((command, source))
+}
+
+/// How the kernel exits QEMU with an exit code, selected through `test-exit-mechanism`.
+///
+/// The `success_code` is the QEMU exit status that signals a successful test (from
+/// `test-success-exit-code`), if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExitMechanism {
+    /// The `isa-debug-exit` device of x86. Writing `value` to its I/O port makes QEMU
+    /// exit with `(value << 1) | 1`.
+    IsaDebugExit {
+        iobase: u16,
+        iosize: u16,
+        success_code: Option<i32>,
+    },
+    /// The semihosting `SYS_EXIT` call. QEMU exits with the exit code passed by the kernel.
+    Semihosting { success_code: Option<i32> },
+}
+
+impl ExitMechanism {
+    /// Returns the exit mechanism of `config`, which defaults to `isa-debug-exit` on x86_64
+    /// and `semihosting` on aarch64. Other architectures have no default.
+    ///
+    /// For `isa-debug-exit`, a `raw` `test-success-exit-code` is the value written by the
+    /// kernel, so `0x10` results in an exit status of `33`. A `shifted` code is the exit
+    /// status observed from QEMU and used as is. For `semihosting`, the code is always
+    /// the exit status.
+    pub(crate) fn new(config: &Config, arch: &Arch) -> Result<Option<ExitMechanism>, Error> {
+        let kind = match (config.test_exit_mechanism.as_deref(), arch) {
+            (Some(kind), _) => kind,
+            (None, &Arch::X86_64) => "isa-debug-exit",
+            (None, &Arch::Aarch64) => "semihosting",
+            (None, &Arch::Other(_)) => return Ok(None),
+        };
+        let code = config.test_success_exit_code;
+        let shifted = config.test_exit_code_shifted;
+        if kind == "semihosting" {
+            if shifted && code.is_some() {
+                Err(format_err!(
+                    "`test-exit-code-kind = \"shifted\"` only applies to the `isa-debug-exit` \
+                     exit mechanism"
+                ))?
+            }
+            return Ok(Some(ExitMechanism::Semihosting { success_code: code }));
+        }
+        let success_code = match code {
+            Some(code) if shifted => {
+                if code % 2 == 0 {
+                    Err(format_err!(
+                        "shifted `test-success-exit-code` must be an odd number between 1 and \
+                         255, got `{}`",
+                        code
+                    ))?
+                }
+                Some(code)
+            }
+            Some(code) => {
+                if code > 127 {
+                    Err(format_err!(
+                        "`test-success-exit-code` must be between 0 and 127, got `{}`",
+                        code
+                    ))?
+                }
+                Some((code << 1) | 1)
+            }
+            None => None,
+        };
+        Ok(Some(ExitMechanism::IsaDebugExit {
+            iobase: 0xf4,
+            iosize: 0x04,
+            success_code,
+        }))
+    }
+
+    /// Returns the QEMU arguments that enable the mechanism.
+    pub(crate) fn qemu_args(&self) -> Vec<String> {
+        match *self {
+            ExitMechanism::IsaDebugExit { iobase, iosize, .. } => vec![
+                "-device".into(),
+                format!("isa-debug-exit,iobase={:#x},iosize={:#04x}", iobase, iosize),
+            ],
+            ExitMechanism::Semihosting { .. } => vec!["-semihosting".into()],
+        }
+    }
+
+    /// Returns whether the given QEMU arguments already enable the mechanism.
+    pub(crate) fn is_enabled_in(&self, args: &[String]) -> bool {
+        match *self {
+            ExitMechanism::IsaDebugExit { .. } => args.iter().any(|arg| arg.contains("isa-debug-exit")),
+            ExitMechanism::Semihosting { .. } => args.iter().any(|arg| arg.starts_with("-semihosting")),
+        }
+    }
+
+    pub(crate) fn success_code(&self) -> Option<i32> {
+        match *self {
+            ExitMechanism::IsaDebugExit { success_code, .. } | ExitMechanism::Semihosting { success_code } => {
+                success_code
+            }
+        }
+    }
+}
+
+/// Returns the QEMU arguments that attach the bootimage as boot drive.
+pub(crate) fn drive_args(image_path: &str) -> Vec<String> {
+    vec!["-drive".into(), format!("format=raw,file={}", image_path)]
+}
+
+/// Returns warnings about arguments in the QEMU command line `argv` that conflict with
+/// the boot drive or the exit device added by bootimage.
+///
+/// `managed_drive` is whether bootimage attached the bootimage at `image_path` itself.
+/// The warnings about drives suggest `--no-drive` if `suggest_no_drive` is set.
+pub(crate) fn conflicting_args(
+    argv: &[String],
+    image_path: &str,
+    managed_drive: bool,
+    suggest_no_drive: bool,
+) -> Vec<String> {
+    let hint = if suggest_no_drive {
+        "; pass `--no-drive` if you want to supply the boot drive yourself"
+    } else {
+        ""
+    };
+    let values = |option: &str| -> Vec<&str> {
+        argv.windows(2)
+            .filter(|pair| pair[0] == option)
+            .map(|pair| pair[1].as_str())
+            .collect()
+    };
+    let mut warnings = Vec::new();
+
+    let image_drives = values("-drive")
+        .iter()
+        .filter(|drive| drive.split(',').any(|part| part == format!("file={}", image_path)))
+        .count()
+        + values("-hda").iter().filter(|&&file| file == image_path).count();
+    if image_drives > 1 {
+        warnings.push(format!("the bootimage is attached as drive {} times{}", image_drives, hint));
+    }
+    if managed_drive {
+        if let Some(file) = values("-hda").into_iter().find(|&file| file != image_path) {
+            warnings.push(format!(
+                "`-hda {}` conflicts with the boot drive attached by bootimage (both are the \
+                 first IDE disk){}",
+                file, hint
+            ));
+        }
+        if let Some(kernel) = values("-kernel").into_iter().next() {
+            warnings.push(format!(
+                "`-kernel {}` makes QEMU boot the given kernel directly instead of the \
+                 bootimage{}",
+                kernel, hint
+            ));
+        }
+    }
+
+    let exit_devices = values("-device")
+        .iter()
+        .filter(|device| device.starts_with("isa-debug-exit"))
+        .count();
+    if exit_devices > 1 {
+        warnings.push(format!(
+            "`-device isa-debug-exit` is passed {} times, but only one device can use its \
+             I/O port; remove it from the run command or arguments",
+            exit_devices
+        ));
+    }
+    warnings
+}
+
+/// Replaces each `{drive}` placeholder in `args` with the `drive` arguments.
+pub(crate) fn expand_drive_placeholder(args: &[String], drive: &[String]) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        if arg == DRIVE_PLACEHOLDER {
+            expanded.extend(drive.iter().cloned());
+        } else {
+            expanded.push(arg.clone());
+        }
+    }
+    expanded
+}
+
+/// Returns the working directory of QEMU, if any.
+///
+/// The `--qemu-cwd` argument takes precedence over the `qemu-cwd` key, which is resolved
+/// relative to the directory of the kernel's `Cargo.toml`.
+pub(crate) fn working_dir(args: &Args, config: &Config) -> Result<Option<PathBuf>, Error> {
+    if let Some(ref dir) = *args.qemu_cwd() {
+        return Ok(Some(dir.clone()));
+    }
+    match config.qemu_cwd {
+        Some(ref dir) => {
+            let manifest_dir = config.manifest_path.parent().unwrap_or_else(|| Path::new(""));
+            let dir = manifest_dir.join(dir);
+            if !dir.is_dir() {
+                Err(format_err!(
+                    "`qemu-cwd` {} is not a directory (it is resolved relative to {})",
+                    dir.display(),
+                    manifest_dir.display()
+                ))?
+            }
+            Ok(Some(dir))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the QEMU arguments that correspond to the convenience flags in `args`.
+///
+/// The `existing` arguments are the ones from the run command and the user supplied
+/// run arguments. Options that are already present there are not added a second time.
+pub(crate) fn extra_args(args: &Args, existing: &[String]) -> Vec<String> {
+    let mut extra = Vec::new();
+
+    if args.headless() {
+        // `-nographic` implies both a disabled display and the serial port on stdio
+        let has_display = contains_any(existing, &["-display", "-nographic"]);
+        let has_serial = contains_any(existing, &["-serial", "-nographic"]);
+        if has_display || has_serial {
+            eprintln!(
+                "warning: `--headless` was passed, but the run command already configures \
+                 the display or serial port; keeping the existing options"
+            );
+        }
+        if !has_display {
+            extra.push("-display".into());
+            extra.push("none".into());
+        }
+        if !has_serial {
+            extra.push("-serial".into());
+            extra.push("stdio".into());
+        }
+    }
+
+    if args.monitor() {
+        if contains_any(existing, &["-monitor"]) {
+            eprintln!(
+                "warning: `--monitor` was passed, but the run command already configures \
+                 a monitor; keeping the existing option"
+            );
+        } else {
+            // a TCP socket instead of stdio, so that the monitor never conflicts with
+            // `-serial stdio`
+            extra.push("-monitor".into());
+            extra.push(format!("tcp:127.0.0.1:{},server,nowait", args.monitor_port()));
+            println!(
+                "QEMU monitor listening on 127.0.0.1:{0} (connect with `telnet 127.0.0.1 {0}`)",
+                args.monitor_port()
+            );
+        }
+    }
+
+    if args.gdb() {
+        if contains_any(existing, &["-gdb", "-s"]) {
+            eprintln!(
+                "warning: `--gdb` was passed, but the run command already configures \
+                 a gdb stub; keeping the existing option"
+            );
+        } else {
+            extra.push("-gdb".into());
+            extra.push(format!("tcp:127.0.0.1:{}", args.gdb_port()));
+        }
+        // wait for the debugger before executing the first instruction
+        if !contains_any(existing, &["-S"]) {
+            extra.push("-S".into());
+        }
+        if !args.gdb_attach() {
+            println!(
+                "QEMU waits for gdb on 127.0.0.1:{0} (connect with `target remote :{0}`)",
+                args.gdb_port()
+            );
+        }
+    }
+
+    if args.snapshot() && !contains_any(existing, &["-snapshot"]) {
+        extra.push("-snapshot".into());
+    }
+
+    extra.extend(firmware_args(args));
+    extra.extend(log_args(args));
+
+    match args.network() {
+        Some(Network::User) => {
+            extra.push("-netdev".into());
+            extra.push("user,id=net0".into());
+            extra.push("-device".into());
+            extra.push(format!("{},netdev=net0", args.net_device()));
+            if let Some(ref pcap) = *args.pcap() {
+                // commas separate the options of `-object`, so they are escaped by doubling
+                extra.push("-object".into());
+                extra.push(format!(
+                    "filter-dump,id=pcap0,netdev=net0,file={}",
+                    pcap.to_string_lossy().replace(',', ",,")
+                ));
+            }
+        }
+        Some(Network::None) => {
+            extra.push("-net".into());
+            extra.push("none".into());
+        }
+        None => {}
+    }
+
+    extra
+}
+
+/// Returns the QEMU arguments for `--bios` and `--pflash`.
+///
+/// `--bios PATH` becomes `-bios PATH`. The first `--pflash PATH` becomes a read-only pflash
+/// drive (the firmware code, e.g. `OVMF_CODE.fd`), and a second one a writable pflash drive
+/// (the firmware variables, e.g. `OVMF_VARS.fd`).
+pub(crate) fn firmware_args(args: &Args) -> Vec<String> {
+    let mut firmware = Vec::new();
+    if let Some(ref bios) = *args.bios() {
+        firmware.push("-bios".into());
+        firmware.push(bios.display().to_string());
+    }
+    for (index, path) in args.pflash().iter().enumerate() {
+        // a comma in a `-drive` option value is escaped by doubling it
+        let file = path.display().to_string().replace(',', ",,");
+        let readonly = if index == 0 { "on" } else { "off" };
+        firmware.push("-drive".into());
+        firmware.push(format!("if=pflash,format=raw,unit={},readonly={},file={}", index, readonly, file));
+    }
+    firmware
+}
+
+/// Returns the QEMU arguments for `--qemu-log` (`-d`) and `--qemu-log-file` (`-D`).
+pub(crate) fn log_args(args: &Args) -> Vec<String> {
+    let mut log = Vec::new();
+    if let Some(ref categories) = *args.qemu_log() {
+        log.push("-d".into());
+        log.push(categories.clone());
+    }
+    if let Some(ref path) = *args.qemu_log_file() {
+        log.push("-D".into());
+        log.push(path.display().to_string());
+    }
+    log
+}
+
+/// Waits until QEMU accepts connections on the given TCP socket.
+///
+/// QEMU opens its sockets (e.g. the gdb stub) shortly after it is started, so a connection
+/// attempt right after spawning it might be refused. The connection is retried with
+/// exponential backoff (starting at 10ms, at most 500ms) until `timeout` is reached. The
+/// test connection is closed immediately. Fails early if QEMU exits in the meantime.
+pub(crate) fn wait_for_socket(address: SocketAddr, timeout: Duration, qemu: &mut Child) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut backoff = Duration::from_millis(10);
+    loop {
+        let remaining = timeout.checked_sub(start.elapsed()).unwrap_or_default();
+        let connect_timeout = remaining.clamp(Duration::from_millis(1), Duration::from_millis(500));
+        let err = match TcpStream::connect_timeout(&address, connect_timeout) {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+        if let Some(exit_status) = qemu.try_wait().context("Failed to query QEMU process")? {
+            Err(format_err!(
+                "QEMU exited with {} before {} became available",
+                exit_status, address
+            ))?
+        }
+        if start.elapsed() >= timeout {
+            Err(format_err!(
+                "QEMU did not open {} within {} seconds (last error: {}); increase the \
+                 `socket-connect-timeout` key if QEMU starts slowly",
+                address,
+                timeout.as_secs(),
+                err
+            ))?
+        }
+        thread::sleep(backoff.min(timeout.checked_sub(start.elapsed()).unwrap_or_default()));
+        backoff = (backoff * 2).min(Duration::from_millis(500));
+    }
+}
+
+/// A Unix domain socket that the serial port of QEMU connects to (`--serial-socket`).
+///
+/// bootimage listens on the socket and QEMU connects to it as client, so that the serial
+/// output of the kernel doesn't share a stream with anything else. The socket file is
+/// removed when the `SerialSocket` is dropped.
+pub(crate) struct SerialSocket {
+    path: PathBuf,
+    #[cfg(unix)]
+    listener: UnixListener,
+}
+
+impl SerialSocket {
+    /// Creates a socket for the test `name` in the temporary directory.
+    ///
+    /// Returns `None` on platforms without Unix domain sockets, where the serial output
+    /// is read from stdio or a file as usual.
+    #[cfg(unix)]
+    pub(crate) fn bind(name: &str) -> Result<Option<SerialSocket>, Error> {
+        use std::{env, process};
+
+        let path = env::temp_dir().join(format!("bootimage-{}-{}.sock", process::id(), name));
+        // a stale socket of a crashed run with the same process id prevents binding
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .context(format_err!("Failed to create serial socket {}", path.display()))?;
+        listener
+            .set_nonblocking(true)
+            .context("Failed to configure serial socket")?;
+        Ok(Some(SerialSocket { path, listener }))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn bind(_name: &str) -> Result<Option<SerialSocket>, Error> {
+        eprintln!(
+            "warning: `--serial-socket` requires Unix domain sockets, which are not \
+             supported on this platform; reading the serial output as usual"
+        );
+        Ok(None)
+    }
+
+    /// Returns the QEMU arguments that connect the serial port to the socket.
+    pub(crate) fn qemu_args(&self) -> Vec<String> {
+        // commas separate the options of `-chardev`, so they are escaped by doubling
+        let path = self.path.to_string_lossy().replace(',', ",,");
+        vec![
+            "-chardev".into(),
+            format!("socket,id=bootimage-serial,path={}", path),
+            "-serial".into(),
+            "chardev:bootimage-serial".into(),
+        ]
+    }
+
+    /// Waits until QEMU connected to the socket and returns the connection, from which
+    /// the serial output can be read.
+    ///
+    /// Fails if QEMU exits or doesn't connect within `timeout`.
+    #[cfg(unix)]
+    pub(crate) fn accept(&self, timeout: Duration, qemu: &mut Child) -> Result<Box<dyn Read + Send>, Error> {
+        let start = Instant::now();
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream
+                        .set_nonblocking(false)
+                        .context("Failed to configure serial socket")?;
+                    return Ok(Box::new(stream));
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => Err(err).context("Failed to accept the serial socket connection")?,
+            }
+            if let Some(exit_status) = qemu.try_wait().context("Failed to query QEMU process")? {
+                Err(format_err!(
+                    "QEMU exited with {} before connecting its serial port to {}",
+                    exit_status,
+                    self.path.display()
+                ))?
+            }
+            if start.elapsed() >= timeout {
+                Err(format_err!(
+                    "QEMU did not connect its serial port to {} within {} seconds; increase \
+                     the `socket-connect-timeout` key if QEMU starts slowly",
+                    self.path.display(),
+                    timeout.as_secs()
+                ))?
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn accept(&self, _timeout: Duration, _qemu: &mut Child) -> Result<Box<dyn Read + Send>, Error> {
+        Err(format_err!("`--serial-socket` is not supported on this platform"))
+    }
+}
+
+impl Drop for SerialSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the `Stdio` for an output stream of QEMU routed through `--qemu-stdout` or
+/// `--qemu-stderr`. A captured stream must be read through `Capture`.
+pub(crate) fn stdio(routing: &OutputRouting) -> Result<Stdio, Error> {
+    match *routing {
+        OutputRouting::Inherit => Ok(Stdio::inherit()),
+        OutputRouting::Capture => Ok(Stdio::piped()),
+        OutputRouting::File(ref path) => {
+            let file = File::create(path).context(format_err!("Failed to create QEMU output file {}", path.display()))?;
+            Ok(file.into())
+        }
+    }
+}
+
+/// The output of QEMU captured through `OutputRouting::Capture`.
+///
+/// Each piped stream is read on its own thread while QEMU runs. Otherwise, QEMU could
+/// block on a full pipe of one stream while bootimage waits for the other stream or
+/// for QEMU to exit.
+pub(crate) struct Capture {
+    stdout: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    stderr: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl Capture {
+    /// Starts reading the piped stdout and stderr of `qemu`, if any. Streams that were
+    /// already taken from `qemu` (e.g. for the serial output) are left alone.
+    pub(crate) fn start(qemu: &mut Child) -> Capture {
+        Capture::start_with(qemu, false)
+    }
+
+    /// Like `start`, but also passes the captured stderr through to the stderr of
+    /// bootimage while QEMU runs (for `--qemu-stderr inherit`).
+    pub(crate) fn start_teeing_stderr(qemu: &mut Child) -> Capture {
+        Capture::start_with(qemu, true)
+    }
+
+    fn start_with(qemu: &mut Child, tee_stderr: bool) -> Capture {
+        fn drain<R: Read + Send + 'static>(
+            stream: Option<R>,
+            tee: bool,
+        ) -> Option<JoinHandle<io::Result<Vec<u8>>>> {
+            stream.map(|mut stream| {
+                thread::spawn(move || {
+                    let mut output = Vec::new();
+                    if !tee {
+                        stream.read_to_end(&mut output)?;
+                        return Ok(output);
+                    }
+                    let mut buffer = [0u8; 4096];
+                    loop {
+                        match stream.read(&mut buffer) {
+                            Ok(0) => return Ok(output),
+                            Ok(n) => {
+                                io::stderr().write_all(&buffer[..n])?;
+                                output.extend_from_slice(&buffer[..n]);
+                            }
+                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                })
+            })
+        }
+        Capture {
+            stdout: drain(qemu.stdout.take(), false),
+            stderr: drain(qemu.stderr.take(), tee_stderr),
+        }
+    }
+
+    /// Waits until QEMU closed the captured streams and returns their contents as
+    /// `(stdout, stderr)`. Streams that were not captured are empty.
+    pub(crate) fn finish(self) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        fn join(reader: Option<JoinHandle<io::Result<Vec<u8>>>>) -> Result<Vec<u8>, Error> {
+            match reader {
+                Some(reader) => Ok(reader
+                    .join()
+                    .map_err(|_| format_err!("Failed to read QEMU output"))?
+                    .context("Failed to read QEMU output")?),
+                None => Ok(Vec::new()),
+            }
+        }
+        Ok((join(self.stdout)?, join(self.stderr)?))
+    }
+}
+
+/// Parts of QEMU warnings that indicate a misconfigured machine, e.g. an `isa-debug-exit`
+/// device whose `iobase` overlaps another device. Such a machine often boots, but the
+/// test never exits.
+const STDERR_WARNING_PATTERNS: &[&str] = &[
+    "failed to",
+    "can't bind",
+    "could not",
+    "cannot",
+    "initialization failed",
+    "overlap",
+];
+
+/// Returns the lines of QEMU's stderr that match one of the `STDERR_WARNING_PATTERNS`
+/// (ignoring case).
+pub(crate) fn stderr_warnings(stderr: &str) -> Vec<&str> {
+    stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| {
+            let line = line.to_lowercase();
+            STDERR_WARNING_PATTERNS.iter().any(|pattern| line.contains(pattern))
+        })
+        .collect()
+}
+
+/// Stops QEMU (or a `test-command`) after its test timed out and waits for it to exit.
+///
+/// The process first gets the chance to shut down cleanly, so that QEMU flushes its
+/// output (e.g. the serial output file). It is only killed if it still runs after the
+/// `grace_period`.
+pub(crate) fn terminate(child: &mut Child, grace_period: Duration) -> Result<(), Error> {
+    if !grace_period.is_zero() && terminate_gracefully(child, grace_period)? {
+        return Ok(());
+    }
+    // fails if the process already exited, which is fine
+    let _ = child.kill();
+    child.wait().context("Failed to wait for QEMU process")?;
+    Ok(())
+}
+
+/// Sends `SIGTERM`, on which QEMU shuts down cleanly, and returns whether the process
+/// exited within `grace_period`.
+#[cfg(unix)]
+fn terminate_gracefully(child: &mut Child, grace_period: Duration) -> Result<bool, Error> {
+    if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Ok(false);
+    }
+    let exit_status = child
+        .wait_timeout(grace_period)
+        .context("Failed to wait for QEMU process")?;
+    Ok(exit_status.is_some())
+}
+
+/// On Windows, there is no signal for a graceful shutdown, so the process is always
+/// terminated (through `TerminateProcess`) by `Child::kill`.
+#[cfg(not(unix))]
+fn terminate_gracefully(_child: &mut Child, _grace_period: Duration) -> Result<bool, Error> {
+    Ok(false)
+}
+
+/// Prints the process id of the spawned QEMU for `--print-pid`.
+///
+/// The line is flushed right away, since a script reading it might wait for it while
+/// QEMU is still running.
+pub(crate) fn print_pid(qemu: &Child) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(stdout, "qemu pid: {}", qemu.id()).context("Failed to print the QEMU process id")?;
+    stdout.flush().context("Failed to print the QEMU process id")?;
+    Ok(())
+}
+
+fn contains_any(args: &[String], options: &[&str]) -> bool {
+    args.iter().any(|arg| options.contains(&arg.as_str()))
+}
+
+/// Maps the exit status of QEMU to the exit code of `bootimage run`.
+///
+/// Without an exit mechanism, QEMU's own exit code is used. As in `bootimage test`, an
+/// exit status equal to the `test-success-exit-code` of the mechanism is treated as
+/// success and mapped to 0. With `isa-debug-exit`, an exit status of `(value << 1) | 1`
+/// was caused by the kernel writing `value` to the device, so `value` is returned instead.
+///
+/// Exit status 1 is ambiguous: it is both QEMU's own error status (e.g. for an invalid
+/// `-drive`) and the status of the kernel writing 0. It is passed on unchanged, so that
+/// a failure of QEMU is never reported as success.
+pub(crate) fn exit_code(status: ExitStatus, exit_mechanism: Option<&ExitMechanism>) -> i32 {
+    let code = match status.code() {
+        Some(code) => code,
+        // terminated by a signal
+        None => return 1,
+    };
+    match exit_mechanism {
+        Some(mechanism) if mechanism.success_code() == Some(code) => 0,
+        _ => isa_debug_exit_value(code, exit_mechanism).unwrap_or(code),
+    }
+}
+
+/// Returns the value that the kernel wrote to the `isa-debug-exit` device, if QEMU exited
+/// with the exit `code` because of that write.
+///
+/// QEMU exits with `(value << 1) | 1`, so only odd codes of at least 3 qualify. Code 1 is
+/// also QEMU's own error status (e.g. for an invalid `-drive`).
+pub(crate) fn isa_debug_exit_value(code: i32, exit_mechanism: Option<&ExitMechanism>) -> Option<i32> {
+    match exit_mechanism {
+        Some(&ExitMechanism::IsaDebugExit { .. }) if code % 2 == 1 && code >= 3 => Some(code >> 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config;
+    use tempdir::TempDir;
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[cfg(unix)]
+    fn isa_debug_exit(success_code: Option<i32>) -> ExitMechanism {
+        ExitMechanism::IsaDebugExit {
+            iobase: 0xf4,
+            iosize: 0x04,
+            success_code,
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_code_without_mechanism_is_passed_on() {
+        assert_eq!(exit_code(exit_status(0), None), 0);
+        assert_eq!(exit_code(exit_status(1), None), 1);
+        assert_eq!(exit_code(exit_status(33), None), 33);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_code_of_signal_is_failure() {
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(exit_code(ExitStatus::from_raw(9), None), 1);
+        assert_eq!(exit_code(ExitStatus::from_raw(9), Some(&isa_debug_exit(None))), 1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_code_of_isa_debug_exit_is_unshifted() {
+        let mechanism = isa_debug_exit(None);
+        assert_eq!(exit_code(exit_status(33), Some(&mechanism)), 16);
+        assert_eq!(exit_code(exit_status(3), Some(&mechanism)), 1);
+        // even statuses can't come from the device
+        assert_eq!(exit_code(exit_status(2), Some(&mechanism)), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_code_one_of_isa_debug_exit_stays_a_failure() {
+        assert_eq!(exit_code(exit_status(1), Some(&isa_debug_exit(None))), 1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_code_of_success_code_is_zero() {
+        assert_eq!(exit_code(exit_status(33), Some(&isa_debug_exit(Some(33)))), 0);
+        let semihosting = ExitMechanism::Semihosting { success_code: Some(7) };
+        assert_eq!(exit_code(exit_status(7), Some(&semihosting)), 0);
+        assert_eq!(exit_code(exit_status(5), Some(&semihosting)), 5);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn isa_debug_exit_value_is_only_read_from_device_statuses() {
+        let mechanism = isa_debug_exit(Some(33));
+        assert_eq!(isa_debug_exit_value(5, Some(&mechanism)), Some(2));
+        assert_eq!(isa_debug_exit_value(33, Some(&mechanism)), Some(16));
+        assert_eq!(isa_debug_exit_value(1, Some(&mechanism)), None);
+        assert_eq!(isa_debug_exit_value(4, Some(&mechanism)), None);
+        let semihosting = ExitMechanism::Semihosting { success_code: Some(0) };
+        assert_eq!(isa_debug_exit_value(5, Some(&semihosting)), None);
+        assert_eq!(isa_debug_exit_value(5, None), None);
+    }
+
+    #[test]
+    fn stderr_warnings_picks_up_known_failures() {
+        let stderr = "qemu-system-x86_64: -netdev user,id=n0,hostfwd=tcp::22-:22: can't bind ip=0.0.0.0 to port 22\n\
+                      qemu-system-x86_64: Failed to initialize KVM: No such file or directory\n";
+        assert_eq!(
+            stderr_warnings(stderr),
+            [
+                "qemu-system-x86_64: -netdev user,id=n0,hostfwd=tcp::22-:22: can't bind ip=0.0.0.0 to port 22",
+                "qemu-system-x86_64: Failed to initialize KVM: No such file or directory",
+            ]
+        );
+    }
+
+    #[test]
+    fn stderr_warnings_ignores_noise() {
+        let stderr = "\n  \nVNC server running on ::1:5900\n\
+                      qemu-system-x86_64: warning: TCG doesn't support requested feature: CPUID.01H:ECX.vmx\n";
+        assert!(stderr_warnings(stderr).is_empty());
+    }
+
+    #[test]
+    fn stderr_warnings_trims_lines() {
+        assert_eq!(stderr_warnings("   Could not open 'disk.img'  \r\n"), ["Could not open 'disk.img'"]);
+    }
+
+    #[cfg(unix)]
+    fn spawn_stub(script: &str) -> Child {
+        use std::process::Command;
+        let child = Command::new("sh")
+            .args(["-c", script])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        // give the shell time to install its traps before it is signalled
+        thread::sleep(Duration::from_millis(200));
+        child
+    }
+
+    #[cfg(unix)]
+    fn terminating_signal(child: &mut Child) -> Option<i32> {
+        use std::os::unix::process::ExitStatusExt;
+        child.try_wait().unwrap().and_then(|status| status.signal())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn terminate_stops_process_with_sigterm() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let marker = dir.path().join("terminated");
+        // `wait` (unlike a foreground `sleep`) is interrupted by the trapped signal
+        let mut child = spawn_stub(&format!(
+            "trap 'echo > {}; exit 0' TERM; sleep 30 & wait",
+            marker.display()
+        ));
+        let start = Instant::now();
+        terminate(&mut child, Duration::from_secs(10)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(marker.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn terminate_kills_process_ignoring_sigterm_after_grace_period() {
+        let mut child = spawn_stub("trap '' TERM; sleep 30");
+        let start = Instant::now();
+        terminate(&mut child, Duration::from_millis(500)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(terminating_signal(&mut child), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn terminate_without_grace_period_kills_immediately() {
+        let mut child = spawn_stub("trap '' TERM; sleep 30");
+        let start = Instant::now();
+        terminate(&mut child, Duration::from_secs(0)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(terminating_signal(&mut child), Some(libc::SIGKILL));
+    }
+
+    fn words(command: &[&str]) -> Vec<String> {
+        command.iter().map(|word| word.to_string()).collect()
+    }
+
+    /// Returns a configuration with all command keys set, and a `test-command` in the
+    /// table of the test `test-custom`.
+    fn config_with_commands() -> Config {
+        let mut config = config::default_config(Path::new("Cargo.toml"));
+        config.run_command = Some(words(&["run-command", "{drive}"]));
+        config.test_command = Some(words(&["test-command"]));
+        let test_override = config::TestOverride {
+            test_command: Some(words(&["table-command"])),
+            ..Default::default()
+        };
+        config.test_overrides.insert("test-custom".into(), test_override);
+        config
+    }
+
+    #[test]
+    fn test_table_command_takes_precedence() {
+        let config = config_with_commands();
+        let (command, source) = resolve_run_command(&config, &Arch::X86_64, Some("test-custom")).unwrap();
+        assert_eq!(command, ["table-command"]);
+        assert_eq!(source, CommandSource::TestTable);
+        assert!(source.is_test_command());
+    }
+
+    #[test]
+    fn test_command_is_used_by_tests_without_table_command() {
+        let config = config_with_commands();
+        let (command, source) = resolve_run_command(&config, &Arch::X86_64, Some("test-other")).unwrap();
+        assert_eq!(command, ["test-command"]);
+        assert_eq!(source, CommandSource::TestCommand);
+        assert!(source.is_test_command());
+    }
+
+    #[test]
+    fn run_command_is_used_by_run_only() {
+        let config = config_with_commands();
+        let (command, source) = resolve_run_command(&config, &Arch::X86_64, None).unwrap();
+        assert_eq!(command, ["run-command", "{drive}"]);
+        assert_eq!(source, CommandSource::RunCommand);
+        assert!(!source.is_test_command());
+
+        let mut config = config_with_commands();
+        config.test_command = None;
+        config.test_overrides.clear();
+        let (command, source) = resolve_run_command(&config, &Arch::X86_64, Some("test-other")).unwrap();
+        assert_eq!(command, ["qemu-system-x86_64"]);
+        assert_eq!(source, CommandSource::Default);
+    }
+
+    #[test]
+    fn default_command_depends_on_arch() {
+        let config = config::default_config(Path::new("Cargo.toml"));
+        let (command, source) = resolve_run_command(&config, &Arch::X86_64, None).unwrap();
+        assert_eq!(command, ["qemu-system-x86_64", "{drive}"]);
+        assert_eq!(source, CommandSource::Default);
+        let (command, source) = resolve_run_command(&config, &Arch::Aarch64, Some("test-basic")).unwrap();
+        assert_eq!(command, ["qemu-system-aarch64", "-machine", "virt", "-cpu", "cortex-a57"]);
+        assert_eq!(source, CommandSource::Default);
+    }
+
+    #[test]
+    fn other_arch_needs_a_configured_command() {
+        let arch = Arch::Other("riscv64".into());
+        let config = config::default_config(Path::new("Cargo.toml"));
+        assert!(resolve_run_command(&config, &arch, None).is_err());
+        assert!(resolve_run_command(&config, &arch, Some("test-basic")).is_err());
+
+        let config = config_with_commands();
+        assert_eq!(resolve_run_command(&config, &arch, None).unwrap().1, CommandSource::RunCommand);
+        let (_, source) = resolve_run_command(&config, &arch, Some("test-basic")).unwrap();
+        assert_eq!(source, CommandSource::TestCommand);
+    }
+}
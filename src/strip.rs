@@ -0,0 +1,72 @@
+use std::{env, fs, process};
+use std::path::{Path, PathBuf};
+use failure::{Error, ResultExt};
+use toolchain;
+
+/// Creates a copy of the kernel without debug info and returns its path.
+///
+/// The original kernel ELF file is kept, so that it can still be used as symbol file for gdb.
+pub(crate) fn strip_kernel(kernel_path: &Path, verbose: bool) -> Result<PathBuf, Error> {
+    let strip_tool = find_strip_tool()?;
+
+    let mut stripped_path = kernel_path.as_os_str().to_owned();
+    stripped_path.push("-stripped");
+    let stripped_path = PathBuf::from(stripped_path);
+
+    let mut command = process::Command::new(&strip_tool);
+    command.arg("--strip-debug");
+    command.arg("-o").arg(&stripped_path);
+    command.arg(kernel_path);
+    let exit_status = command
+        .status()
+        .context(format_err!("Failed to execute strip command: {:?}", command))?;
+    if !exit_status.success() {
+        Err(format_err!("Failed to strip kernel: {:?} exited with {}", command, exit_status))?
+    }
+
+    if verbose {
+        let original_size = fs::metadata(kernel_path)
+            .context("Failed to read kernel output file")?
+            .len();
+        let stripped_size = fs::metadata(&stripped_path)
+            .context("Failed to read stripped kernel file")?
+            .len();
+        println!("Stripped kernel from {} to {} bytes", original_size, stripped_size);
+    }
+
+    Ok(stripped_path)
+}
+
+/// Searches for `llvm-strip`, first in the sysroot of the active toolchain (installed
+/// by the `llvm-tools-preview` component) and then in the `PATH`.
+fn find_strip_tool() -> Result<PathBuf, Error> {
+    let tool_name = format!("llvm-strip{}", env::consts::EXE_SUFFIX);
+
+    if let Some(mut rustlib) = toolchain::sysroot() {
+        rustlib.push("lib");
+        rustlib.push("rustlib");
+        if let Ok(entries) = fs::read_dir(&rustlib) {
+            for entry in entries.filter_map(Result::ok) {
+                let mut tool_path = entry.path();
+                tool_path.push("bin");
+                tool_path.push(&tool_name);
+                if tool_path.is_file() {
+                    return Ok(tool_path);
+                }
+            }
+        }
+    }
+
+    let in_path = process::Command::new(&tool_name)
+        .arg("--version")
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status();
+    match in_path {
+        Ok(ref status) if status.success() => Ok(PathBuf::from(tool_name)),
+        _ => Err(format_err!(
+            "`--strip` requires `llvm-strip`, but it was not found. Run \
+             `rustup component add llvm-tools-preview` to install it."
+        )),
+    }
+}
@@ -1,109 +1,838 @@
-use std::{fs, io, process};
+use std::{fs, io, process, thread};
+use std::io::{BufRead, BufReader, Read};
 use failure::{Error, ResultExt};
-use args::Args;
+use args::{Args, OutputRouting};
+use artifacts::{self, Artifact};
 use build;
-use wait_timeout::ChildExt;
-use std::time::Duration;
+use config::Config;
+use color::{self, Color, Stream};
+use qemu::{self, ExitMechanism};
+use test_state::TestState;
+use timings;
+use error::{self, ErrorKind};
+use junit::{self, Failure, TestCase};
+use cargo_metadata::{Metadata as CargoMetadata, Target};
+use serde_json;
+use wait_timeout::{self, ChildExt};
+use std::time::{Duration, Instant, SystemTime};
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
 pub(crate) fn test(args: Args) -> Result<(), Error> {
     let (args, config, metadata, root_dir, out_dir) = build::common_setup(args)?;
 
+    if args.list_tests() {
+        return list_tests(&args, &metadata);
+    }
+    if args.check_config() {
+        return check_config(&args, &config, &metadata, &out_dir);
+    }
+
     let test_args = args.clone();
-    let test_run_command = vec![
-        "qemu-system-x86_64".into(),
-        "-drive".into(),
-        "format=raw,file={}".into(),
-        "-device".into(),
-        "isa-debug-exit,iobase=0xf4,iosize=0x04".into(),
-        "-display".into(),
-        "none".into(),
-        "-serial".into(),
-        "file:{}-output.txt".into(),
-    ];
     let test_config = {
         let mut test_config = config.clone();
         test_config.output = None;
-        test_config.run_command = test_run_command;
         test_config
     };
+    let arch = qemu::Arch::of_target(args.target().as_deref());
 
     let mut tests = Vec::new();
+    let mut test_cases = Vec::new();
+    let mut artifacts = Vec::new();
+    let mut test_state = if args.changed() {
+        Some(TestState::load(&out_dir)?)
+    } else {
+        None
+    };
+    let mut unchanged = 0;
 
-    assert_eq!(metadata.packages.len(), 1, "Only crates with one package are supported");
-    let target_iter = metadata.packages[0].targets.iter();
-    for target in target_iter.filter(|t| t.kind == ["bin"] && t.name.starts_with("test-")) {
+    let (package_name, test_targets) = test_targets(&metadata)?;
+    // the output of a single test is streamed, since it can't interleave with other output
+    let stream_output = args.nocapture() || test_targets.len() == 1;
+    let ok = color::paint(Stream::Stdout, Color::Green, "Ok");
+    let failed = color::paint(Stream::Stderr, Color::Red, "Failed");
+
+    for target in test_targets {
         println!("{}", target.name);
 
+        // the `[package.metadata.bootimage.test."name"]` table overrides the global config
+        let target_config = config.for_test(&target.name);
         let mut target_args = test_args.clone();
-        target_args.set_bin_name(target.name.clone());
-        let test_path = build::build_impl(&target_args, &test_config, &metadata, &root_dir, &out_dir, false)?;
+        target_args.set_bin_name(target.name.clone()).context(ErrorKind::Args)?;
+        let fingerprint = target_args.fingerprint();
+        if let Some(ref test_state) = test_state {
+            let kernel_path = out_dir.join(&target.name);
+            if test_state.is_unchanged(&target.name, &kernel_path, &config.manifest_path, &fingerprint) {
+                println!("Unchanged");
+                println!();
+                unchanged += 1;
+                continue;
+            }
+        }
+        // sources modified during the build are changes for the next `--changed` run
+        let build_started = SystemTime::now();
+        let bootimage = match build::build_impl(&target_args, &test_config, &metadata, &root_dir, &out_dir, false) {
+            Ok(bootimage) => bootimage,
+            Err(ref err) if args.keep_going() && build::is_kernel_build_failure(err) => {
+                writeln!(io::stderr(), "{}: failed to build", failed)?;
+                println!();
+                test_cases.push(TestCase {
+                    name: target.name.clone(),
+                    duration: Duration::from_secs(0),
+                    failure: Some(Failure {
+                        kind: "build-failed",
+                        message: String::from("the test failed to build"),
+                    }),
+                    output: String::new(),
+                });
+                tests.push((target.name.clone(), TestResult::BuildFailed));
+                if let Some(ref mut test_state) = test_state {
+                    test_state.record_failure(&target.name);
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        // removes the image when the test is finished, including on errors
+        let _image_guard = if args.keep_image() {
+            artifacts.push(Artifact::new(&target_args, &bootimage)?);
+            None
+        } else {
+            Some(RemoveOnDrop(bootimage.path.clone()))
+        };
+        let test_path = bootimage.path;
 
-        let test_result;
         let output_file = format!("{}-output.txt", test_path.display());
 
-        let mut command = process::Command::new("qemu-system-x86_64");
-        command.arg("-drive");
-        command.arg(format!("format=raw,file={}", test_path.display()));
-        command.arg("-device");
-        command.arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
-        command.arg("-display");
-        command.arg("none");
-        command.arg("-serial");
-        command.arg(format!("file:{}", output_file));
-        command.stderr(process::Stdio::null());
-        let mut child = command.spawn()
-            .context(format_err!("Failed to launch QEMU: {:?}", command))?;
-        let timeout = Duration::from_secs(60);
-        match child.wait_timeout(timeout).context("Failed to wait with timeout")? {
-            None => {
-                child.kill().context("Failed to kill QEMU")?;
-                child.wait().context("Failed to wait for QEMU process")?;
-                test_result = TestResult::TimedOut;
-                writeln!(io::stderr(), "Timed Out")?;
+        let image_path = test_path.display().to_string();
+        let user_args: Vec<String> = target_config
+            .test_args
+            .iter()
+            .map(|arg| arg.replace("{}", &image_path))
+            .collect();
+        // the defaults of `bootimage test` are only added if `test-args` doesn't
+        // configure the respective option itself
+        let has = |options: &[&str]| user_args.iter().any(|arg| options.contains(&arg.as_str()));
+        let capture_serial = !has(&["-serial", "-nographic"]);
+
+        // the exit code of a passed test, if the result is decided by the exit code
+        let success_code;
+        let mut exit_mechanism = None;
+        let mut command;
+        let mut serial_socket = None;
+        let (run_command, source) = qemu::resolve_run_command(&target_config, &arch, Some(&target.name))?;
+        if source.is_test_command() {
+            // a custom command (e.g. flashing real hardware and reading its serial port)
+            // gets no QEMU arguments; its stdout is the serial output of the test
+            success_code = target_config.test_success_exit_code;
+            command = process::Command::new(&run_command[0]);
+            command.args(run_command[1..].iter().map(|arg| arg.replace("{}", &image_path)));
+            command.stdout(process::Stdio::piped());
+        } else {
+            exit_mechanism = ExitMechanism::new(&target_config, &arch)?;
+            success_code = exit_mechanism.as_ref().and_then(ExitMechanism::success_code);
+            command = process::Command::new(&run_command[0]);
+            command.args(&run_command[1..]);
+            command.args(qemu::drive_args(&image_path));
+            if let Some(ref exit_mechanism) = exit_mechanism {
+                if !exit_mechanism.is_enabled_in(&user_args) {
+                    command.args(exit_mechanism.qemu_args());
+                }
             }
-            Some(_) => {
-                let output = fs::read_to_string(&output_file)
-                    .context(format_err!("Failed to read test output file {}", output_file))?;
-                if output.starts_with("ok\n") {
-                    test_result = TestResult::Ok;
-                    println!("Ok");
-                } else if output.starts_with("failed\n") {
-                    test_result = TestResult::Failed;
-                    writeln!(io::stderr(), "Failed:")?;
-                    for line in output[7..].lines() {
-                        writeln!(io::stderr(), "    {}", line)?;
-                    }
+            if !has(&["-display", "-nographic"]) {
+                command.arg("-display");
+                command.arg("none");
+            }
+            if capture_serial && args.serial_socket() {
+                serial_socket = qemu::SerialSocket::bind(&target.name)?;
+            }
+            if let Some(ref socket) = serial_socket {
+                command.args(socket.qemu_args());
+            } else if capture_serial {
+                command.arg("-serial");
+                if stream_output {
+                    command.arg("stdio");
+                    command.stdout(process::Stdio::piped());
                 } else {
-                    test_result = TestResult::Invalid;
-                    writeln!(io::stderr(), "Failed: Invalid Output:")?;
-                    for line in output.lines() {
+                    command.arg(format!("file:{}", output_file));
+                }
+            } else if target_config.test_success_exit_code.is_none() {
+                eprintln!(
+                    "warning: `test-args` configures the serial port, so the test output can't be \
+                     read; set `test-success-exit-code` to decide the test result by the exit code"
+                );
+            }
+            if args.snapshot() {
+                command.arg("-snapshot");
+            }
+            command.args(qemu::firmware_args(&args));
+            command.args(qemu::log_args(&args));
+            command.args(&user_args);
+            let argv: Vec<String> = command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+            for warning in qemu::conflicting_args(&argv, &image_path, true, false) {
+                eprintln!("warning: {}", warning);
+            }
+        }
+        command.envs(args.env_vars());
+        if let Some(dir) = qemu::working_dir(&args, &target_config)? {
+            command.current_dir(dir);
+        }
+        // stderr is always read (and passed through for `inherit`), so that warnings of
+        // QEMU can be shown as likely cause of a timeout
+        let tee_stderr = *args.qemu_stderr() == Some(OutputRouting::Inherit);
+        match *args.qemu_stderr() {
+            Some(ref routing) if !tee_stderr => command.stderr(qemu::stdio(routing)?),
+            _ => command.stderr(process::Stdio::piped()),
+        };
+        let start = Instant::now();
+        let mut child = timings::time("qemu startup", || command.spawn())
+            .context(format_err!("Failed to launch the test: {:?}", command))
+            .context(ErrorKind::Run)?;
+        let serial_reader = match serial_socket {
+            Some(ref socket) => {
+                let timeout = Duration::from_secs(target_config.socket_connect_timeout);
+                let serial = match socket.accept(timeout, &mut child) {
+                    Ok(serial) => serial,
+                    Err(err) => {
+                        let _ = child.kill();
+                        child.wait().context("Failed to wait for QEMU process")?;
+                        return Err(err.context(ErrorKind::Run).into());
+                    }
+                };
+                Some(thread::spawn(move || stream_serial(serial, stream_output)))
+            }
+            None => child
+                .stdout
+                .take()
+                .map(|stdout| thread::spawn(move || stream_serial(stdout, stream_output))),
+        };
+        let capture = if tee_stderr {
+            qemu::Capture::start_teeing_stderr(&mut child)
+        } else {
+            qemu::Capture::start(&mut child)
+        };
+
+        let timeout = Duration::from_secs(target_config.test_timeout);
+        let exit_status = match child.wait_timeout(timeout).context("Failed to wait with timeout")? {
+            None => {
+                qemu::terminate(&mut child, Duration::from_millis(target_config.kill_grace_period))?;
+                None
+            }
+            Some(exit_status) => Some(exit_status),
+        };
+        let duration = start.elapsed();
+        let (_, captured_stderr) = capture.finish()?;
+        let streamed_output = match serial_reader {
+            Some(reader) => Some(
+                reader
+                    .join()
+                    .map_err(|_| format_err!("Failed to read serial output"))?
+                    .context("Failed to read serial output")?,
+            ),
+            None => None,
+        };
+
+        let output = match streamed_output {
+            Some(output) => output,
+            None if !capture_serial => String::new(),
+            // a test that timed out might not have written its output file yet
+            None if exit_status.is_none() => fs::read_to_string(&output_file).unwrap_or_default(),
+            None => fs::read_to_string(&output_file)
+                .context(format_err!("Failed to read test output file {}", output_file))?,
+        };
+        // QEMU writes the output file itself if the output isn't streamed
+        if (source.is_test_command() || serial_socket.is_some()) && !stream_output {
+            fs::write(&output_file, &output)
+                .context(format_err!("Failed to write test output file {}", output_file))?;
+        }
+        // streamed output was already printed
+        let print_output = |output: &str| -> io::Result<()> {
+            if !stream_output {
+                print_tail(&mut io::stderr(), output, args.tail(), &output_file)?;
+            }
+            Ok(())
+        };
+        let qemu_stderr = match *args.qemu_stderr() {
+            Some(OutputRouting::File(ref path)) => fs::read_to_string(path).unwrap_or_default(),
+            _ => String::from_utf8_lossy(&captured_stderr).into_owned(),
+        };
+        let stderr_warnings = qemu::stderr_warnings(&qemu_stderr);
+        let parser_status = match (exit_status, &target_config.test_result_parser) {
+            (Some(_), Some(parser)) => Some(run_result_parser(parser, &output)?),
+            _ => None,
+        };
+        let Outcome {
+            result: test_result,
+            failure_message,
+            header,
+            details,
+        } = outcome(
+            &target_config,
+            exit_status.map(Status::from),
+            parser_status.map(Status::from),
+            success_code,
+            exit_mechanism.as_ref(),
+            &output,
+            &stderr_warnings,
+        );
+        match test_result {
+            TestResult::Ok => println!("{}", ok),
+            TestResult::TimedOut => {
+                writeln!(io::stderr(), "Timed Out")?;
+                print_output(details)?;
+                if !stderr_warnings.is_empty() {
+                    writeln!(io::stderr(), "    Likely cause (QEMU stderr):")?;
+                    for line in &stderr_warnings {
                         writeln!(io::stderr(), "    {}", line)?;
                     }
                 }
-            },
+            }
+            TestResult::Failed | TestResult::Invalid | TestResult::BuildFailed => {
+                writeln!(io::stderr(), "{}{}", failed, header)?;
+                print_output(details)?;
+            }
+        }
+        let captured = *args.qemu_stderr() == Some(OutputRouting::Capture);
+        if test_result != TestResult::Ok && captured && !captured_stderr.is_empty() {
+            writeln!(io::stderr(), "    QEMU stderr:")?;
+            for line in String::from_utf8_lossy(&captured_stderr).lines() {
+                writeln!(io::stderr(), "    {}", line)?;
+            }
         }
-        println!("");
+        println!();
 
+        let failure = match test_result {
+            TestResult::Ok => None,
+            TestResult::Failed => Some("failed"),
+            TestResult::TimedOut => Some("timeout"),
+            TestResult::Invalid => Some("invalid-output"),
+            TestResult::BuildFailed => Some("build-failed"),
+        };
+        test_cases.push(TestCase {
+            name: target.name.clone(),
+            duration,
+            failure: failure.map(|kind| Failure {
+                kind,
+                message: failure_message,
+            }),
+            output,
+        });
+        if let Some(ref mut test_state) = test_state {
+            if test_result == TestResult::Ok {
+                test_state.record_pass(&target.name, build_started, &fingerprint);
+            } else {
+                test_state.record_failure(&target.name);
+            }
+        }
         tests.push((target.name.clone(), test_result))
     }
 
+    if let Some(ref test_state) = test_state {
+        test_state.save()?;
+    }
+    if unchanged > 0 {
+        println!("Skipped {} unchanged tests (`--changed`).", unchanged);
+    }
+
+    if let Some(ref manifest_out) = *args.manifest_out() {
+        artifacts::write_manifest(manifest_out, &artifacts)?;
+    }
+    if let Some(ref junit_path) = *args.junit() {
+        junit::write_report(junit_path, package_name, &test_cases)?;
+    }
+
+    report_results(&tests, &mut io::stderr())
+}
+
+/// Prints the summary of the test results, and fails with exit code 1 if any test didn't
+/// succeed.
+///
+/// The counts and the failed tests are written to `stderr`. Tests that failed to build
+/// only appear with `--keep-going`; without it, the run already stopped with the build
+/// error (and a non-zero exit code) at the first test that failed to build.
+fn report_results<W: Write>(tests: &[(String, TestResult)], stderr: &mut W) -> Result<(), Error> {
     if tests.iter().all(|t| t.1 == TestResult::Ok) {
         println!("All tests succeeded.");
-        Ok(())
+        return Ok(());
+    }
+    let count = |results: &[TestResult]| tests.iter().filter(|t| results.contains(&t.1)).count();
+    writeln!(
+        stderr,
+        "{} passed, {} failed, {} timed out, {} failed to build",
+        count(&[TestResult::Ok]),
+        count(&[TestResult::Failed, TestResult::Invalid]),
+        count(&[TestResult::TimedOut]),
+        count(&[TestResult::BuildFailed])
+    )?;
+    writeln!(stderr, "The following tests failed:")?;
+    for test in tests.iter().filter(|t| t.1 != TestResult::Ok) {
+        writeln!(stderr, "    {}: {:?}", test.0, test.1)?;
+    }
+    Err(error::Exit { code: 1 })?
+}
+
+/// Removes the bootimage of a test when dropped, for `--no-keep-image`.
+///
+/// Only the disk image is removed; the kernel executable and the output file are kept.
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.0) {
+            Err(ref err) if err.kind() != io::ErrorKind::NotFound => {
+                eprintln!("warning: failed to remove {}: {}", self.0.display(), err);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Resolves the configuration of each integration test for `--check-config`, in
+/// addition to the build configuration (see `build::check_config`).
+fn check_config(args: &Args, config: &Config, metadata: &CargoMetadata, out_dir: &Path) -> Result<(), Error> {
+    let mut values = build::check_config(args, config, metadata, out_dir)?;
+    let arch = qemu::Arch::of_target(args.target().as_deref());
+    for target in test_targets(metadata)?.1 {
+        let target_config = config.for_test(&target.name);
+        let (command, source) = qemu::resolve_run_command(&target_config, &arch, Some(&target.name))?;
+        if !source.is_test_command() {
+            ExitMechanism::new(&target_config, &arch)?;
+        }
+        let mut description = format!("{} (timeout {}s", command.join(" "), target_config.test_timeout);
+        if let Some(code) = target_config.test_success_exit_code {
+            description.push_str(&format!(", success exit code {}", code));
+        }
+        if target_config.should_panic {
+            description.push_str(", should panic");
+        }
+        description.push(')');
+        values.push((format!("test {}", target.name), description));
+    }
+    build::print_config_ok(&values);
+    Ok(())
+}
+
+/// Prints the last `tail` lines of the serial output of a failed test to `stderr`.
+///
+/// The lines are indented and preceded by a header that says how many lines were omitted,
+/// so that the line that decided the test result is easy to find.
+fn print_tail<W: Write>(stderr: &mut W, output: &str, tail: usize, output_file: &str) -> io::Result<()> {
+    let lines: Vec<&str> = output.lines().collect();
+    if lines.is_empty() || tail == 0 {
+        return Ok(());
+    }
+    let omitted = lines.len().saturating_sub(tail);
+    if omitted == 0 {
+        writeln!(stderr, "    Serial output:")?;
     } else {
-        writeln!(io::stderr(), "The following tests failed:")?;
-        for test in tests.iter().filter(|t| t.1 != TestResult::Ok) {
-            writeln!(io::stderr(), "    {}: {:?}", test.0, test.1)?;
+        writeln!(
+            stderr,
+            "    Last {} lines of serial output ({} omitted, see {}):",
+            tail, omitted, output_file
+        )?;
+    }
+    for line in &lines[omitted..] {
+        writeln!(stderr, "    {}", line)?;
+    }
+    Ok(())
+}
+
+/// The exit status of a test or its `test-result-parser`, however it was waited for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Status {
+    /// The exit code, or `None` if the process was terminated by a signal.
+    code: Option<i32>,
+    /// The description of the status, e.g. `exit code: 3`.
+    description: String,
+}
+
+impl Status {
+    fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl From<process::ExitStatus> for Status {
+    fn from(status: process::ExitStatus) -> Status {
+        Status {
+            code: status.code(),
+            description: status.to_string(),
         }
-        process::exit(1);
     }
 }
 
+impl From<wait_timeout::ExitStatus> for Status {
+    fn from(status: wait_timeout::ExitStatus) -> Status {
+        Status {
+            code: status.code(),
+            description: status.to_string(),
+        }
+    }
+}
+
+/// The result of a test, with the messages that report it.
+struct Outcome<'a> {
+    result: TestResult,
+    /// The message of the failure in the JUnit report.
+    failure_message: String,
+    /// The text printed after "Failed".
+    header: String,
+    /// The serial output printed for a failed test.
+    details: &'a str,
+}
+
+/// Decides the result of a test that exited with `exit_status`, or timed out if it is `None`.
+///
+/// The result is decided by the exit status of the `test-result-parser` (`parser_status`),
+/// by comparing the exit status with the `success_code`, or else by the first line of the
+/// serial `output`. The `exit_mechanism` is the one added to the QEMU arguments, which is
+/// `None` for a `test-command`. The `stderr_warnings` of QEMU are the likely cause of a
+/// timeout.
+///
+/// The result of a `should-panic` test is inverted, but only if the kernel reported the
+/// failure itself: through `isa-debug-exit` (see `qemu::isa_debug_exit_value`), a `failed`
+/// line, or a failing `test-result-parser`. Other failures, e.g. QEMU's own error status 1
+/// for an invalid argument or a process terminated by a signal, stay failures.
+fn outcome<'a>(
+    config: &Config,
+    exit_status: Option<Status>,
+    parser_status: Option<Status>,
+    success_code: Option<i32>,
+    exit_mechanism: Option<&ExitMechanism>,
+    output: &'a str,
+    stderr_warnings: &[&str],
+) -> Outcome<'a> {
+    let mut outcome = Outcome {
+        result: TestResult::Ok,
+        failure_message: String::new(),
+        header: String::new(),
+        details: output,
+    };
+    // whether the failure was reported by the kernel, which `should-panic` expects
+    let mut kernel_failure = false;
+    let exit_status = match exit_status {
+        Some(exit_status) => exit_status,
+        None => {
+            outcome.result = TestResult::TimedOut;
+            outcome.failure_message = format!("timed out after {} seconds", config.test_timeout);
+            if !stderr_warnings.is_empty() {
+                outcome.failure_message.push_str(&format!(
+                    "; likely cause (QEMU stderr): {}",
+                    stderr_warnings.join("; ")
+                ));
+            }
+            return outcome;
+        }
+    };
+    if let Some(parser_status) = parser_status {
+        outcome.failure_message = format!("test-result-parser exited with {}", parser_status.description);
+        outcome.header = format!(": {}", outcome.failure_message);
+        if !parser_status.success() {
+            outcome.result = TestResult::Failed;
+            kernel_failure = parser_status.code.is_some();
+        }
+    } else if let Some(success_code) = success_code {
+        outcome.failure_message = format!("QEMU exited with {}", exit_status.description);
+        outcome.header = format!(": {}", exit_status.description);
+        if exit_status.code != Some(success_code) {
+            outcome.result = TestResult::Failed;
+            kernel_failure = exit_status
+                .code
+                .and_then(|code| qemu::isa_debug_exit_value(code, exit_mechanism))
+                .is_some();
+        }
+    } else if output.starts_with("ok\n") {
+        // the test passed
+    } else if let Some(message) = output.strip_prefix("failed\n") {
+        outcome.result = TestResult::Failed;
+        outcome.failure_message = String::from("test printed `failed`");
+        outcome.header = String::from(":");
+        outcome.details = message;
+        kernel_failure = true;
+    } else {
+        outcome.result = TestResult::Invalid;
+        outcome.failure_message = String::from("invalid output");
+        outcome.header = String::from(": Invalid Output:");
+    }
+    if config.should_panic {
+        match outcome.result {
+            TestResult::Ok => {
+                outcome.result = TestResult::Failed;
+                outcome.failure_message = String::from("test passed, but it should panic");
+                outcome.header = format!(": {}", outcome.failure_message);
+                outcome.details = output;
+            }
+            TestResult::Failed if kernel_failure => outcome.result = TestResult::Ok,
+            _ => {}
+        }
+    }
+    outcome
+}
+
+/// Runs the `test-result-parser` command with the serial output of a test on stdin.
+fn run_result_parser(parser: &[String], output: &str) -> Result<process::ExitStatus, Error> {
+    let mut command = process::Command::new(&parser[0]);
+    command.args(&parser[1..]);
+    command.stdin(process::Stdio::piped());
+    let mut child = command
+        .spawn()
+        .context(format_err!("Failed to execute test-result-parser: {:?}", command))?;
+    {
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or(format_err!("Failed to open the stdin of test-result-parser"))?;
+        // the parser might exit without reading all of its input
+        match stdin.write_all(output.as_bytes()) {
+            Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+            result => result.context("Failed to write to test-result-parser")?,
+        }
+    }
+    let exit_status = child.wait().context("Failed to wait for test-result-parser")?;
+    Ok(exit_status)
+}
+
+/// Returns the name of the crate and its integration tests, i.e. its binaries starting
+/// with `test-`.
+fn test_targets(metadata: &CargoMetadata) -> Result<(&str, Vec<&Target>), Error> {
+    if metadata.packages.len() != 1 {
+        Err(format_err!(
+            "`bootimage test` only supports crates with one package, but the cargo metadata \
+             contains {} packages",
+            metadata.packages.len()
+        )).context(ErrorKind::Metadata)?
+    }
+    let package = &metadata.packages[0];
+    let targets = package
+        .targets
+        .iter()
+        .filter(|t| t.kind == ["bin"] && t.name.starts_with("test-"))
+        .collect();
+    Ok((&package.name, targets))
+}
+
+/// Prints the names of the integration tests for `--list-tests`, one per line or as a
+/// JSON array with a JSON `--message-format`.
+fn list_tests(args: &Args, metadata: &CargoMetadata) -> Result<(), Error> {
+    let names: Vec<&str> = test_targets(metadata)?.1.iter().map(|t| t.name.as_str()).collect();
+    if args.message_format_json() {
+        println!("{}", serde_json::to_string(&names).context("Failed to serialize test names")?);
+    } else {
+        for name in names {
+            println!("{}", name);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the serial output of a test until QEMU exits and returns it. If `print` is true,
+/// the output is also printed while the test runs.
+fn stream_serial<R: Read>(serial: R, print: bool) -> io::Result<String> {
+    let mut serial = BufReader::new(serial);
+    let mut output = String::new();
+    let mut line = Vec::new();
+    while serial.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        if print {
+            println!("    {}", text.trim_end_matches('\n'));
+        }
+        output.push_str(&text);
+        line.clear();
+    }
+    Ok(output)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum TestResult {
     Ok,
     Failed,
     TimedOut,
     Invalid,
+    BuildFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config;
+
+    fn exited(code: i32) -> Option<Status> {
+        Some(Status {
+            code: Some(code),
+            description: format!("exit code: {}", code),
+        })
+    }
+
+    fn config(should_panic: bool) -> Config {
+        let mut config = config::default_config(Path::new("Cargo.toml"));
+        config.should_panic = should_panic;
+        config
+    }
+
+    fn isa_debug_exit() -> ExitMechanism {
+        let mut config = config(false);
+        config.test_success_exit_code = Some(0x10);
+        ExitMechanism::new(&config, &qemu::Arch::X86_64).unwrap().unwrap()
+    }
+
+    /// Returns the result of a test decided by the `isa-debug-exit` status `code`.
+    fn exit_code_result(should_panic: bool, code: Option<Status>) -> TestResult {
+        let mechanism = isa_debug_exit();
+        let success_code = mechanism.success_code();
+        outcome(&config(should_panic), code, None, success_code, Some(&mechanism), "", &[]).result
+    }
+
+    fn output_result(should_panic: bool, output: &str) -> TestResult {
+        outcome(&config(should_panic), exited(0), None, None, None, output, &[]).result
+    }
+
+    #[test]
+    fn exit_code_decides_result() {
+        assert_eq!(exit_code_result(false, exited(33)), TestResult::Ok);
+        assert_eq!(exit_code_result(false, exited(35)), TestResult::Failed);
+        assert_eq!(exit_code_result(false, exited(1)), TestResult::Failed);
+    }
+
+    #[test]
+    fn serial_output_decides_result() {
+        assert_eq!(output_result(false, "ok\n"), TestResult::Ok);
+        assert_eq!(output_result(false, "failed\npanicked at 'oops'\n"), TestResult::Failed);
+        assert_eq!(output_result(false, "garbage\n"), TestResult::Invalid);
+    }
+
+    #[test]
+    fn should_panic_turns_pass_into_failure() {
+        assert_eq!(exit_code_result(true, exited(33)), TestResult::Failed);
+        let outcome = outcome(&config(true), exited(0), None, None, None, "ok\n", &[]);
+        assert_eq!(outcome.result, TestResult::Failed);
+        assert_eq!(outcome.failure_message, "test passed, but it should panic");
+    }
+
+    #[test]
+    fn should_panic_turns_kernel_failure_into_pass() {
+        assert_eq!(exit_code_result(true, exited(35)), TestResult::Ok);
+        assert_eq!(output_result(true, "failed\npanicked at 'oops'\n"), TestResult::Ok);
+        let parser_status = exited(1);
+        let outcome = outcome(&config(true), exited(0), parser_status, None, None, "", &[]);
+        assert_eq!(outcome.result, TestResult::Ok);
+    }
+
+    #[test]
+    fn should_panic_keeps_qemu_errors_as_failure() {
+        // QEMU's own error status, e.g. for an invalid `-device`
+        assert_eq!(exit_code_result(true, exited(1)), TestResult::Failed);
+        let signal = Some(Status {
+            code: None,
+            description: String::from("signal: 9"),
+        });
+        assert_eq!(exit_code_result(true, signal), TestResult::Failed);
+        // a `test-command` has no exit mechanism, so its exit code is never the kernel's
+        let outcome = outcome(&config(true), exited(127), None, Some(0), None, "", &[]);
+        assert_eq!(outcome.result, TestResult::Failed);
+        assert_eq!(output_result(true, "garbage\n"), TestResult::Invalid);
+    }
+
+    #[test]
+    fn should_panic_keeps_timeout() {
+        assert_eq!(exit_code_result(true, None), TestResult::TimedOut);
+        assert_eq!(outcome(&config(true), None, None, None, None, "", &[]).result, TestResult::TimedOut);
+    }
+
+    #[test]
+    fn summary_counts_results_and_fails_run() {
+        let tests = vec![
+            (String::from("test-basic-boot"), TestResult::Ok),
+            (String::from("test-panic"), TestResult::Failed),
+            (String::from("test-garbage"), TestResult::Invalid),
+            (String::from("test-hang"), TestResult::TimedOut),
+            (String::from("test-broken"), TestResult::BuildFailed),
+        ];
+        let mut stderr = Vec::new();
+        let err = report_results(&tests, &mut stderr).unwrap_err();
+        assert_eq!(error::exit_code(&err), (1, false));
+        assert_eq!(
+            String::from_utf8(stderr).unwrap(),
+            "1 passed, 2 failed, 1 timed out, 1 failed to build\n\
+             The following tests failed:\n    test-panic: Failed\n    test-garbage: Invalid\n    \
+             test-hang: TimedOut\n    test-broken: BuildFailed\n"
+        );
+    }
+
+    #[test]
+    fn build_failure_alone_fails_run() {
+        let tests = vec![(String::from("test-broken"), TestResult::BuildFailed)];
+        let mut stderr = Vec::new();
+        let err = report_results(&tests, &mut stderr).unwrap_err();
+        assert_eq!(error::exit_code(&err).0, 1);
+        assert!(String::from_utf8(stderr).unwrap().starts_with("0 passed, 0 failed, 0 timed out, 1 failed to build\n"));
+
+        let tests = vec![(String::from("test-basic-boot"), TestResult::Ok)];
+        let mut stderr = Vec::new();
+        report_results(&tests, &mut stderr).unwrap();
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn timeout_message_includes_qemu_stderr_cause() {
+        let mut config = config(false);
+        config.test_timeout = 300;
+        let stderr = "VNC server running on ::1:5900\n\
+                      qemu-system-x86_64: Failed to initialize KVM: No such file or directory\n";
+        let warnings = qemu::stderr_warnings(stderr);
+        let timed_out = outcome(&config, None, None, None, None, "", &warnings);
+        assert_eq!(timed_out.result, TestResult::TimedOut);
+        assert_eq!(
+            timed_out.failure_message,
+            "timed out after 300 seconds; likely cause (QEMU stderr): \
+             qemu-system-x86_64: Failed to initialize KVM: No such file or directory"
+        );
+        let timed_out = outcome(&config, None, None, None, None, "", &[]);
+        assert_eq!(timed_out.failure_message, "timed out after 300 seconds");
+    }
+
+    #[cfg(unix)]
+    fn parser(script: &str) -> Vec<String> {
+        vec![String::from("sh"), String::from("-c"), String::from(script)]
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn result_parser_reads_serial_output() {
+        let grep_ok = parser("grep -q '^test result: ok$'");
+        assert!(run_result_parser(&grep_ok, "booting\ntest result: ok\n").unwrap().success());
+        let status = run_result_parser(&grep_ok, "booting\ntest result: FAILED\n").unwrap();
+        assert_eq!(status.code(), Some(1));
+        let parser_status = Some(Status::from(status));
+        let failed = outcome(&config(false), exited(0), parser_status, None, None, "", &[]);
+        assert_eq!(failed.result, TestResult::Failed);
+        assert_eq!(failed.failure_message, "test-result-parser exited with exit status: 1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn result_parser_may_ignore_its_input() {
+        let output = "x".repeat(1 << 20);
+        assert!(run_result_parser(&parser("true"), &output).unwrap().success());
+        assert!(!run_result_parser(&parser("false"), &output).unwrap().success());
+        assert!(run_result_parser(&[String::from("/nonexistent/parser")], "").is_err());
+    }
+
+    fn tail(output: &str, tail: usize) -> String {
+        let mut stderr = Vec::new();
+        print_tail(&mut stderr, output, tail, "test-panic-output.txt").unwrap();
+        String::from_utf8(stderr).unwrap()
+    }
+
+    #[test]
+    fn failure_report_ends_with_tail_of_output() {
+        let output = "booting\nrunning 2 tests\ntest a ... ok\npanicked at 'oops', src/main.rs:9\n";
+        assert_eq!(
+            tail(output, 2),
+            "    Last 2 lines of serial output (2 omitted, see test-panic-output.txt):\n\
+             \x20   test a ... ok\n    panicked at 'oops', src/main.rs:9\n"
+        );
+        assert_eq!(tail("failed\n", 20), "    Serial output:\n    failed\n");
+        assert_eq!(tail(output, 0), "");
+        assert_eq!(tail("", 20), "");
+    }
 }
@@ -0,0 +1,236 @@
+//! The state file of `bootimage test --changed`, which records the last successful run
+//! of each test.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use artifacts;
+use build;
+use failure::{Error, ResultExt};
+use serde_json::{self, Value};
+
+/// The version of the state file format.
+const STATE_VERSION: u64 = 1;
+
+/// The name of the state file in the output directory of the tests.
+const STATE_FILE_NAME: &str = "bootimage-test-state.json";
+
+/// The last successful run of a test.
+#[derive(Debug)]
+struct PassedRun {
+    /// When the build of the test started, in milliseconds since the Unix epoch.
+    started_at: u64,
+    /// The `Args::fingerprint` of the run.
+    fingerprint: String,
+}
+
+/// The last successful runs of the tests, keyed by test name.
+///
+/// The file has the format:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "tests": {
+///     "test-basic-boot": { "started-at": 1700000000000, "fingerprint": "4f1a9c2b7d3e8a60" }
+///   }
+/// }
+/// ```
+#[derive(Debug)]
+pub(crate) struct TestState {
+    path: PathBuf,
+    passed: BTreeMap<String, PassedRun>,
+}
+
+impl TestState {
+    /// Reads the state file in `out_dir`.
+    ///
+    /// A missing file, or one of another version, is treated as empty state, so that all
+    /// tests run.
+    pub(crate) fn load(out_dir: &Path) -> Result<TestState, Error> {
+        let path = out_dir.join(STATE_FILE_NAME);
+        let mut passed = BTreeMap::new();
+        if let Ok(content) = fs::read_to_string(&path) {
+            let state: Value = serde_json::from_str(&content)
+                .context(format_err!("Failed to parse {} (delete it to run all tests)", path.display()))?;
+            if state["version"].as_u64() == Some(STATE_VERSION) {
+                for (name, run) in state["tests"].as_object().into_iter().flatten() {
+                    if let (Some(started_at), Some(fingerprint)) =
+                        (run["started-at"].as_u64(), run["fingerprint"].as_str())
+                    {
+                        let fingerprint = fingerprint.to_owned();
+                        passed.insert(name.clone(), PassedRun { started_at, fingerprint });
+                    }
+                }
+            }
+        }
+        Ok(TestState { path, passed })
+    }
+
+    /// Returns whether the test `name` passed before and none of its inputs changed since.
+    ///
+    /// The inputs are the sources of the test kernel at `kernel_path` according to its
+    /// dep-info file, and `manifest_path` (which contains the configuration). A test is
+    /// considered changed if one of them was modified after the build of its last
+    /// successful run started, if the arguments have another `fingerprint`, or if the
+    /// kernel has no dep-info file. Inputs that are not in the dep-info file, such as the
+    /// bootloader, the toolchain, or the environment, are not considered.
+    pub(crate) fn is_unchanged(&self, name: &str, kernel_path: &Path, manifest_path: &Path, fingerprint: &str) -> bool {
+        let run = match self.passed.get(name) {
+            Some(run) if run.fingerprint == fingerprint => run,
+            _ => return false,
+        };
+        let mut inputs = match build::dep_info(kernel_path) {
+            Some(deps) => deps,
+            None => return false,
+        };
+        inputs.push(manifest_path.to_owned());
+        inputs.iter().all(|input| match modified_millis(input) {
+            Some(modified) => modified < run.started_at,
+            None => false,
+        })
+    }
+
+    /// Records that the test `name`, whose build started at `started`, passed.
+    pub(crate) fn record_pass(&mut self, name: &str, started: SystemTime, fingerprint: &str) {
+        let started_at = started
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        let fingerprint = fingerprint.to_owned();
+        self.passed.insert(name.to_owned(), PassedRun { started_at, fingerprint });
+    }
+
+    /// Records that the test `name` didn't pass, so that it runs again next time.
+    pub(crate) fn record_failure(&mut self, name: &str) {
+        self.passed.remove(name);
+    }
+
+    /// Writes the state file.
+    pub(crate) fn save(&self) -> Result<(), Error> {
+        let tests: serde_json::Map<String, Value> = self
+            .passed
+            .iter()
+            .map(|(name, run)| {
+                let run = json!({ "started-at": run.started_at, "fingerprint": run.fingerprint });
+                (name.clone(), run)
+            })
+            .collect();
+        let state = json!({ "version": STATE_VERSION, "tests": tests });
+        let json = serde_json::to_string_pretty(&state).context("Failed to serialize test state")?;
+        artifacts::write_atomically(&self.path, json.as_bytes(), "test state")
+    }
+}
+
+/// Returns the modification time of `path` in milliseconds since the Unix epoch.
+fn modified_millis(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let duration = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(duration.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    /// Creates a kernel with a dep-info file listing `main.rs`, and a `Cargo.toml`, and
+    /// returns the paths of the kernel, `main.rs` and the manifest.
+    fn create_inputs(dir: &Path) -> (PathBuf, PathBuf, PathBuf) {
+        let kernel = dir.join("test-basic-boot");
+        let source = dir.join("main.rs");
+        let manifest = dir.join("Cargo.toml");
+        fs::write(&kernel, "kernel").unwrap();
+        fs::write(&source, "fn main() {}").unwrap();
+        fs::write(&manifest, "[package]").unwrap();
+        let dep_info = format!("{}: {}\n", kernel.display(), source.display());
+        fs::write(dir.join("test-basic-boot.d"), dep_info).unwrap();
+        (kernel, source, manifest)
+    }
+
+    /// Waits until the modification times of files written next differ from earlier ones.
+    fn tick() {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn unmodified_test_is_unchanged() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let (kernel, _, manifest) = create_inputs(dir.path());
+        tick();
+        let mut state = TestState::load(dir.path()).unwrap();
+        state.record_pass("test-basic-boot", SystemTime::now(), "fp");
+        assert!(state.is_unchanged("test-basic-boot", &kernel, &manifest, "fp"));
+    }
+
+    #[test]
+    fn modified_source_or_manifest_is_changed() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let (kernel, source, manifest) = create_inputs(dir.path());
+        tick();
+        let mut state = TestState::load(dir.path()).unwrap();
+        state.record_pass("test-basic-boot", SystemTime::now(), "fp");
+        tick();
+        fs::write(&source, "fn main() { loop {} }").unwrap();
+        assert!(!state.is_unchanged("test-basic-boot", &kernel, &manifest, "fp"));
+
+        state.record_pass("test-basic-boot", SystemTime::now(), "fp");
+        tick();
+        fs::write(&manifest, "[package]\nname = \"kernel\"").unwrap();
+        assert!(!state.is_unchanged("test-basic-boot", &kernel, &manifest, "fp"));
+    }
+
+    #[test]
+    fn other_fingerprint_unknown_test_or_missing_dep_info_is_changed() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let (kernel, _, manifest) = create_inputs(dir.path());
+        tick();
+        let mut state = TestState::load(dir.path()).unwrap();
+        state.record_pass("test-basic-boot", SystemTime::now(), "fp");
+        assert!(!state.is_unchanged("test-basic-boot", &kernel, &manifest, "other"));
+        assert!(!state.is_unchanged("test-other", &kernel, &manifest, "fp"));
+        fs::remove_file(dir.path().join("test-basic-boot.d")).unwrap();
+        assert!(!state.is_unchanged("test-basic-boot", &kernel, &manifest, "fp"));
+    }
+
+    #[test]
+    fn failure_makes_test_changed() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let (kernel, _, manifest) = create_inputs(dir.path());
+        tick();
+        let mut state = TestState::load(dir.path()).unwrap();
+        state.record_pass("test-basic-boot", SystemTime::now(), "fp");
+        state.record_failure("test-basic-boot");
+        assert!(!state.is_unchanged("test-basic-boot", &kernel, &manifest, "fp"));
+    }
+
+    #[test]
+    fn state_survives_save_and_load() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let (kernel, _, manifest) = create_inputs(dir.path());
+        tick();
+        let mut state = TestState::load(dir.path()).unwrap();
+        state.record_pass("test-basic-boot", SystemTime::now(), "fp");
+        state.save().unwrap();
+        assert!(dir.path().join(STATE_FILE_NAME).is_file());
+
+        let state = TestState::load(dir.path()).unwrap();
+        assert!(state.is_unchanged("test-basic-boot", &kernel, &manifest, "fp"));
+    }
+
+    #[test]
+    fn state_of_other_version_is_ignored() {
+        let dir = TempDir::new("bootimage-test").unwrap();
+        let (kernel, _, manifest) = create_inputs(dir.path());
+        let state = json!({
+            "version": STATE_VERSION + 1,
+            "tests": { "test-basic-boot": { "started-at": u64::MAX, "fingerprint": "fp" } },
+        });
+        fs::write(dir.path().join(STATE_FILE_NAME), state.to_string()).unwrap();
+        let state = TestState::load(dir.path()).unwrap();
+        assert!(!state.is_unchanged("test-basic-boot", &kernel, &manifest, "fp"));
+    }
+}
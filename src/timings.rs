@@ -0,0 +1,140 @@
+//! The phase timings of `--profile-startup`.
+//!
+//! The durations are recorded in a global list, so that the phases don't need to pass
+//! a recorder around. Without `--profile-startup`, `time` only runs the phase.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether the report is a JSON message (with a JSON `--message-format`).
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// The recorded phases in the order they first ran, with their summed durations.
+static PHASES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+/// Enables recording for `--profile-startup`. With `json`, the report is printed as JSON
+/// message.
+pub(crate) fn enable(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Runs `f` and records its wall-clock duration as `phase`.
+///
+/// A phase that runs several times (e.g. the kernel build of each test) is reported
+/// with the sum of its durations.
+pub(crate) fn time<T, F: FnOnce() -> T>(phase: &'static str, f: F) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    let mut phases = PHASES.lock().unwrap_or_else(|err| err.into_inner());
+    record(&mut phases, phase, elapsed);
+    result
+}
+
+/// Adds `elapsed` to the duration of `phase`, or appends the phase if it is new.
+fn record(phases: &mut Vec<(&'static str, Duration)>, phase: &'static str, elapsed: Duration) {
+    match phases.iter_mut().find(|&&mut (name, _)| name == phase) {
+        Some(&mut (_, ref mut duration)) => *duration += elapsed,
+        None => phases.push((phase, elapsed)),
+    }
+}
+
+/// Prints the recorded phases as a table on stderr, or as a JSON message on stdout.
+///
+/// Only the first call prints anything.
+pub(crate) fn report() {
+    if !ENABLED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let phases = PHASES.lock().unwrap_or_else(|err| err.into_inner());
+    if JSON.load(Ordering::Relaxed) {
+        println!("{}", json_message(&phases));
+    } else {
+        eprint!("{}", table(&phases));
+    }
+}
+
+/// Returns the `bootimage-timings` JSON message of `phases`.
+fn json_message(phases: &[(&str, Duration)]) -> Value {
+    let total: Duration = phases.iter().map(|&(_, duration)| duration).sum();
+    let phases: Vec<_> = phases
+        .iter()
+        .map(|&(name, duration)| json!({ "name": name, "seconds": duration.as_secs_f64() }))
+        .collect();
+    json!({ "reason": "bootimage-timings", "phases": phases, "total-seconds": total.as_secs_f64() })
+}
+
+/// Returns `phases` as a table with one line per phase and a final `total` line.
+fn table(phases: &[(&str, Duration)]) -> String {
+    let total: Duration = phases.iter().map(|&(_, duration)| duration).sum();
+    let mut table = String::from("Timings:\n");
+    for &(name, duration) in phases.iter().chain(Some(&("total", total))) {
+        table.push_str(&format!("    {:<18} {:>9.3}s\n", name, duration.as_secs_f64()));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phases() -> Vec<(&'static str, Duration)> {
+        let mut phases = Vec::new();
+        record(&mut phases, "metadata", Duration::from_millis(120));
+        record(&mut phases, "kernel build", Duration::from_millis(2000));
+        record(&mut phases, "bootloader build", Duration::from_millis(1500));
+        record(&mut phases, "image assembly", Duration::from_millis(30));
+        record(&mut phases, "kernel build", Duration::from_millis(500));
+        record(&mut phases, "qemu startup", Duration::from_millis(250));
+        phases
+    }
+
+    #[test]
+    fn repeated_phases_are_summed_in_first_run_order() {
+        let names: Vec<_> = phases().iter().map(|&(name, _)| name).collect();
+        assert_eq!(
+            names,
+            ["metadata", "kernel build", "bootloader build", "image assembly", "qemu startup"]
+        );
+        assert_eq!(phases()[1].1, Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn table_lists_phases_and_total() {
+        assert_eq!(
+            table(&phases()),
+            "Timings:\n\
+             \x20   metadata               0.120s\n\
+             \x20   kernel build           2.500s\n\
+             \x20   bootloader build       1.500s\n\
+             \x20   image assembly         0.030s\n\
+             \x20   qemu startup           0.250s\n\
+             \x20   total                  4.400s\n"
+        );
+    }
+
+    #[test]
+    fn json_message_lists_phases_and_total() {
+        let message = json_message(&phases());
+        assert_eq!(message["reason"], "bootimage-timings");
+        let names: Vec<_> = message["phases"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|phase| phase["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["metadata", "kernel build", "bootloader build", "image assembly", "qemu startup"]
+        );
+        assert_eq!(message["phases"][1]["seconds"], 2.5);
+        assert_eq!(message["total-seconds"], 4.4);
+    }
+}
@@ -0,0 +1,49 @@
+//! Queries about the active Rust toolchain.
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+/// Returns the sysroot of the active toolchain (`rustc --print sysroot`).
+pub(crate) fn sysroot() -> Option<PathBuf> {
+    let output = process::Command::new("rustc")
+        .arg("--print")
+        .arg("sysroot")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()))
+}
+
+/// Returns whether the `rust-src` component is installed, or `None` if the sysroot
+/// can't be determined.
+///
+/// The check only looks at the location used by rustup, so a missing source is not
+/// necessarily an error (e.g. if `XARGO_RUST_SRC` points to a vendored copy).
+pub(crate) fn has_rust_src() -> Option<bool> {
+    let mut rust_src = sysroot()?;
+    rust_src.push("lib");
+    rust_src.push("rustlib");
+    rust_src.push("src");
+    rust_src.push("rust");
+    Some(rust_src.is_dir())
+}
+
+/// Returns whether the active toolchain accepts unstable `-Z` flags, or `None` if
+/// `rustc --version` can't be run.
+///
+/// This is the case for nightly and locally built (`-dev`) toolchains, and for any
+/// toolchain if `RUSTC_BOOTSTRAP` is set.
+pub(crate) fn is_nightly() -> Option<bool> {
+    if env::var_os("RUSTC_BOOTSTRAP").is_some() {
+        return Some(true);
+    }
+    let output = process::Command::new("rustc").arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let version = String::from_utf8_lossy(&output.stdout);
+    Some(version.contains("-nightly") || version.contains("-dev"))
+}